@@ -26,12 +26,14 @@ pub enum ReadinessState {
 /// - Healthy, Everything nominal
 /// - MissingRpcs, Some RPCs are not following the head but otherwise ok
 /// - Unhealthy, Nothing works
+/// - Stale, RPCs answer but the head stopped moving
 #[derive(Debug, PartialEq, Clone, Copy, Default)]
 pub enum HealthState {
     #[default]
     Healthy, // Everything nominal
     MissingRpcs, // Some RPCs are not following the head but otherwise ok
     Unhealthy,   // Nothing works
+    Stale,       // RPCs answer but the head stopped moving
 }
 
 #[derive(Debug, PartialEq, Clone, Copy, Default)]
@@ -176,7 +178,7 @@ pub async fn accept_health_request(
     match rax.health {
         HealthState::Healthy => ok!(),
         HealthState::MissingRpcs => partial_ok!(),
-        HealthState::Unhealthy => nok!(),
+        HealthState::Unhealthy | HealthState::Stale => nok!(),
     }
 }
 
@@ -284,6 +286,13 @@ mod tests {
         let (tx, _rx) = oneshot::channel();
         request_snd.send(tx).await.unwrap();
         liveness_status.write().unwrap().health = HealthState::Unhealthy;
+        let response = accept_health_request(request_snd.clone()).await.unwrap();
+        assert_eq!(response.status(), 503);
+
+        // Test with Stale state
+        let (tx, _rx) = oneshot::channel();
+        request_snd.send(tx).await.unwrap();
+        liveness_status.write().unwrap().health = HealthState::Stale;
         let response = accept_health_request(request_snd).await.unwrap();
         assert_eq!(response.status(), 503);
     }
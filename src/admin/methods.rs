@@ -576,8 +576,8 @@ mod tests {
     fn create_test_cache() -> RequestBus<Vec<u8>, Vec<u8>> {
         let cache = Config::tmp().unwrap();
         let cache = Db::open_with_config(&cache).unwrap();
-        let (db_tx, db_rx) = mpsc::unbounded_channel();
-        tokio::task::spawn(database_processing(db_rx, cache));
+        let (db_tx, db_rx) = mpsc::channel(64);
+        tokio::task::spawn(database_processing(db_rx, cache, 4));
 
         db_tx
     }
@@ -1,22 +1,37 @@
 use crate::{
     balancer::{
         format::{
+            get_block_number_from_request,
             incoming_to_value,
             replace_block_tags,
         },
+        local_responder::local_response,
+        logs::{
+            fetch_logs,
+            log_chunks,
+            ChunkError,
+        },
+        micro_cache::micro_cache_head,
         processing::{
             cache_query,
+            get_cached,
             update_rpc_latency,
             CacheArgs,
         },
-        selection::select::pick,
+        selection::select::pick_at,
+        splice::splice_id,
     },
     cache_error,
-    database::types::GenericBytes,
-    db_get,
+    database::{
+        key::CacheKey,
+        types::GenericBytes,
+    },
     no_rpc_available,
     print_cache_error,
-    rpc::types::Rpc,
+    rpc::{
+        method::MethodInfo,
+        types::Rpc,
+    },
     rpc_response,
     timed_out,
     websocket::{
@@ -38,15 +53,6 @@ use tokio::sync::{
 
 use serde_json::Value;
 
-// Select either blake3 or xxhash based on the features
-#[cfg(not(feature = "xxhash"))]
-use blake3::hash;
-
-#[cfg(feature = "xxhash")]
-use xxhash_rust::xxh3::xxh3_64;
-#[cfg(feature = "xxhash")]
-use zerocopy::AsBytes; // Impls AsBytes trait for u64
-
 use http_body_util::Full;
 use hyper::{
     body::Bytes,
@@ -102,6 +108,8 @@ pub struct RequestParams {
     pub ttl: u128,
     pub max_retries: u32,
     pub header_check: bool,
+    pub logs_chunk_size: u64,
+    pub logs_max_chunks: usize,
 }
 
 #[derive(Debug)]
@@ -174,17 +182,14 @@ macro_rules! get_response {
         $ttl:expr,
         $max_retries:expr
     ) => {
-        match db_get!($cache_args.cache, $tx_hash.as_bytes().to_owned().into()) {
-            Ok(Some(mut rax)) => {
+        match get_cached(&$cache_args, &$tx_hash).await {
+            Ok(Some(rax)) => {
                 $rpc_position = None;
                 // Reconstruct ID
-                let mut cached: Value = simd_json::serde::from_slice(rax.as_mut()).unwrap();
-
-                cached["id"] = $id.into();
-                cached.to_string()
+                Bytes::from(splice_id(&rax, &Value::from($id)))
             }
             Ok(_) => {
-                fetch_from_rpc!(
+                Bytes::from(fetch_from_rpc!(
                     $tx,
                     $cache_args,
                     $tx_hash,
@@ -193,7 +198,7 @@ macro_rules! get_response {
                     $con_params,
                     $ttl,
                     $max_retries
-                )
+                ))
             }
             Err(_) => {
                 // If anything errors send an rpc request and see if it works, if not then gg
@@ -215,12 +220,64 @@ macro_rules! fetch_from_rpc {
         $con_params:expr,
         $ttl:expr,
         $max_retries:expr
+    ) => {
+        fetch_from_rpc!(
+            $tx,
+            $cache_args,
+            $tx_hash,
+            $rpc_position,
+            $id,
+            $con_params,
+            $ttl,
+            $max_retries,
+            cache true
+        )
+    };
+    // Head scoped requests still have their tags, so they only go in the micro cache
+    (
+        $tx:expr,
+        $cache_args:expr,
+        $tx_hash:expr,
+        $rpc_position:expr,
+        $id:expr,
+        $con_params:expr,
+        $ttl:expr,
+        $max_retries:expr,
+        uncached
+    ) => {
+        fetch_from_rpc!(
+            $tx,
+            $cache_args,
+            $tx_hash,
+            $rpc_position,
+            $id,
+            $con_params,
+            $ttl,
+            $max_retries,
+            cache false
+        )
+    };
+    (
+        $tx:expr,
+        $cache_args:expr,
+        $tx_hash:expr,
+        $rpc_position:expr,
+        $id:expr,
+        $con_params:expr,
+        $ttl:expr,
+        $max_retries:expr,
+        cache $cache:expr
     ) => {{
         // Kinda jank but set the id back to what it was before
         $tx["id"] = $id.into();
 
+        // Nodes that don't have the block yet would answer with `null`
+        let block = get_block_number_from_request($tx.clone(), &$cache_args.named_numbers);
+
         // Loop until we get a response
         let mut rx;
+        let node;
+        let node_head;
         let mut retries = 0;
         loop {
             // Get the next Rpc in line.
@@ -231,7 +288,7 @@ macro_rules! fetch_from_rpc {
                     e.into_inner()
                 });
 
-                (rpc, $rpc_position) = pick(&mut rpc_list_guard);
+                (rpc, $rpc_position) = pick_at(&mut rpc_list_guard, block);
             }
             tracing::info!(rpc.name, "Forwarding to");
 
@@ -251,6 +308,8 @@ macro_rules! fetch_from_rpc {
             {
                 Ok(rxa) => {
                     rx = rxa.unwrap();
+                    node = rpc.name;
+                    node_head = rpc.status.head;
                     break;
                 }
                 Err(_) => {
@@ -266,7 +325,17 @@ macro_rules! fetch_from_rpc {
         }
 
         // Don't cache responses that contain errors or missing trie nodes
-        cache_query(&mut rx, $tx, $tx_hash, &$cache_args);
+        if $cache {
+            cache_query(
+                &mut rx,
+                $tx,
+                &$tx_hash,
+                &node,
+                Some(node_head),
+                &$cache_args,
+            )
+            .await;
+        }
 
         rx
     }};
@@ -313,40 +382,121 @@ where
     // and does not impact the request result.
     let id = tx["id"].take().as_u64().unwrap_or(0);
 
-    // Hash the request with either blake3 or xxhash depending on the enabled feature
-    let tx_hash;
-    #[cfg(not(feature = "xxhash"))]
-    {
-        tx_hash = hash(tx.to_string().as_bytes());
-    }
-    #[cfg(feature = "xxhash")]
-    {
-        tx_hash = xxh3_64(tx.to_string().as_bytes());
+    // Requests that are only valid for the current head never touch the DB,
+    // they're served from the micro cache instead.
+    let micro_head = micro_cache_head(&tx, &cache_args.named_numbers);
+
+    // Rewrite named block parameters if possible.
+    //
+    // This has to happen before hashing so that `latest` and the block
+    // number it points to end up under the same key.
+    if micro_head.is_none() {
+        tx = replace_block_tags(&mut tx, &cache_args.named_numbers);
     }
 
+    // Hash the request with either blake3 or xxhash depending on the enabled feature
+    let tx_hash = CacheKey::new(tx.to_string());
+
+    // Some methods are known to take their time
+    let ttl = MethodInfo::ttl_for(tx["method"].as_str(), params.ttl);
+
     // RPC used to get the response, we use it to update the latency for it later.
     let mut rpc_position;
 
-    // Rewrite named block parameters if possible
-    let mut tx = replace_block_tags(&mut tx, &cache_args.named_numbers);
-
-    // Get the response from either the DB or from a RPC. If it timeouts, retry.
-    let rax = get_response!(
-        tx,
-        cache_args,
-        tx_hash,
-        rpc_position,
-        id,
-        con_params,
-        params.ttl,
-        params.max_retries
+    let local = local_response(
+        &tx,
+        id.into(),
+        &cache_args.chain_info,
+        &cache_args.named_numbers,
     );
 
-    // Convert rx to bytes and but it in a Buf
-    let body = hyper::body::Bytes::from(rax);
+    // Wide log queries are answered a chunk at a time, so most of them can come from the cache
+    let logs = log_chunks(&tx, params.logs_chunk_size, params.logs_max_chunks);
+
+    let rax = if let Some(local) = local {
+        // Answered without the DB or an upstream
+        rpc_position = None;
+        Bytes::from(local)
+    } else if let Some(head) = micro_head {
+        let cached = cache_args
+            .micro_cache
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(head, tx_hash.as_bytes(), id.into());
+
+        match cached {
+            Some(cached) => {
+                rpc_position = None;
+                Bytes::from(cached)
+            }
+            None => {
+                let rax = fetch_from_rpc!(
+                    tx,
+                    cache_args,
+                    tx_hash,
+                    rpc_position,
+                    id,
+                    con_params,
+                    ttl,
+                    params.max_retries,
+                    uncached
+                );
+
+                if let Ok(response) = serde_json::from_str(&rax) {
+                    cache_args
+                        .micro_cache
+                        .write()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .insert(head, tx_hash.as_bytes(), response);
+                }
+                Bytes::from(rax)
+            }
+        }
+    } else if let Some(chunks) = logs {
+        rpc_position = None;
+        match fetch_logs(
+            &tx,
+            chunks,
+            id.into(),
+            &con_params.rpc_list,
+            &cache_args,
+            ttl,
+            params.max_retries,
+        )
+        .await
+        {
+            Ok(rax) => Bytes::from(rax),
+            Err(ChunkError::NoRpcAvailable) => return (no_rpc_available!(), None),
+            Err(ChunkError::TimedOut) => return (timed_out!(), None),
+        }
+    } else if MethodInfo::is_cacheable(tx["method"].as_str()) {
+        // Get the response from either the DB or from a RPC. If it timeouts, retry.
+        get_response!(
+            tx,
+            cache_args,
+            tx_hash,
+            rpc_position,
+            id,
+            con_params,
+            ttl,
+            params.max_retries
+        )
+    } else {
+        // Never cached, so don't bother looking
+        Bytes::from(fetch_from_rpc!(
+            tx,
+            cache_args,
+            tx_hash,
+            rpc_position,
+            id,
+            con_params,
+            ttl,
+            params.max_retries
+        ))
+    };
 
     // Put it in a http_body_util::Full
-    let body = Full::new(body);
+    let body = Full::new(rax);
 
     // Build the response
     let res = hyper::Response::builder()
@@ -426,6 +576,8 @@ where
             ttl: config_guard.ttl,
             max_retries: config_guard.max_retries,
             header_check: config_guard.header_check,
+            logs_chunk_size: config_guard.logs_chunk_size,
+            logs_max_chunks: config_guard.logs_max_chunks,
         }
     };
 
@@ -1,5 +1,8 @@
 use crate::{
-    rpc::method::EthRpcMethod,
+    rpc::method::{
+        BlockParam,
+        MethodInfo,
+    },
     NamedBlocknumbers,
 };
 use http_body_util::BodyExt;
@@ -71,8 +74,71 @@ fn has_named_number(param: &str) -> NamedNumber {
     NamedNumber::Null
 }
 
+/// JSON pointers to every block number in the params of `tx`.
+///
+/// Block parameters are either a tag or number, or an [EIP-1898](https://eips.ethereum.org/EIPS/eip-1898)
+/// object with a `blockNumber` or `blockHash`. `eth_getLogs` filters have a `fromBlock` and
+/// a `toBlock`, or a `blockHash`. Hashes aren't numbers, so they don't get one.
+fn block_pointers(tx: &Value) -> Vec<String> {
+    // The JSON-RPC standard is all over the place so depending on the method, we need to look at
+    // different param indexes. Why? Has i ever???
+    let Some(block_param) = MethodInfo::of(tx["method"].as_str()).map(|info| info.block_param)
+    else {
+        return Vec::new();
+    };
+    let Some(position) = block_param.position() else {
+        return Vec::new();
+    };
+    let pointer = format!("/params/{position}");
+
+    match &tx["params"][position] {
+        Value::Object(param) if param.contains_key("blockHash") => Vec::new(),
+        Value::Object(_) if matches!(block_param, BlockParam::Filter(_)) => {
+            vec![format!("{pointer}/fromBlock"), format!("{pointer}/toBlock")]
+        }
+        Value::Object(param) if param.contains_key("blockNumber") => {
+            vec![format!("{pointer}/blockNumber")]
+        }
+        Value::Object(_) => Vec::new(),
+        _ => vec![pointer],
+    }
+}
+
+/// Returns the block parameter at `pointer` without quotes.
+///
+/// Some methods default to `latest` if it's left out, like both ends of an `eth_getLogs` range.
+fn block_param(tx: &Value, pointer: &str) -> Option<String> {
+    match tx.pointer(pointer) {
+        Some(param) => Some(param.to_string().replace('\"', "")),
+        None if MethodInfo::of(tx["method"].as_str())
+            .is_some_and(|info| info.block_param.defaults_to_latest()) =>
+        {
+            Some(NamedNumber::Latest.as_ref().to_string())
+        }
+        None => None,
+    }
+}
+
+/// Returns the number a named block points to, `None` if we don't know it (yet).
+fn named_number(nn: NamedNumber, named_blocknumbers: &NamedBlocknumbers) -> Option<u64> {
+    // 0 means we don't know where the tag points to (yet), but genesis is a valid earliest block
+    let number = match nn {
+        NamedNumber::Latest => named_blocknumbers.latest,
+        NamedNumber::Earliest => return named_blocknumbers.earliest,
+        NamedNumber::Safe => named_blocknumbers.safe,
+        NamedNumber::Finalized => named_blocknumbers.finalized,
+        NamedNumber::Pending => named_blocknumbers.pending,
+        NamedNumber::Null => return None,
+    };
+
+    Some(number).filter(|&number| number != 0)
+}
+
 /// Return the blocknumber from a json-rpc request as a Option<String>,
 /// returning None if it cant find anything.
+///
+/// Block ranges return their highest block, since a reorg of any block in
+/// the range would change the response.
 pub fn get_block_number_from_request(
     tx: Value,
     named_blocknumbers: &Arc<RwLock<NamedBlocknumbers>>,
@@ -87,40 +153,45 @@ pub fn get_block_number_from_request(
         return None;
     }
 
-    // The JSON-RPC standard is all over the place so depending on the method, we need to look at
-    // different param indexes. Why? Has i ever???
-    let Some(position) = EthRpcMethod::get_position(tx["method"].as_str()) else {
-        return None;
-    };
+    let mut highest = None;
+    for pointer in block_pointers(&tx) {
+        let block_number = block_param(&tx, &pointer)?;
 
-    // Get the corresponding blockbumber from the params
-    let block_number = tx["params"][position].to_string().replace('\"', "");
-
-    // Return the corresponding named parameter from the RwLock is present
-    let nn = has_named_number(&block_number);
-    if nn != NamedNumber::Null {
-        let rwlock_guard = named_blocknumbers.read().unwrap();
-
-        match nn {
-            NamedNumber::Latest => return Some(rwlock_guard.latest),
-            NamedNumber::Earliest => return Some(rwlock_guard.earliest),
-            NamedNumber::Safe => return Some(rwlock_guard.safe),
-            NamedNumber::Finalized => return Some(rwlock_guard.finalized),
-            NamedNumber::Pending => return Some(rwlock_guard.pending),
-            NamedNumber::Null => return None,
-        }
+        // Return the corresponding named parameter from the RwLock is present
+        let nn = has_named_number(&block_number);
+        let number = if nn != NamedNumber::Null {
+            named_number(nn, &named_blocknumbers.read().unwrap())?
+        } else {
+            // Convert to decimal
+            u64::from_str_radix(block_number.strip_prefix("0x")?, 16).ok()?
+        };
+
+        highest = highest.max(Some(number));
     }
 
-    // Convert to decimal
-    let block_number = match u64::from_str_radix(&block_number[2..], 16) {
-        Ok(block_number) => block_number,
-        Err(_) => return None,
+    highest
+}
+
+/// Return the block a hash-addressed response belongs to, if it says.
+///
+/// Receipts and transactions have a `blockNumber`, blocks have a `number`.
+/// Traces might have either as a plain integer.
+pub fn get_block_number_from_response(result: &Value) -> Option<u64> {
+    let number = match result.get("blockNumber") {
+        Some(number) => number,
+        None => result.get("number")?,
     };
 
-    Some(block_number)
+    match number {
+        Value::String(number) => u64::from_str_radix(number.strip_prefix("0x")?, 16).ok(),
+        number => number.as_u64(),
+    }
 }
 
 /// Replaces block tags with a hex number and return the request
+///
+/// Block params that default to `latest` get filled in if they're missing,
+/// so a request for `latest` can't end up cached without mentioning it.
 pub fn replace_block_tags(
     tx: &mut Value,
     named_blocknumbers: &Arc<RwLock<NamedBlocknumbers>>,
@@ -131,35 +202,50 @@ pub fn replace_block_tags(
         return tx.to_owned();
     }
 
-    // Determine the correct parameter index based on the method
-    let Some(position) = EthRpcMethod::get_position(tx["method"].as_str()) else {
+    let pointers = block_pointers(tx);
+    if pointers.is_empty() {
         return tx.to_owned();
-    };
-
-    // Extract the block number parameter
-    let block_number = tx["params"][position].to_string().replace('\"', "");
-
-    // Check if the block number is a named tag
-    let nn = has_named_number(&block_number);
-    if nn != NamedNumber::Null {
-        let rwlock_guard = named_blocknumbers.read().unwrap_or_else(|e| {
-            // Handle the case where the RwLock is poisoned
-            e.into_inner()
-        });
+    }
 
-        // Replace the named block tag with its corresponding hex value
-        match nn {
-            NamedNumber::Latest => {
-                if rwlock_guard.latest != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.latest));
+    let rwlock_guard = named_blocknumbers.read().unwrap_or_else(|e| {
+        // Handle the case where the RwLock is poisoned
+        e.into_inner()
+    });
+
+    for pointer in pointers {
+        let Some(block_number) = block_param(tx, &pointer) else {
+            continue;
+        };
+
+        // Check if the block number is a named tag, and replace it with its corresponding
+        // hex value. `pending` is a moving target, so it stays.
+        let nn = has_named_number(&block_number);
+        let param = match nn {
+            NamedNumber::Null => continue,
+            NamedNumber::Pending => json!(block_number),
+            _ => {
+                match named_number(nn, &rwlock_guard) {
+                    Some(number) => json!(format!("0x{:x}", number)),
+                    None => json!(block_number),
                 }
             }
-            NamedNumber::Finalized => {
-                if rwlock_guard.finalized != 0 {
-                    tx["params"][position] = json!(format!("0x{:x}", rwlock_guard.finalized));
+        };
+
+        match tx.pointer_mut(&pointer) {
+            Some(slot) => *slot = param,
+            None => {
+                // It was left out, put it where it should've been
+                let (parent, key) = pointer.rsplit_once('/').unwrap();
+                match tx.pointer_mut(parent) {
+                    Some(Value::Object(filter)) => {
+                        filter.insert(key.to_string(), param);
+                    }
+                    Some(Value::Array(params)) if key.parse() == Ok(params.len()) => {
+                        params.push(param);
+                    }
+                    _ => (),
                 }
             }
-            _ => (),
         }
     }
 
@@ -201,7 +287,7 @@ mod tests {
     fn dummy_named_blocknumbers() -> Arc<RwLock<NamedBlocknumbers>> {
         Arc::new(RwLock::new(NamedBlocknumbers {
             latest: 10,
-            earliest: 2,
+            earliest: Some(2),
             safe: 3,
             finalized: 4,
             pending: 5,
@@ -434,6 +520,172 @@ mod tests {
         assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), expected);
     }
 
+    #[test]
+    fn replace_safe_and_earliest_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+        for (tag, expected) in [("safe", "0x3"), ("finalized", "0x4"), ("earliest", "0x2")] {
+            let mut tx = json!({
+                "method": EthRpcMethod::GetBalance,
+                "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", tag]
+            });
+            let expected = json!({
+                "method": EthRpcMethod::GetBalance,
+                "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", expected]
+            });
+
+            assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), expected);
+        }
+
+        // Genesis is a perfectly good earliest block
+        named_blocknumbers.write().unwrap().earliest = Some(0);
+        let mut tx = json!({
+            "method": EthRpcMethod::GetBalance,
+            "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "earliest"]
+        });
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers)["params"][1],
+            "0x0"
+        );
+
+        // Tags we don't know yet or that keep moving stay put
+        named_blocknumbers.write().unwrap().earliest = None;
+        named_blocknumbers.write().unwrap().safe = 0;
+        named_blocknumbers.write().unwrap().pending = 0;
+        for tag in ["earliest", "safe", "pending"] {
+            let mut tx = json!({
+                "method": EthRpcMethod::GetBalance,
+                "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", tag]
+            });
+            assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+            assert_eq!(get_block_number_from_request(tx, &named_blocknumbers), None);
+        }
+    }
+
+    #[test]
+    fn eip1898_block_param_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+
+        let mut tx = json!({
+            "method": EthRpcMethod::Call,
+            "params": [{"to": "0x0"}, {"blockNumber": "safe"}]
+        });
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers)["params"][1],
+            json!({"blockNumber": "0x3"})
+        );
+        assert_eq!(
+            get_block_number_from_request(tx, &named_blocknumbers),
+            Some(3)
+        );
+
+        let tx = json!({
+            "method": EthRpcMethod::GetBalance,
+            "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", {"blockNumber": "0x20"}]
+        });
+        assert_eq!(
+            get_block_number_from_request(tx, &named_blocknumbers),
+            Some(0x20)
+        );
+
+        // Hashes have no number to go by
+        let mut tx = json!({
+            "method": EthRpcMethod::GetBalance,
+            "params": [
+                "0x407d73d8a49eeb85d32cf465507dd71d507100c1",
+                {"blockHash": "0xabc", "requireCanonical": true}
+            ]
+        });
+        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+        assert_eq!(get_block_number_from_request(tx, &named_blocknumbers), None);
+    }
+
+    #[test]
+    fn optional_block_param_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+
+        // Left out means `latest`, so it gets pinned like `latest` would
+        let mut tx = json!({
+            "method": "eth_estimateGas",
+            "params": [{"to": "0x0"}]
+        });
+        assert_eq!(
+            get_block_number_from_request(tx.clone(), &named_blocknumbers),
+            Some(10)
+        );
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers)["params"],
+            json!([{"to": "0x0"}, "0xa"])
+        );
+
+        let mut tx = json!({
+            "method": "eth_getProof",
+            "params": ["0x0", ["0x1"], "finalized"]
+        });
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers)["params"][2],
+            "0x4"
+        );
+
+        // Unknown methods are left alone
+        let mut tx = json!({
+            "method": "eth_somethingNew",
+            "params": ["latest"]
+        });
+        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+    }
+
+    #[test]
+    fn get_logs_range_test() {
+        let named_blocknumbers = dummy_named_blocknumbers();
+
+        let mut tx = json!({
+            "method": EthRpcMethod::GetLogs,
+            "params": [{"fromBlock": "finalized", "toBlock": "latest", "address": "0x0"}]
+        });
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers)["params"][0],
+            json!({"fromBlock": "0x4", "toBlock": "0xa", "address": "0x0"})
+        );
+        // The whole range goes if any of it reorgs, so it lives at the top of it
+        assert_eq!(
+            get_block_number_from_request(tx, &named_blocknumbers),
+            Some(10)
+        );
+
+        // Missing ends are `latest`
+        let mut tx = json!({
+            "method": EthRpcMethod::GetLogs,
+            "params": [{"fromBlock": "0x2"}]
+        });
+        assert_eq!(
+            get_block_number_from_request(tx.clone(), &named_blocknumbers),
+            Some(10)
+        );
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers)["params"][0],
+            json!({"fromBlock": "0x2", "toBlock": "0xa"})
+        );
+
+        // And stay spelled out if we don't know where `latest` is
+        named_blocknumbers.write().unwrap().latest = 0;
+        let mut tx = json!({
+            "method": EthRpcMethod::GetLogs,
+            "params": [{"fromBlock": "0x2"}]
+        });
+        assert_eq!(
+            replace_block_tags(&mut tx, &named_blocknumbers)["params"][0],
+            json!({"fromBlock": "0x2", "toBlock": "latest"})
+        );
+        assert_eq!(get_block_number_from_request(tx, &named_blocknumbers), None);
+
+        let mut tx = json!({
+            "method": EthRpcMethod::GetLogs,
+            "params": [{"blockHash": "0xabc"}]
+        });
+        assert_eq!(replace_block_tags(&mut tx, &named_blocknumbers), tx);
+        assert_eq!(get_block_number_from_request(tx, &named_blocknumbers), None);
+    }
+
     #[test]
     fn keep_hex_block_number_test() {
         let named_blocknumbers = dummy_named_blocknumbers();
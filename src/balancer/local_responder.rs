@@ -0,0 +1,125 @@
+//! Answers requests we already know the answer to.
+//!
+//! `eth_chainId` and `net_version` can't change while we're running, and `eth_blockNumber`
+//! is whatever newHeads last told us. None of these need the DB or an upstream.
+
+use crate::{
+    health::safe_block::NamedBlocknumbers,
+    rpc::{
+        method::EthRpcMethod,
+        types::ChainInfo,
+    },
+};
+
+use std::sync::{
+    Arc,
+    RwLock,
+};
+
+use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
+
+/// Returns the response to `tx` if we can answer it locally, with `id` set.
+///
+/// `eth_blockNumber` is only answered while `latest` is fresh. The newHeads
+/// subscription sets it to 0 if we stop getting new heads in time.
+pub fn local_response(
+    tx: &Value,
+    id: Value,
+    chain_info: &ChainInfo,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
+) -> Option<String> {
+    let method = EthRpcMethod::try_from(tx["method"].as_str()).ok()?;
+
+    let result = match method {
+        EthRpcMethod::ChainId => json!(format!("0x{:x}", chain_info.chain_id?)),
+        EthRpcMethod::NetVersion => json!(chain_info.net_version.as_ref()?),
+        EthRpcMethod::BlockNumber => {
+            let latest = named_numbers
+                .read()
+                .unwrap_or_else(|e| {
+                    // Handle the case where the RwLock is poisoned
+                    e.into_inner()
+                })
+                .latest;
+
+            if latest == 0 {
+                return None;
+            }
+            json!(format!("0x{:x}", latest))
+        }
+        _ => return None,
+    };
+
+    metrics::counter!("local_responses_total", "method" => method.as_str()).increment(1);
+
+    Some(json!({"jsonrpc": "2.0", "id": id, "result": result}).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain_info() -> ChainInfo {
+        ChainInfo {
+            chain_id: Some(1),
+            net_version: Some("1".to_string()),
+        }
+    }
+
+    #[test]
+    fn test_chain_constants() {
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+
+        let tx = json!({"jsonrpc": "2.0", "method": "eth_chainId", "params": []});
+        assert_eq!(
+            local_response(&tx, json!(3), &chain_info(), &named_numbers).unwrap(),
+            r#"{"id":3,"jsonrpc":"2.0","result":"0x1"}"#
+        );
+
+        let tx = json!({"jsonrpc": "2.0", "method": "net_version", "params": []});
+        assert_eq!(
+            local_response(&tx, json!(4), &chain_info(), &named_numbers).unwrap(),
+            r#"{"id":4,"jsonrpc":"2.0","result":"1"}"#
+        );
+    }
+
+    #[test]
+    fn test_unverified_chain_is_forwarded() {
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let chain_info = ChainInfo::default();
+
+        let tx = json!({"jsonrpc": "2.0", "method": "eth_chainId", "params": []});
+        assert!(local_response(&tx, json!(1), &chain_info, &named_numbers).is_none());
+
+        let tx = json!({"jsonrpc": "2.0", "method": "net_version", "params": []});
+        assert!(local_response(&tx, json!(1), &chain_info, &named_numbers).is_none());
+    }
+
+    #[test]
+    fn test_block_number() {
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let tx = json!({"jsonrpc": "2.0", "method": "eth_blockNumber", "params": []});
+
+        // Stale or unknown head
+        assert!(local_response(&tx, json!(1), &chain_info(), &named_numbers).is_none());
+
+        named_numbers.write().unwrap().latest = 0x10;
+        assert_eq!(
+            local_response(&tx, json!(1), &chain_info(), &named_numbers).unwrap(),
+            r#"{"id":1,"jsonrpc":"2.0","result":"0x10"}"#
+        );
+    }
+
+    #[test]
+    fn test_other_methods_are_forwarded() {
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        named_numbers.write().unwrap().latest = 0x10;
+
+        let tx = json!({"jsonrpc": "2.0", "method": "eth_getBalance", "params": ["0x0", "latest"]});
+        assert!(local_response(&tx, json!(1), &chain_info(), &named_numbers).is_none());
+    }
+}
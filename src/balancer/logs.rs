@@ -0,0 +1,367 @@
+//! Splitting wide `eth_getLogs` queries into chunks we can cache.
+//!
+//! Ranges are cut into chunks aligned to multiples of the chunk size, so scans that
+//! overlap end up asking for the same chunks. Every chunk is its own request as far
+//! as the cache is concerned, and the ones we don't have are fetched in parallel from
+//! different nodes. The client gets the logs of all of them, in order, as one response.
+
+use crate::{
+    balancer::{
+        processing::{
+            cache_query,
+            get_cached,
+            CacheArgs,
+        },
+        selection::select::{
+            freshest,
+            pick,
+        },
+    },
+    database::{
+        key::CacheKey,
+        types::GenericBytes,
+    },
+    rpc::method::EthRpcMethod,
+    Rpc,
+};
+
+use std::{
+    sync::{
+        Arc,
+        RwLock,
+    },
+    time::Duration,
+};
+
+use futures::{
+    stream,
+    StreamExt,
+};
+use serde_json::{
+    json,
+    Value,
+};
+use tokio::time::timeout;
+
+/// Why we couldn't get a chunk.
+#[derive(Debug, PartialEq)]
+pub enum ChunkError {
+    NoRpcAvailable,
+    TimedOut,
+}
+
+/// Returns the chunks an `eth_getLogs` should be split into, if it's worth splitting.
+///
+/// Filters by block hash, or with ends we couldn't resolve to a number, are left alone.
+/// So are ranges that fit in one chunk, or would need more than `max_chunks`.
+pub fn log_chunks(tx: &Value, chunk_size: u64, max_chunks: usize) -> Option<Vec<(u64, u64)>> {
+    if chunk_size == 0 || tx["method"].as_str() != Some(EthRpcMethod::GetLogs.as_str()) {
+        return None;
+    }
+
+    let filter = tx["params"].get(0)?.as_object()?;
+    if filter.contains_key("blockHash") {
+        return None;
+    }
+
+    let number = |key| {
+        let number = filter.get(key)?.as_str()?.strip_prefix("0x")?;
+        u64::from_str_radix(number, 16).ok()
+    };
+    let (from, to) = (number("fromBlock")?, number("toBlock")?);
+    if from > to {
+        return None;
+    }
+
+    // Checked before building them so huge ranges don't allocate
+    let count = to / chunk_size - from / chunk_size + 1;
+    if count < 2 || count > max_chunks as u64 {
+        return None;
+    }
+
+    Some(chunk_range(from, to, chunk_size))
+}
+
+/// Splits `from..=to` at multiples of `chunk_size`.
+///
+/// Only the first and last chunk can be partial.
+pub fn chunk_range(from: u64, to: u64, chunk_size: u64) -> Vec<(u64, u64)> {
+    let mut chunks = Vec::new();
+    let mut start = from;
+    loop {
+        let end = (start / chunk_size)
+            .saturating_mul(chunk_size)
+            .saturating_add(chunk_size - 1)
+            .min(to);
+        chunks.push((start, end));
+
+        if end == to {
+            return chunks;
+        }
+        start = end + 1;
+    }
+}
+
+/// Returns `tx` narrowed down to the blocks of `chunk`.
+fn chunk_request(tx: &Value, (from, to): (u64, u64)) -> Value {
+    let mut chunk = tx.clone();
+    chunk["params"][0]["fromBlock"] = format!("0x{:x}", from).into();
+    chunk["params"][0]["toBlock"] = format!("0x{:x}", to).into();
+    chunk
+}
+
+/// Merges the responses for every chunk into the response for the whole range.
+///
+/// If any of them isn't a list of logs, that's what the client gets instead.
+pub fn merge_chunks(id: Value, chunks: Vec<Value>) -> Value {
+    let mut logs = Vec::new();
+    for mut chunk in chunks {
+        match chunk.get_mut("result").map(Value::take) {
+            Some(Value::Array(chunk_logs)) => logs.extend(chunk_logs),
+            result => {
+                if let Some(result) = result {
+                    chunk["result"] = result;
+                }
+                chunk["id"] = id;
+                return chunk;
+            }
+        }
+    }
+
+    json!({
+        "jsonrpc": "2.0",
+        "id": id,
+        "result": logs,
+    })
+}
+
+/// Gets the response for `tx` a chunk at a time, from the cache where we can.
+///
+/// Chunks we don't have are spread over the nodes in `rpc_list`, starting with
+/// the one we would've picked for the whole thing, and a chunk that fails is
+/// retried on the next one.
+pub async fn fetch_logs<K, V>(
+    tx: &Value,
+    chunks: Vec<(u64, u64)>,
+    id: Value,
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    cache_args: &CacheArgs<K, V>,
+    ttl: u128,
+    max_retries: u32,
+) -> Result<Vec<u8>, ChunkError>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let (first, parallel) = {
+        let mut rpc_list = rpc_list.write().unwrap();
+        // About one chunk in flight per node
+        (pick(&mut rpc_list).1.unwrap_or(0), rpc_list.len().max(1))
+    };
+
+    let responses: Vec<_> = stream::iter(chunks.into_iter().enumerate())
+        .map(|(i, chunk)| fetch_chunk(tx, chunk, rpc_list, first + i, cache_args, ttl, max_retries))
+        .buffered(parallel)
+        .collect()
+        .await;
+
+    let responses = responses.into_iter().collect::<Result<_, _>>()?;
+    Ok(serde_json::to_vec(&merge_chunks(id, responses)).unwrap_or_default())
+}
+
+async fn fetch_chunk<K, V>(
+    tx: &Value,
+    (from, to): (u64, u64),
+    rpc_list: &Arc<RwLock<Vec<Rpc>>>,
+    position: usize,
+    cache_args: &CacheArgs<K, V>,
+    ttl: u128,
+    max_retries: u32,
+) -> Result<Value, ChunkError>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let chunk = chunk_request(tx, (from, to));
+    let tx_hash = CacheKey::new(chunk.to_string());
+    if let Ok(Some(cached)) = get_cached(cache_args, &tx_hash).await {
+        if let Ok(cached) = serde_json::from_slice(&cached) {
+            return Ok(cached);
+        }
+    }
+
+    let mut retries = 0;
+    loop {
+        let (rpc, cacheable) = {
+            let rpc_list = rpc_list.read().unwrap();
+
+            // Nodes that don't have the whole chunk yet would leave logs out
+            let eligible = (0..rpc_list.len())
+                .filter(|&i| rpc_list[i].has_block(to))
+                .collect::<Vec<_>>();
+            let i = match eligible.len() {
+                0 => freshest(&rpc_list).ok_or(ChunkError::NoRpcAvailable)?,
+                len => eligible[(position + retries as usize) % len],
+            };
+            // Whatever the freshest node gives us might still be missing logs, so don't keep it
+            (rpc_list[i].clone(), !eligible.is_empty())
+        };
+
+        let mut request = chunk.clone();
+        request["id"] = 0.into();
+        match timeout(
+            Duration::from_millis(ttl.try_into().unwrap_or(u64::MAX)),
+            rpc.send_request(request),
+        )
+        .await
+        {
+            Ok(Ok(mut rx)) => {
+                if let Ok(response) = serde_json::from_str(&rx) {
+                    if cacheable {
+                        cache_query(
+                            &mut rx,
+                            chunk,
+                            &tx_hash,
+                            &rpc.name,
+                            Some(rpc.status.head),
+                            cache_args,
+                        )
+                        .await;
+                    }
+                    return Ok(response);
+                }
+                tracing::warn!(
+                    rpc.name,
+                    "Got an invalid response for a chunk of logs, retrying."
+                );
+            }
+            Ok(Err(err)) => {
+                tracing::warn!(rpc.name, ?err, "Failed to get a chunk of logs, retrying.");
+            }
+            Err(_) => {
+                tracing::warn!(rpc.name, "A chunk of logs has timed out, retrying.");
+            }
+        }
+
+        retries += 1;
+        if retries >= max_retries {
+            return Err(ChunkError::TimedOut);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn get_logs(from: &str, to: &str) -> Value {
+        json!({
+            "method": "eth_getLogs",
+            "params": [{"fromBlock": from, "toBlock": to, "address": "0xabc"}],
+        })
+    }
+
+    #[test]
+    fn test_chunk_range() {
+        assert_eq!(
+            chunk_range(950, 3100, 1000),
+            vec![(950, 999), (1000, 1999), (2000, 2999), (3000, 3100)]
+        );
+        assert_eq!(chunk_range(1000, 1999, 1000), vec![(1000, 1999)]);
+        assert_eq!(chunk_range(5, 5, 1), vec![(5, 5)]);
+        assert_eq!(
+            chunk_range(u64::MAX - 1, u64::MAX, 1),
+            vec![(u64::MAX - 1, u64::MAX - 1), (u64::MAX, u64::MAX)]
+        );
+    }
+
+    #[test]
+    fn test_log_chunks() {
+        let tx = get_logs("0x3b6", "0xc1c");
+        assert_eq!(
+            log_chunks(&tx, 1000, 64),
+            Some(vec![(950, 999), (1000, 1999), (2000, 2999), (3000, 3100)])
+        );
+        // Too many, or not enough to be worth it
+        assert_eq!(log_chunks(&tx, 1000, 3), None);
+        assert_eq!(log_chunks(&tx, 10000, 64), None);
+        assert_eq!(log_chunks(&tx, 0, 64), None);
+
+        assert_eq!(log_chunks(&get_logs("latest", "0xc1c"), 1000, 64), None);
+        assert_eq!(log_chunks(&get_logs("0xc1c", "0x3b6"), 1000, 64), None);
+        assert_eq!(
+            log_chunks(
+                &json!({"method": "eth_getLogs", "params": [{"blockHash": "0x1", "fromBlock": "0x1", "toBlock": "0xfffff"}]}),
+                1000,
+                64
+            ),
+            None
+        );
+        assert_eq!(
+            log_chunks(
+                &json!({"method": "eth_call", "params": [{"fromBlock": "0x1", "toBlock": "0xfffff"}]}),
+                1000,
+                64
+            ),
+            None
+        );
+    }
+
+    #[test]
+    fn test_merge_chunks() {
+        let merged = merge_chunks(
+            7.into(),
+            vec![
+                json!({"id": 0, "result": [{"logIndex": "0x0"}]}),
+                json!({"id": 0, "result": []}),
+                json!({"id": 0, "result": [{"logIndex": "0x1"}, {"logIndex": "0x2"}]}),
+            ],
+        );
+        assert_eq!(
+            merged,
+            json!({"jsonrpc": "2.0", "id": 7, "result": [{"logIndex": "0x0"}, {"logIndex": "0x1"}, {"logIndex": "0x2"}]})
+        );
+
+        let error = json!({"id": 0, "error": {"code": -32005, "message": "range too wide"}});
+        let merged = merge_chunks(
+            7.into(),
+            vec![json!({"id": 0, "result": []}), error.clone()],
+        );
+        assert_eq!(merged["id"], 7);
+        assert_eq!(merged["error"], error["error"]);
+        assert!(merged.get("result").is_none());
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_fetch_cached_chunks() {
+        let cache_args = CacheArgs::default();
+        let tx = get_logs("0x5", "0x14");
+        let chunks = log_chunks(&tx, 10, 64).unwrap();
+
+        for (i, &chunk) in chunks.iter().enumerate() {
+            let chunk = chunk_request(&tx, chunk);
+            let tx_hash = CacheKey::new(chunk.to_string());
+            let mut rx = format!(r#"{{"jsonrpc":"2.0","id":0,"result":[{{"logIndex":"0x{i}"}}]}}"#);
+            cache_query(&mut rx, chunk, &tx_hash, "test", None, &cache_args).await;
+        }
+
+        // Nothing to ask, so we don't need any nodes
+        let rpc_list = Arc::new(RwLock::new(Vec::new()));
+        let response = fetch_logs(&tx, chunks, 3.into(), &rpc_list, &cache_args, 1000, 1)
+            .await
+            .unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Value>(&response).unwrap(),
+            json!({"jsonrpc": "2.0", "id": 3, "result": [{"logIndex": "0x0"}, {"logIndex": "0x1"}, {"logIndex": "0x2"}]})
+        );
+
+        // But we do for chunks we don't have
+        let wider = get_logs("0x5", "0x1e");
+        let chunks = log_chunks(&wider, 10, 64).unwrap();
+        assert_eq!(
+            fetch_logs(&wider, chunks, 3.into(), &rpc_list, &cache_args, 1000, 1).await,
+            Err(ChunkError::NoRpcAvailable)
+        );
+    }
+}
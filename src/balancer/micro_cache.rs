@@ -0,0 +1,224 @@
+//! Memory-only cache for requests whose answer only changes with a new head.
+//!
+//! Things like `eth_gasPrice` or an `eth_call` against `latest` can't be put in the DB,
+//! but every client asking for them within the same block gets the same answer.
+//! Entries are keyed by the request and the head they were fetched at, and get
+//! dropped as soon as `subscribe_to_new_heads` sees a new head.
+
+use crate::{
+    health::safe_block::NamedBlocknumbers,
+    rpc::method::{
+        EthRpcMethod,
+        MethodInfo,
+    },
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        RwLock,
+    },
+};
+
+use rust_tracing::deps::metrics;
+use serde_json::Value;
+
+const MICRO_CACHE_HITS: &str = "micro_cache_hits";
+const MICRO_CACHE_MISSES: &str = "micro_cache_misses";
+
+/// Max amount of responses we hold for a single head.
+///
+/// `eth_call` params are arbitrary so we need some kind of upper bound.
+const MICRO_CACHE_MAX_ENTRIES: usize = 4096;
+
+/// Responses valid for a single chain head.
+#[derive(Debug, Default)]
+pub struct HeadMicroCache {
+    head: u64,
+    entries: HashMap<Vec<u8>, Value>,
+}
+
+impl HeadMicroCache {
+    /// Get the response for `key` if it was fetched at `head`, with `id` spliced in.
+    pub fn get(&self, head: u64, key: &[u8], id: Value) -> Option<String> {
+        let cached = match self.entries.get(key) {
+            Some(cached) if head != 0 && head == self.head => cached,
+            _ => {
+                metrics::counter!(MICRO_CACHE_MISSES).increment(1);
+                return None;
+            }
+        };
+        metrics::counter!(MICRO_CACHE_HITS).increment(1);
+
+        let mut cached = cached.clone();
+        cached["id"] = id;
+        Some(cached.to_string())
+    }
+
+    /// Insert a response fetched at `head`.
+    ///
+    /// Errors, `null` results and responses for a head we already moved past are ignored.
+    pub fn insert(&mut self, head: u64, key: &[u8], mut response: Value) {
+        if head == 0
+            || head != self.head
+            || self.entries.len() >= MICRO_CACHE_MAX_ENTRIES
+            || response.get("error").is_some()
+            || response["result"].is_null()
+        {
+            return;
+        }
+
+        response["id"] = Value::Null;
+        self.entries.insert(key.to_vec(), response);
+    }
+
+    /// Move to a new head, dropping everything cached for the previous one.
+    pub fn set_head(&mut self, head: u64) {
+        if head != self.head {
+            self.head = head;
+            self.entries.clear();
+        }
+    }
+
+    /// Drop everything, even if the head number didn't change.
+    ///
+    /// Used when a reorg replaces the block at the current height.
+    pub fn clear(&mut self) {
+        self.entries.clear();
+    }
+}
+
+/// Returns true if the response to `tx` is only valid for the current head.
+pub fn is_head_scoped(tx: &Value) -> bool {
+    match EthRpcMethod::try_from(tx["method"].as_str()) {
+        Ok(EthRpcMethod::GasPrice) | Ok(EthRpcMethod::MaxPriorityFeePerGas) => true,
+        Ok(EthRpcMethod::FeeHistory) => tx["params"][1] == "latest",
+        // `eth_call` defaults to `latest` if the block param is omitted
+        Ok(EthRpcMethod::Call) => tx["params"].get(1).map_or(true, |tag| tag == "latest"),
+        _ => false,
+    }
+}
+
+/// Returns the head `tx` should be micro-cached at, or `None` if it shouldn't be.
+///
+/// We need to know the head for this to work so it only kicks in with WS enabled.
+pub fn micro_cache_head(tx: &Value, named_numbers: &Arc<RwLock<NamedBlocknumbers>>) -> Option<u64> {
+    // Writes have to reach a node every time
+    if MethodInfo::is_write(tx["method"].as_str()) || !is_head_scoped(tx) {
+        return None;
+    }
+
+    let latest = named_numbers
+        .read()
+        .unwrap_or_else(|e| {
+            // Handle the case where the RwLock is poisoned
+            e.into_inner()
+        })
+        .latest;
+
+    (latest != 0).then_some(latest)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn response(result: Value) -> Value {
+        json!({"jsonrpc": "2.0", "id": 1, "result": result})
+    }
+
+    #[test]
+    fn test_is_head_scoped() {
+        assert!(is_head_scoped(
+            &json!({"method": "eth_gasPrice", "params": []})
+        ));
+        assert!(is_head_scoped(
+            &json!({"method": "eth_maxPriorityFeePerGas", "params": []})
+        ));
+        assert!(is_head_scoped(
+            &json!({"method": "eth_feeHistory", "params": ["0x4", "latest", [25, 75]]})
+        ));
+        assert!(!is_head_scoped(
+            &json!({"method": "eth_feeHistory", "params": ["0x4", "0x10", [25, 75]]})
+        ));
+        assert!(is_head_scoped(
+            &json!({"method": "eth_call", "params": [{"to": "0x0"}, "latest"]})
+        ));
+        assert!(is_head_scoped(
+            &json!({"method": "eth_call", "params": [{"to": "0x0"}]})
+        ));
+        assert!(!is_head_scoped(
+            &json!({"method": "eth_call", "params": [{"to": "0x0"}, "0x10"]})
+        ));
+        assert!(!is_head_scoped(
+            &json!({"method": "eth_getBalance", "params": ["0x0", "latest"]})
+        ));
+    }
+
+    #[test]
+    fn test_micro_cache_head() {
+        let named_numbers = Arc::new(RwLock::new(NamedBlocknumbers::default()));
+        let tx = json!({"method": "eth_gasPrice", "params": []});
+
+        // We don't know the head yet
+        assert_eq!(micro_cache_head(&tx, &named_numbers), None);
+
+        named_numbers.write().unwrap().latest = 100;
+        assert_eq!(micro_cache_head(&tx, &named_numbers), Some(100));
+
+        let tx = json!({"method": "eth_getBalance", "params": ["0x0", "latest"]});
+        assert_eq!(micro_cache_head(&tx, &named_numbers), None);
+    }
+
+    #[test]
+    fn test_get_and_insert() {
+        let mut micro_cache = HeadMicroCache::default();
+        micro_cache.set_head(100);
+
+        micro_cache.insert(100, b"key", response(json!("0x1")));
+        assert_eq!(
+            micro_cache.get(100, b"key", json!(7)).unwrap(),
+            r#"{"id":7,"jsonrpc":"2.0","result":"0x1"}"#
+        );
+        assert!(micro_cache.get(100, b"other", json!(7)).is_none());
+        assert!(micro_cache.get(101, b"key", json!(7)).is_none());
+    }
+
+    #[test]
+    fn test_insert_ignores_stale_and_errors() {
+        let mut micro_cache = HeadMicroCache::default();
+        micro_cache.set_head(100);
+
+        // Fetched at a head we already moved past
+        micro_cache.insert(99, b"stale", response(json!("0x1")));
+        assert!(micro_cache.get(99, b"stale", json!(1)).is_none());
+        assert!(micro_cache.get(100, b"stale", json!(1)).is_none());
+
+        micro_cache.insert(100, b"null", response(Value::Null));
+        assert!(micro_cache.get(100, b"null", json!(1)).is_none());
+
+        micro_cache.insert(
+            100,
+            b"error",
+            json!({"jsonrpc": "2.0", "id": 1, "error": {"code": -32000}}),
+        );
+        assert!(micro_cache.get(100, b"error", json!(1)).is_none());
+    }
+
+    #[test]
+    fn test_set_head_clears() {
+        let mut micro_cache = HeadMicroCache::default();
+        micro_cache.set_head(100);
+        micro_cache.insert(100, b"key", response(json!("0x1")));
+
+        // Same head shouldn't clear anything
+        micro_cache.set_head(100);
+        assert!(micro_cache.get(100, b"key", json!(1)).is_some());
+
+        micro_cache.set_head(101);
+        assert!(micro_cache.get(101, b"key", json!(1)).is_none());
+        assert!(micro_cache.entries.is_empty());
+    }
+}
@@ -9,6 +9,10 @@
 
 pub mod accept_http;
 pub mod format;
+pub mod local_responder;
+pub mod logs;
+pub mod micro_cache;
 pub mod processing;
 mod response_errors;
 pub mod selection;
+pub mod splice;
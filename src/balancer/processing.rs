@@ -1,19 +1,42 @@
 use crate::{
     balancer::{
-        format::get_block_number_from_request,
+        format::{
+            get_block_number_from_request,
+            get_block_number_from_response,
+        },
+        micro_cache::HeadMicroCache,
         selection::cache_rules::{
+            cache_hash_addressed_result,
             cache_method,
             cache_result,
         },
+        splice::cacheable_body,
     },
     database::{
         accept::db_insert,
+        envelope::{
+            self,
+            EntryMeta,
+            Finality,
+        },
+        hot_cache::HotCache,
+        key::CacheKey,
         types::{
+            DbRequest,
             GenericBytes,
             RequestBus,
+            RequestKind,
         },
     },
-    health::safe_block::NamedBlocknumbers,
+    db_get,
+    health::{
+        head_cache::head_cache_key,
+        safe_block::NamedBlocknumbers,
+    },
+    rpc::{
+        method::MethodInfo,
+        types::ChainInfo,
+    },
     Rpc,
 };
 
@@ -26,11 +49,12 @@ use std::{
     time::Duration,
 };
 
-use tokio::sync::watch;
+use tokio::sync::{
+    oneshot,
+    watch,
+};
 
-use blake3::Hash;
 use serde_json::Value;
-use simd_json::to_vec;
 
 #[derive(Clone)]
 pub struct CacheArgs<K, V>
@@ -41,33 +65,42 @@ where
     pub finalized_rx: watch::Receiver<u64>,
     pub named_numbers: Arc<RwLock<NamedBlocknumbers>>,
     pub head_cache: Arc<RwLock<BTreeMap<u64, Vec<K>>>>,
+    pub micro_cache: Arc<RwLock<HeadMicroCache>>,
+    pub hot_cache: Arc<HotCache>,
+    pub chain_info: Arc<ChainInfo>,
     pub cache: RequestBus<K, V>,
+    /// Store requests next to their responses, and only serve hits for the same request.
+    pub verify_hits: bool,
 }
 
 impl CacheArgs<[u8; 32], Vec<u8>> {
     #[cfg(test)]
     /// **Note:** This should only be used for testing!
     pub fn default() -> Self {
-        use crate::database_processing;
-
-        use sled::{
-            Config,
-            Db,
+        use crate::{
+            database::memory::{
+                MemoryConfig,
+                MemoryDb,
+            },
+            database_processing,
         };
 
         use tokio::sync::mpsc;
 
-        let cache = Config::tmp().unwrap();
-        let cache = Db::open_with_config(&cache).unwrap();
+        let cache = MemoryDb::new(&MemoryConfig::default());
 
-        let (db_tx, db_rx) = mpsc::unbounded_channel();
-        tokio::task::spawn(database_processing(db_rx, cache));
+        let (db_tx, db_rx) = mpsc::channel(64);
+        tokio::task::spawn(database_processing(db_rx, cache, 4));
 
         CacheArgs {
             finalized_rx: watch::channel(0).1,
             named_numbers: Arc::new(RwLock::new(NamedBlocknumbers::default())),
             head_cache: Arc::new(RwLock::new(BTreeMap::new())),
+            micro_cache: Arc::new(RwLock::new(HeadMicroCache::default())),
+            hot_cache: Arc::new(HotCache::new(1024 * 1024)),
+            chain_info: Arc::new(ChainInfo::default()),
             cache: db_tx,
+            verify_hits: false,
         }
     }
 }
@@ -80,55 +113,189 @@ pub fn can_cache<M: AsRef<str>>(method: M, result: &str) -> bool {
     cache_method(method) && cache_result(result)
 }
 
+/// Get a cached response, checking the hot cache before asking the DB task.
+///
+/// Responses we had to get from the DB are promoted to the hot cache.
+/// If we're verifying hits, responses cached for a different request are a miss.
+pub async fn get_cached<K, V>(
+    cache_args: &CacheArgs<K, V>,
+    tx_hash: &CacheKey,
+) -> Result<Option<Vec<u8>>, oneshot::error::RecvError>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes,
+{
+    let hot_cache = &cache_args.hot_cache;
+
+    if cache_args.verify_hits {
+        if let Some(hot) = hot_cache.get_matching(tx_hash.as_bytes(), tx_hash.request()) {
+            return Ok(Some(hot));
+        }
+
+        let generation = hot_cache.generation();
+        let cached = db_get!(
+            cache_args.cache,
+            tx_hash.as_bytes().to_owned().into(),
+            matching tx_hash.request().to_vec()
+        )?;
+        if let Some(cached) = &cached {
+            hot_cache.promote(
+                tx_hash.as_bytes(),
+                Some(tx_hash.request()),
+                cached.clone(),
+                generation,
+            );
+        }
+        return Ok(cached);
+    }
+
+    if let Some(hot) = hot_cache.get(tx_hash.as_bytes()) {
+        return Ok(Some(hot));
+    }
+
+    // A reorg may delete what we read before we get to promote it
+    let generation = hot_cache.generation();
+    let mut cached = db_get!(cache_args.cache, tx_hash.as_bytes().to_owned().into())?;
+    // Might've been cached before keys were versioned. Back then requests for tags
+    // got cached too, so only trust it if none are left.
+    if let (None, Some(legacy)) = (&cached, tx_hash.legacy()) {
+        if std::str::from_utf8(tx_hash.request()).is_ok_and(cache_method) {
+            cached = db_get!(cache_args.cache, legacy.into())?;
+        }
+    }
+    if let Some(cached) = &cached {
+        hot_cache.promote(tx_hash.as_bytes(), None, cached.clone(), generation);
+    }
+    Ok(cached)
+}
+
 /// Check if we should cache the query, and if so cache it in the DB
+///
+/// `node` is the name of the node that answered, stored with the response.
+/// `node_head` is that node's head, if we know which one it is.
 pub async fn cache_query<K, V>(
     rx: &mut str,
     method: Value,
-    tx_hash: Hash,
+    tx_hash: &CacheKey,
+    node: &str,
+    node_head: Option<u64>,
     cache_args: &CacheArgs<K, V>,
 ) where
     K: GenericBytes + From<[u8; 32]>,
     V: GenericBytes + From<Vec<u8>>,
 {
-    if can_cache(method.to_string(), rx) {
-        // Insert the response hash into the head_cache
-        let num = get_block_number_from_request(method, &cache_args.named_numbers);
-
-        // Insert the key of the request we made into our `head_cache`
-        // so we can invalidate it and remove it from the DB if it reorgs.
-        if let Some(num) = num {
-            if num > *cache_args.finalized_rx.borrow() {
-                let mut head_cache = cache_args.head_cache.write().unwrap();
-                head_cache
-                    .entry(num)
-                    .or_default()
-                    .push(tx_hash.as_bytes().to_owned().into());
-            }
+    let Some(info) = MethodInfo::of(method["method"].as_str()).filter(|info| info.caches()) else {
+        return;
+    };
 
-            // Replace the id with Value::Null and insert the request.
-            //
-            // In some cases the response might not contain an ID like in
-            // https://github.com/rainshowerLabs/blutgang/issues/88.
-            // In this case we just skip inserting it into the DB as its an error.
-            //
-            // TODO: kinda cringe how we do this gymnasctics of changing things back and forth
-            let mut rx_value: Value = unsafe { simd_json::serde::from_str(rx).unwrap() };
-            if let Some(id) = rx_value.get_mut("id") {
-                *id = Value::Null;
-            } else {
-                return;
-            }
+    // A hit could never be verified, so don't bother
+    if cache_args.verify_hits && tx_hash.request().len() > envelope::MAX_REQUEST_LEN {
+        return;
+    }
 
-            drop(
-                db_insert(
-                    &cache_args.cache.clone(),
-                    tx_hash.as_bytes().to_owned().into(),
-                    to_vec(&rx_value).unwrap().into(),
-                )
-                .await,
-            );
+    // Hash-addressed requests don't say which block they're about, so we go by the response.
+    let mut parsed = None;
+    let num = if info.hash_addressed {
+        let Ok(response) = serde_json::from_str::<Value>(rx) else {
+            return;
+        };
+        if !cache_method(method.to_string()) || !cache_hash_addressed_result(&response) {
+            return;
+        }
+
+        // Things like traces don't include their block, but it can't be newer
+        // than the head of the node that answered.
+        let num = get_block_number_from_response(&response["result"])
+            .or_else(|| node_head.filter(|&head| head != 0));
+        parsed = Some(response);
+        num
+    } else if can_cache(method.to_string(), rx) {
+        get_block_number_from_request(method, &cache_args.named_numbers)
+    } else {
+        return;
+    };
+
+    // Without a block we couldn't tell when it reorgs
+    let Some(num) = num else {
+        return;
+    };
+
+    // Past the head, nodes answer with whatever they have so far. Like an empty list of logs.
+    let latest = cache_args
+        .named_numbers
+        .read()
+        .unwrap_or_else(|e| e.into_inner())
+        .latest;
+    if (latest != 0 && num > latest) || node_head.is_some_and(|head| head != 0 && num > head) {
+        return;
+    }
+
+    // Insert the key of the request we made into our `head_cache`
+    // so we can invalidate it and remove it from the DB if it reorgs.
+    let finalized = *cache_args.finalized_rx.borrow();
+    if num > finalized {
+        // The bus is bounded, so get a slot before taking the lock
+        // instead of waiting for one while holding it.
+        let permit = cache_args.cache.reserve().await;
+
+        let mut head_cache = cache_args.head_cache.write().unwrap();
+        head_cache
+            .entry(num)
+            .or_default()
+            .push(tx_hash.as_bytes().to_owned().into());
+
+        // Persist the membership so we still know this entry can reorg after a restart.
+        //
+        // We send this while holding the lock so a reorg removing
+        // the membership can't reach the DB before it.
+        if let Ok(permit) = permit {
+            let (tx, _) = oneshot::channel();
+            permit.send(DbRequest::new(
+                RequestKind::Write(
+                    head_cache_key(num, tx_hash.as_bytes()).into(),
+                    tx_hash.as_bytes().to_vec().into(),
+                ),
+                tx,
+            ));
         }
     }
+
+    // Store the response with a null id up front, so hits can splice theirs in.
+    //
+    // In some cases the response might not contain an ID like in
+    // https://github.com/rainshowerLabs/blutgang/issues/88.
+    // In this case we just skip inserting it into the DB as its an error.
+    let rx_value: Value = match parsed {
+        Some(response) => response,
+        None => unsafe { simd_json::serde::from_str(rx).unwrap() },
+    };
+    let Some(body) = cacheable_body(rx_value) else {
+        return;
+    };
+
+    let mut meta = EntryMeta::now(info.name, num, node, Finality::of(num, finalized));
+
+    // Write to both tiers
+    if cache_args.verify_hits {
+        meta = meta.with_request(tx_hash.request());
+        cache_args.hot_cache.insert_with_request(
+            tx_hash.as_bytes(),
+            tx_hash.request(),
+            body.clone(),
+        );
+    } else {
+        cache_args
+            .hot_cache
+            .insert(tx_hash.as_bytes(), body.clone());
+    }
+    drop(
+        db_insert(
+            &cache_args.cache.clone(),
+            tx_hash.as_bytes().to_owned().into(),
+            envelope::encode(&meta, &body).into(),
+        )
+        .await,
+    );
 }
 
 /// Updates the latency of an RPC node given an rpc list, its position, and the time it took for
@@ -154,10 +321,7 @@ pub fn update_rpc_latency(rpc_list: &Arc<RwLock<Vec<Rpc>>>, rpc_position: usize,
 
 #[cfg(test)]
 mod tests {
-    use crate::{
-        db_get,
-        rpc::method::EthRpcMethod,
-    };
+    use crate::rpc::method::EthRpcMethod;
     use serde_json::json;
 
     use super::*;
@@ -168,7 +332,14 @@ mod tests {
             EthRpcMethod::GetBlockByNumber,
             r#"{"result": "0x1"}"#
         ));
-        assert!(!can_cache(EthRpcMethod::Subscribe, r#"{"result": "0x1"}"#));
+        assert!(!can_cache(
+            r#"{"method": "eth_getBlockByNumber", "params": ["latest", false]}"#,
+            r#"{"result": "0x1"}"#
+        ));
+        // Writes are kept out by the method table
+        assert!(!MethodInfo::is_cacheable(Some(
+            EthRpcMethod::Subscribe.as_ref()
+        )));
     }
 
     #[test]
@@ -185,9 +356,9 @@ mod tests {
         let cache_args = CacheArgs::default();
         let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
         let method = json!({"method": EthRpcMethod::GetBlockByNumber, "params": ["0x10", false]});
-        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        let tx_hash = CacheKey::new(method.to_string());
 
-        cache_query(&mut rx, method.clone(), tx_hash, &cache_args).await;
+        cache_query(&mut rx, method.clone(), &tx_hash, "test", None, &cache_args).await;
 
         let cached_value = db_get!(cache_args.cache, tx_hash.as_bytes().to_owned())
             .unwrap()
@@ -202,9 +373,9 @@ mod tests {
         let cache_args = CacheArgs::default();
         let mut rx = r#"{ "code": -32005, "data": { "see": "https://infura.io/dashboard" }, "message": "daily request count exceeded, request rate limited" }, payload={ "id": 12449, "jsonrpc": "2.0", "method": "eth_blockNumber", "params": [  ] }"#.to_string();
         let method = json!({"method": EthRpcMethod::GetBlockByNumber, "params": ["0x10", false]});
-        let tx_hash = blake3::hash(method.to_string().as_bytes());
+        let tx_hash = CacheKey::new(method.to_string());
 
-        cache_query(&mut rx, method.clone(), tx_hash, &cache_args).await;
+        cache_query(&mut rx, method.clone(), &tx_hash, "test", None, &cache_args).await;
 
         let cached_value = db_get!(cache_args.cache, tx_hash.as_bytes().to_owned()).unwrap();
         assert!(
@@ -213,6 +384,183 @@ mod tests {
         );
     }
 
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_cache_query_by_response_block() {
+        let mut cache_args = CacheArgs::default();
+        let (_finalized_tx, finalized_rx) = watch::channel(0x10);
+        cache_args.finalized_rx = finalized_rx;
+
+        let receipt = |number: &str| {
+            format!(
+                r#"{{"jsonrpc":"2.0","id":1,"result":{{"blockNumber":{number},"contractAddress":null,"status":"0x1"}}}}"#
+            )
+        };
+        let cache = |rx: String, hash: &str| {
+            let method = json!({"method": "eth_getTransactionReceipt", "params": [hash]});
+            let tx_hash = CacheKey::new(method.to_string());
+            let cache_args = &cache_args;
+            async move {
+                cache_query(&mut rx.clone(), method, &tx_hash, "test", None, cache_args).await;
+                db_get!(cache_args.cache, tx_hash.as_bytes().to_owned())
+                    .unwrap()
+                    .map(|_| tx_hash)
+            }
+        };
+
+        // Finalized, so it's cached for good
+        assert!(cache(receipt(r#""0x10""#), "0xa").await.is_some());
+        assert!(cache_args.head_cache.read().unwrap().is_empty());
+
+        // Newer ones can still reorg
+        let tx_hash = cache(receipt(r#""0x11""#), "0xb").await.unwrap();
+        assert_eq!(
+            cache_args.head_cache.read().unwrap()[&0x11],
+            vec![*tx_hash.as_bytes()]
+        );
+
+        // Pending and unknown transactions are left alone
+        assert!(cache(receipt("null"), "0xc").await.is_none());
+        assert!(cache(
+            r#"{"jsonrpc":"2.0","id":1,"result":null}"#.to_string(),
+            "0xd"
+        )
+        .await
+        .is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_cached_promotes_to_hot_tier() {
+        let cache_args = CacheArgs::default();
+        let tx_hash = CacheKey::new("eth_getBlockByNumber".to_string());
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_none());
+
+        db_insert(
+            &cache_args.cache,
+            tx_hash.as_bytes().to_owned(),
+            b"response".to_vec(),
+        )
+        .await
+        .await
+        .unwrap();
+        assert_eq!(
+            get_cached(&cache_args, &tx_hash).await.unwrap().unwrap(),
+            b"response"
+        );
+        assert_eq!(
+            cache_args.hot_cache.get(tx_hash.as_bytes()).unwrap(),
+            b"response"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_get_cached_falls_back_to_legacy_key() {
+        let cache_args = CacheArgs::default();
+        let tx_hash = CacheKey::new("eth_getBlockByNumber".to_string());
+        let Some(legacy) = tx_hash.legacy() else {
+            return;
+        };
+
+        db_insert(&cache_args.cache, legacy, b"response".to_vec())
+            .await
+            .await
+            .unwrap();
+        assert_eq!(
+            get_cached(&cache_args, &tx_hash).await.unwrap().unwrap(),
+            b"response"
+        );
+
+        // We couldn't resolve the tag, so whatever's there is for an old head
+        let tx_hash = CacheKey::new(
+            r#"{"method":"eth_getBlockByNumber","params":["latest",false]}"#.to_string(),
+        );
+        let legacy = tx_hash.legacy().unwrap();
+        db_insert(&cache_args.cache, legacy, b"response".to_vec())
+            .await
+            .await
+            .unwrap();
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_get_cached_verifies_request() {
+        let cache_args = CacheArgs {
+            verify_hits: true,
+            ..CacheArgs::default()
+        };
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x1","id":1}"#.to_string();
+        let method = json!({"method": EthRpcMethod::GetBlockByNumber, "params": ["0x10", false]});
+        let tx_hash = CacheKey::new(method.to_string());
+
+        cache_query(&mut rx, method, &tx_hash, "test", None, &cache_args).await;
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_some());
+
+        // Another request under the same key, as if their hashes collided
+        let other = r#"{"method":"eth_getBlockByNumber","params":["0x11",false]}"#;
+        let meta = EntryMeta::now("eth_getBlockByNumber", 0x11, "test", Finality::Unknown)
+            .with_request(other.as_bytes());
+        db_insert(
+            &cache_args.cache,
+            tx_hash.as_bytes().to_owned(),
+            envelope::encode(&meta, br#"{"result":"0x2"}"#),
+        )
+        .await
+        .await
+        .unwrap();
+        cache_args.hot_cache.remove(tx_hash.as_bytes());
+
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_cache_query_hash_addressed_without_block() {
+        let cache_args = CacheArgs::default();
+        cache_args.named_numbers.write().unwrap().latest = 200;
+        let mut rx = r#"{"jsonrpc":"2.0","result":"0x5","id":1}"#.to_string();
+        let method = json!({"method": "eth_getBlockTransactionCountByHash", "params": ["0xabc"]});
+        let tx_hash = CacheKey::new(method.to_string());
+
+        // Our head says nothing about the block the node answered from
+        cache_query(&mut rx, method.clone(), &tx_hash, "test", None, &cache_args).await;
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_none());
+
+        cache_query(&mut rx, method, &tx_hash, "test", Some(100), &cache_args).await;
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_some());
+        assert!(cache_args.head_cache.read().unwrap()[&100].contains(tx_hash.as_bytes()));
+    }
+
+    #[tokio::test]
+    async fn test_cache_query_past_head() {
+        let cache_args = CacheArgs::default();
+        cache_args.named_numbers.write().unwrap().latest = 0x10;
+        let mut rx = r#"{"jsonrpc":"2.0","result":[],"id":1}"#.to_string();
+
+        // Past our head
+        let method =
+            json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x5", "toBlock": "0x11"}]});
+        let tx_hash = CacheKey::new(method.to_string());
+        cache_query(&mut rx, method, &tx_hash, "test", None, &cache_args).await;
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_none());
+
+        // Past the head of the node that answered
+        let method =
+            json!({"method": "eth_getLogs", "params": [{"fromBlock": "0x5", "toBlock": "0x10"}]});
+        let tx_hash = CacheKey::new(method.to_string());
+        cache_query(
+            &mut rx,
+            method.clone(),
+            &tx_hash,
+            "test",
+            Some(0xf),
+            &cache_args,
+        )
+        .await;
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_none());
+
+        cache_query(&mut rx, method, &tx_hash, "test", Some(0x10), &cache_args).await;
+        assert!(get_cached(&cache_args, &tx_hash).await.unwrap().is_some());
+    }
+
     #[tokio::test]
     async fn test_update_rpc_latency() {
         let rpc_list = Arc::new(RwLock::new(vec![Rpc::new(
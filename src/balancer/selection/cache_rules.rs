@@ -1,9 +1,7 @@
 use memchr::memmem;
+use serde_json::Value;
 
-use crate::{
-    balancer::format::NamedNumber,
-    rpc::method::EthRpcMethod,
-};
+use crate::balancer::format::NamedNumber;
 
 // Return true if we are supposed to be caching the input.
 //
@@ -21,10 +19,6 @@ pub fn cache_method<M: AsRef<str>>(rx: M) -> bool {
         NamedNumber::Safe.as_ref(),
         NamedNumber::Finalized.as_ref(),
         NamedNumber::Pending.as_ref(),
-        EthRpcMethod::BlockNumber.as_ref(),
-        EthRpcMethod::GetTransactionCount.as_ref(),
-        EthRpcMethod::Subscribe.as_ref(),
-        EthRpcMethod::Unsubscribe.as_ref(),
     ];
     // rx should look something like `{"id":1,"jsonrpc":"2.0","method":"eth_call","params":...`
     // Even tho rx should look like the example above, its still a valid request if the method
@@ -61,3 +55,23 @@ pub fn cache_result(rx: &str) -> bool {
 
     true
 }
+
+// Same as cache_result but for responses to hash-addressed methods.
+//
+// Receipts and the like are full of fields that are `null` on purpose, like `contractAddress`,
+// so we only refuse errors, `null` results, and transactions that are still pending.
+pub fn cache_hash_addressed_result(response: &Value) -> bool {
+    // If no-cache feature is on, return false
+    #[cfg(feature = "no-cache")]
+    return false;
+
+    let result = match response.get("result") {
+        Some(Value::Null) | None => return false,
+        Some(result) => result,
+    };
+
+    response.get("error").is_none()
+        && result
+            .get("blockNumber")
+            .map_or(true, |number| !number.is_null())
+}
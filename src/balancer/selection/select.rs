@@ -3,20 +3,49 @@ use std::time::SystemTime;
 
 // Generic entry point fn to select the next rpc and return its position
 pub fn pick(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
-    // If len is 1, return the only element
-    if list.len() == 1 {
-        return (list[0].clone(), Some(0));
-    } else if list.is_empty() {
+    pick_from(list, (0..list.len()).collect())
+}
+
+// Picks one of the nodes at `candidates`
+fn pick_from(list: &mut [Rpc], candidates: Vec<usize>) -> (Rpc, Option<usize>) {
+    // If there's only one, return it
+    if candidates.len() == 1 {
+        return (list[candidates[0]].clone(), Some(candidates[0]));
+    } else if candidates.is_empty() {
         return (Rpc::default(), None);
     }
 
-    algo(list)
+    algo(list, candidates)
 }
 
-// Sorting algo
-pub fn argsort(data: &[Rpc]) -> Vec<usize> {
-    let mut indices = (0..data.len()).collect::<Vec<usize>>();
+// Same as `pick`, but only picks nodes that should have `block`
+//
+// If none of them do, the node with the highest head is our best bet.
+pub fn pick_at(list: &mut [Rpc], block: Option<u64>) -> (Rpc, Option<usize>) {
+    let Some(block) = block else {
+        return pick(list);
+    };
+
+    let eligible = (0..list.len())
+        .filter(|&i| list[i].has_block(block))
+        .collect::<Vec<_>>();
+    if eligible.is_empty() {
+        return match freshest(list) {
+            Some(i) => (list[i].clone(), Some(i)),
+            None => pick(list),
+        };
+    }
 
+    pick_from(list, eligible)
+}
+
+// Position of the node with the highest head
+pub fn freshest(list: &[Rpc]) -> Option<usize> {
+    (0..list.len()).max_by_key(|&i| list[i].status.head)
+}
+
+// Sorting algo, over the `indices` into `data` we're picking from
+pub fn argsort(data: &[Rpc], mut indices: Vec<usize>) -> Vec<usize> {
     // Use sort_by_cached_key with a closure that compares latency
     // Uses pdqsort and does not allocate so should be fast
     indices.sort_unstable_by_key(|&index| data[index].status.latency as u64);
@@ -35,9 +64,9 @@ pub fn argsort(data: &[Rpc]) -> Vec<usize> {
     not(feature = "selection-random"),
     not(feature = "old-weighted-round-robin"),
 ))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+fn algo(list: &mut [Rpc], candidates: Vec<usize>) -> (Rpc, Option<usize>) {
     // Sort by latency
-    let indices = argsort(list);
+    let indices = argsort(list, candidates);
 
     let time = SystemTime::now()
         .duration_since(SystemTime::UNIX_EPOCH)
@@ -72,11 +101,11 @@ fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     feature = "selection-weighed-round-robin",
     feature = "selection-random"
 ))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+fn algo(list: &mut [Rpc], candidates: Vec<usize>) -> (Rpc, Option<usize>) {
     use rand::Rng;
 
     let mut rng = rand::thread_rng();
-    let index = rng.gen_range(0..list.len());
+    let index = candidates[rng.gen_range(0..candidates.len())];
     (list[index].clone(), Some(index))
 }
 
@@ -84,9 +113,9 @@ fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
     feature = "selection-weighed-round-robin",
     feature = "old-weighted-round-robin",
 ))]
-fn algo(list: &mut [Rpc]) -> (Rpc, Option<usize>) {
+fn algo(list: &mut [Rpc], candidates: Vec<usize>) -> (Rpc, Option<usize>) {
     // Sort by latency
-    let indices = argsort(list);
+    let indices = argsort(list, candidates);
 
     // Picks the second fastest one if the fastest one has maxed out
     if list[indices[0]].max_consecutive <= list[indices[0]].consecutive {
@@ -116,7 +145,7 @@ mod tests {
 
         let v = vec![rpc2, rpc3, rpc1];
         let vx = v.clone();
-        let i = argsort(&v);
+        let i = argsort(&v, (0..v.len()).collect());
         assert_eq!(i, &[2, 0, 1]);
         assert_eq!(v[0].get_url(), vx[0].get_url());
     }
@@ -200,4 +229,34 @@ mod tests {
         assert_eq!(rpc.status.latency, 7.0);
         assert_eq!(index, Some(1));
     }
+
+    // Nodes behind the block we want shouldn't be picked
+    #[test]
+    fn test_pick_at() {
+        let mut rpc_list = vec![Rpc::default(), Rpc::default(), Rpc::default()];
+        for (i, rpc) in rpc_list.iter_mut().enumerate() {
+            rpc.status.latency = i as f64;
+            rpc.max_consecutive = 10;
+        }
+        rpc_list[0].status.head = 99;
+        rpc_list[1].status.head = 100;
+        rpc_list[2].status.head = 101;
+
+        // The fastest one is behind
+        let (rpc, index) = pick_at(&mut rpc_list, Some(100));
+        assert_eq!(index, Some(1));
+        assert_eq!(rpc.status.head, 100);
+        assert_eq!(rpc_list[1].consecutive, 1);
+
+        assert_eq!(pick_at(&mut rpc_list, Some(99)).1, Some(0));
+        assert_eq!(pick_at(&mut rpc_list, None).1, Some(0));
+
+        // Nobody has it yet
+        assert_eq!(pick_at(&mut rpc_list, Some(102)).1, Some(2));
+
+        // Nodes we haven't heard from aren't ruled out
+        rpc_list[0].status.head = 0;
+        assert_eq!(pick_at(&mut rpc_list, Some(101)).1, Some(0));
+        assert_eq!(pick_at(&mut Vec::new(), Some(1)).1, None);
+    }
 }
@@ -0,0 +1,84 @@
+//! Layout of cached responses that lets us answer hits without parsing them.
+//!
+//! Responses are cached with a null `id` as their first field, so serving one is just
+//! swapping those 4 bytes for the caller's id. Values cached before this layout get
+//! parsed and reserialized like they always were.
+
+use serde_json::Value;
+
+/// Every response we cache starts with this.
+const CACHED_ID_PREFIX: &[u8] = br#"{"id":null"#;
+
+/// Serializes `response` the way we cache it, with a null `id` up front.
+///
+/// Returns `None` if it isn't an object with an `id`, which means it's an error.
+pub fn cacheable_body(mut response: Value) -> Option<Vec<u8>> {
+    response.as_object_mut()?.remove("id")?;
+    let rest = serde_json::to_vec(&response).ok()?;
+
+    let mut body = Vec::with_capacity(CACHED_ID_PREFIX.len() + rest.len() + 1);
+    body.extend_from_slice(CACHED_ID_PREFIX);
+    match rest.as_slice() {
+        b"{}" => body.push(b'}'),
+        _ => {
+            body.push(b',');
+            body.extend_from_slice(&rest[1..]);
+        }
+    }
+    Some(body)
+}
+
+/// Returns the cached response with its `id` set to `id`.
+pub fn splice_id(cached: &[u8], id: &Value) -> Vec<u8> {
+    let Some(rest) = cached.strip_prefix(CACHED_ID_PREFIX) else {
+        // Cached before we had a layout, so we have to do it the slow way.
+        // simd_json parses in place, so it gets a copy.
+        let mut scratch = cached.to_vec();
+        let Ok(mut response) = simd_json::serde::from_slice::<Value>(&mut scratch) else {
+            return cached.to_vec();
+        };
+        response["id"] = id.clone();
+        return serde_json::to_vec(&response).unwrap_or_else(|_| cached.to_vec());
+    };
+
+    let id = serde_json::to_vec(id).unwrap_or_else(|_| b"null".to_vec());
+    let mut response = Vec::with_capacity(cached.len() + id.len());
+    response.extend_from_slice(&CACHED_ID_PREFIX[..CACHED_ID_PREFIX.len() - 4]);
+    response.extend_from_slice(&id);
+    response.extend_from_slice(rest);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_splice_id() {
+        let response = json!({"jsonrpc": "2.0", "id": 7, "result": {"number": "0x10"}});
+        let body = cacheable_body(response).unwrap();
+        assert_eq!(
+            body,
+            br#"{"id":null,"jsonrpc":"2.0","result":{"number":"0x10"}}"#
+        );
+
+        assert_eq!(
+            splice_id(&body, &json!(1)),
+            br#"{"id":1,"jsonrpc":"2.0","result":{"number":"0x10"}}"#
+        );
+        let spliced: Value = serde_json::from_slice(&splice_id(&body, &json!("abc"))).unwrap();
+        assert_eq!(spliced["id"], "abc");
+        assert_eq!(spliced["result"]["number"], "0x10");
+
+        assert_eq!(cacheable_body(json!({"id": 1})).unwrap(), br#"{"id":null}"#);
+        assert!(cacheable_body(json!({"result": "0x1"})).is_none());
+    }
+
+    #[test]
+    fn test_legacy_layout() {
+        let cached = br#"{"jsonrpc":"2.0","result":"0x1","id":null}"#;
+        let spliced: Value = serde_json::from_slice(&splice_id(cached, &json!(5))).unwrap();
+        assert_eq!(spliced, json!({"jsonrpc": "2.0", "id": 5, "result": "0x1"}));
+    }
+}
@@ -3,11 +3,72 @@ use crate::{
         TAGLINE,
         VERSION_STR,
     },
-    database::types::GenericDatabase,
+    database::{
+        dedup::{
+            CONTENT_PREFIX,
+            REFS_PREFIX,
+        },
+        eviction::EVICTION_PREFIX,
+        types::GenericDatabase,
+    },
+    health::head_cache::HEAD_CACHE_PREFIX,
 };
 
+/// `blutgang_is_lb` is cached as a blake3 cache
+const BLUTGANG_IS_LB_KEY: [u8; 32] = [
+    176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53, 4, 158,
+    140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
+];
+/// `web3_clientVersion` is cached as a blake3 cache
+const CLIENT_VERSION_KEY: [u8; 32] = [
+    36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67, 121,
+    225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
+];
+/// Chain the cache was written for, as a big endian u64.
+const CHAIN_ID_KEY: &[u8] = b"chain_id";
+
+/// Returns true if `key` is blutgang's own data rather than a cached response.
+///
+/// These must never be evicted.
+pub fn is_reserved_key(key: &[u8]) -> bool {
+    key == BLUTGANG_IS_LB_KEY
+        || key == CLIENT_VERSION_KEY
+        || key == b"xxhash"
+        || key == b"blake3"
+        || key == CHAIN_ID_KEY
+        || key.starts_with(HEAD_CACHE_PREFIX)
+        || key.starts_with(CONTENT_PREFIX)
+        || key.starts_with(REFS_PREFIX)
+        || key.starts_with(EVICTION_PREFIX)
+}
+
+/// Returns the name of the hash algorithm we key cache entries with.
+pub const fn hash_algorithm() -> &'static str {
+    if cfg!(feature = "xxhash") {
+        "xxhash"
+    } else {
+        "blake3"
+    }
+}
+
+/// Returns the hash algorithm `cache` was written with, if `setup_data` ever ran on it.
+pub fn stored_hash_algorithm<DB: GenericDatabase>(cache: &DB) -> Option<&'static str> {
+    ["xxhash", "blake3"]
+        .into_iter()
+        .find(|algo| matches!(cache.read(algo.as_bytes()), Ok(Some(_))))
+}
+
+/// Returns the chain ID the cache was written for, if we know it.
+pub fn stored_chain_id<DB: GenericDatabase>(cache: &DB) -> Option<u64> {
+    let stored = cache.read(CHAIN_ID_KEY).ok()??;
+    stored.try_into().ok().map(u64::from_be_bytes)
+}
+
 /// Sets up the cache with various basic data about our current blutgang instance.
-pub fn setup_data<DB: GenericDatabase>(cache: &DB, do_clear: bool) {
+///
+/// `chain_id` is the chain our nodes are on, recorded so we never serve this cache
+/// for another one. It must already be checked against [`stored_chain_id`].
+pub fn setup_data<DB: GenericDatabase>(cache: &DB, do_clear: bool, chain_id: Option<u64>) {
     // Clear database if specified
     if do_clear {
         cache.clear().unwrap();
@@ -22,41 +83,30 @@ pub fn setup_data<DB: GenericDatabase>(cache: &DB, do_clear: bool) {
     tracing::info!("Starting Blutgang {}", VERSION_STR);
 
     // Insert kv pair `blutgang_is_lb` `true` to know what we're interacting with
-    // `blutgang_is_lb` is cached as a blake3 cache
-    let _ = cache.write(
-        [
-            176, 76, 1, 109, 13, 127, 134, 25, 55, 111, 28, 182, 82, 155, 135, 143, 204, 161, 53,
-            4, 158, 140, 22, 219, 138, 5, 57, 150, 8, 154, 17, 252,
-        ],
-        version_json.as_bytes(),
-    );
+    let _ = cache.write(BLUTGANG_IS_LB_KEY, version_json.as_bytes());
     // Insert kv pair `web3_clientVersion` `true` to know what we're interacting with
-    // `web3_clientVersion` is cached as a blake3 cache
-    let _ = cache.write(
-        [
-            36, 20, 170, 125, 105, 107, 149, 148, 52, 126, 215, 218, 112, 55, 222, 60, 186, 44, 67,
-            121, 225, 160, 31, 209, 9, 99, 81, 233, 137, 37, 62, 79,
-        ],
-        version_json.as_bytes(),
-    );
+    let _ = cache.write(CLIENT_VERSION_KEY, version_json.as_bytes());
+
+    if let Some(chain_id) = chain_id {
+        let _ = cache.write(CHAIN_ID_KEY, chain_id.to_be_bytes());
+    }
 
     // Insert which hashing algo we're using based on the selected features.
     // If `xxhash` is enabled we're using xxhash3, otherwise blake3.
     //
-    // Print a warning if we see an keys are in an unexpectd hash format.
+    // Keys are tagged with the algorithm, so entries from the other one are never
+    // served, just dead weight. Let the user know in case they want to clear them.
     if cfg!(feature = "xxhash") {
         let _ = cache.write(b"xxhash", b"true");
         if cache.read(b"blake3").unwrap().is_some() {
-            tracing::error!("Blutgang has detected that your DB is using blake3 while we're currently using xxhash! \
-                Please remove all cache entries and try again.");
-            tracing::info!("If you believe this is an error, please open a pull request!");
+            tracing::warn!("Blutgang has detected that your DB was also written with blake3 while we're currently using xxhash! \
+                Those entries won't be served, clear the cache to reclaim their space.");
         }
     } else {
         let _ = cache.write(b"blake3", b"true");
         if cache.read(b"xxhash").unwrap().is_some() {
-            tracing::error!("Blutgang has detected that your DB is using xxhash while we're currently using blake3! \
-                Please remove all cache entries and try again.");
-            tracing::info!("If you believe this is an error, please open a pull request!");
+            tracing::warn!("Blutgang has detected that your DB was also written with xxhash while we're currently using blake3! \
+                Those entries won't be served, clear the cache to reclaim their space.");
         }
     }
 }
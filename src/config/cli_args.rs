@@ -33,6 +33,9 @@ const ADMIN_OPTS: &str = "Admin Namespace Options";
     about = "Blutgang load balancer and cache. For more info read the wiki: https://github.com/rainshowerLabs/blutgang/wiki",
 )]
 pub struct Blutgang {
+    #[command(subcommand)]
+    pub command: Option<Command>,
+
     // -- Core Configuration Options
     //
     /// Path to a TOML config file for blutgang.
@@ -145,6 +148,47 @@ pub struct Blutgang {
     pub admin_key: Option<String>,
 }
 
+/// Commands that run instead of starting the load balancer.
+#[derive(Debug, clap::Subcommand, Clone, PartialEq, Eq)]
+pub enum Command {
+    /// Manage the cache DB.
+    Cache {
+        #[command(subcommand)]
+        action: CacheCommand,
+    },
+}
+
+#[derive(Debug, clap::Subcommand, Clone, PartialEq, Eq)]
+pub enum CacheCommand {
+    /// Write every cache entry into a compressed snapshot file.
+    Export {
+        /// Path of the snapshot to write.
+        #[arg(long)]
+        out: std::path::PathBuf,
+    },
+    /// Load a snapshot written by `cache export` into the cache DB.
+    Import {
+        /// Path of the snapshot to read.
+        #[arg(long = "in")]
+        input: std::path::PathBuf,
+        /// Import snapshots hashed with another algorithm (`xxhash` vs blake3) anyway.
+        /// None of their entries will be served.
+        #[arg(long)]
+        allow_other_hash: bool,
+    },
+    /// Copy every cache entry from one DB backend into another.
+    ///
+    /// Both backends are opened with the options from the config file.
+    Migrate {
+        /// Backend to copy from.
+        #[arg(long)]
+        from: Db,
+        /// Backend to copy into. Must be empty.
+        #[arg(long)]
+        to: Db,
+    },
+}
+
 #[derive(Debug, clap::Args, Clone)]
 pub struct RpcList {
     /// RPC endpoint [http(s)://]
@@ -193,11 +237,16 @@ impl RpcList {
     }
 }
 
-#[derive(Debug, Clone, Default, clap::ValueEnum)]
+// `RocksDb` is the name of the backend, not a stutter
+#[allow(clippy::enum_variant_names)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
 pub(crate) enum Db {
     #[default]
     Sled,
 
     #[clap(name = "rocksdb")]
     RocksDb,
+
+    /// Memory only, for tests and short-lived deployments.
+    Memory,
 }
@@ -13,6 +13,30 @@ pub enum ConfigError {
     #[error("Node is syncing!")]
     Syncing,
 
+    #[error(
+        "the cache belongs to chain {cache} but the RPCs are on chain {nodes}, \
+        use a different cache or clear it with `--clear-cache`"
+    )]
+    ChainMismatch { cache: u64, nodes: u64 },
+
+    #[error(
+        "couldn't tell which chain the RPCs are on, most of them have to be reachable \
+        and agree on it unless the cache was already written for a chain"
+    )]
+    UnknownChain,
+
+    #[error(
+        "deduplication can't be combined with `eviction.max_bytes`, shared bodies aren't \
+        counted against it. Use `eviction.max_entries` instead"
+    )]
+    DedupWithByteBudget,
+
+    #[error("can't migrate the cache into the backend it's already in")]
+    SameMigrationBackend,
+
+    #[error("the memory backend doesn't persist anything, so it can't be migrated from or to")]
+    MemoryMigration,
+
     #[error("failed to read config file '{}': {err:?}", config.display())]
     ReadError {
         config: path::PathBuf,
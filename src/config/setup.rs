@@ -1,10 +1,22 @@
 use crate::{
     config::error::ConfigError,
-    rpc::error::RpcError,
+    rpc::{
+        error::RpcError,
+        types::ChainInfo,
+    },
     Rpc,
 };
-use std::time::Instant;
-use tokio::sync::mpsc;
+use std::{
+    collections::HashMap,
+    time::{
+        Duration,
+        Instant,
+    },
+};
+use tokio::{
+    sync::mpsc,
+    time::timeout,
+};
 
 #[derive(Debug)]
 enum StartingLatencyResp {
@@ -100,6 +112,123 @@ pub async fn sort_by_latency(
     Ok((sorted_rpc_list, poverty_list))
 }
 
+/// Returns the value every node reported, or `None` if they don't agree.
+fn unanimous<T: PartialEq>(mut reported: impl Iterator<Item = Option<T>>) -> Option<T> {
+    let first = reported.next()??;
+    for value in reported {
+        if value.as_ref() != Some(&first) {
+            return None;
+        }
+    }
+    Some(first)
+}
+
+/// Returns the chain ID most nodes reported, or `None` on a tie.
+fn majority(reported: impl Iterator<Item = Option<u64>>) -> Option<u64> {
+    let mut counts = HashMap::<u64, usize>::new();
+    for chain_id in reported.flatten() {
+        *counts.entry(chain_id).or_default() += 1;
+    }
+
+    let most = counts.values().copied().max()?;
+    let mut leaders = counts.into_iter().filter(|(_, count)| *count == most);
+    let (chain_id, _) = leaders.next()?;
+    leaders.next().is_none().then_some(chain_id)
+}
+
+/// Returns the chain we should be serving, given what the nodes and the cache say.
+///
+/// Refuses caches written for a different chain than the one our nodes are on,
+/// and to guess if the nodes can't agree and the cache doesn't know either.
+fn expected_chain_id(
+    reported: impl Iterator<Item = Option<u64>>,
+    cached_chain_id: Option<u64>,
+) -> Result<u64, ConfigError> {
+    match (majority(reported), cached_chain_id) {
+        (Some(nodes), Some(cache)) if nodes != cache => {
+            Err(ConfigError::ChainMismatch { cache, nodes })
+        }
+        (nodes, cache) => nodes.or(cache).ok_or(ConfigError::UnknownChain),
+    }
+}
+
+/// Ask every RPC for its `eth_chainId` and `net_version`.
+///
+/// Nodes on a different chain than most of them (or than `cached_chain_id`, the chain
+/// the cache was written for) are moved to the poverty list. Returns the chain we're
+/// serving, along with the values every remaining node agrees on, so we never answer
+/// with something that depends on which node we would've picked.
+pub async fn verify_chain_info(
+    rpc_list: &mut Vec<Rpc>,
+    poverty_list: &mut Vec<Rpc>,
+    cached_chain_id: Option<u64>,
+    ttl: u64,
+) -> Result<(u64, ChainInfo), ConfigError> {
+    let ttl = Duration::from_millis(ttl);
+
+    let mut handles = Vec::with_capacity(rpc_list.len());
+    for rpc in rpc_list.iter().cloned() {
+        handles.push(tokio::spawn(async move {
+            let chain_id = timeout(ttl, rpc.chain_id()).await.ok().and_then(Result::ok);
+            let net_version = timeout(ttl, rpc.net_version())
+                .await
+                .ok()
+                .and_then(Result::ok);
+            (rpc.name, chain_id, net_version)
+        }));
+    }
+
+    let mut reported = Vec::with_capacity(handles.len());
+    for handle in handles {
+        // Keep the indexes lined up with `rpc_list`
+        reported.push(handle.await.unwrap_or_default());
+    }
+
+    let expected = expected_chain_id(
+        reported.iter().map(|(_, chain_id, _)| *chain_id),
+        cached_chain_id,
+    )?;
+
+    // Refuse nodes on another chain, we'd be caching their responses as ours
+    let mut index = 0;
+    rpc_list.retain_mut(|rpc| {
+        let (_, chain_id, _) = &reported[index];
+        index += 1;
+
+        match chain_id {
+            Some(chain_id) if *chain_id != expected => {
+                tracing::error!(
+                    rpc = rpc.name,
+                    chain_id,
+                    expected,
+                    "RPC is on a different chain! Adding to poverty list"
+                );
+                rpc.status.is_erroring = true;
+                poverty_list.push(rpc.clone());
+                false
+            }
+            _ => true,
+        }
+    });
+    reported.retain(|(_, chain_id, _)| chain_id.is_none() || *chain_id == Some(expected));
+
+    let chain_info = ChainInfo {
+        chain_id: unanimous(reported.iter().map(|(_, chain_id, _)| *chain_id)),
+        net_version: unanimous(reported.iter().map(|(_, _, version)| version.clone())),
+    };
+
+    if chain_info.chain_id.is_none() || chain_info.net_version.is_none() {
+        tracing::warn!(
+            ?reported,
+            "RPCs didn't agree on the chain they're on, chain constant methods will be forwarded"
+        );
+    } else {
+        tracing::info!(?chain_info, "Verified chain");
+    }
+
+    Ok((expected, chain_info))
+}
+
 // #[cfg(test)]
 // mod tests {
 //     use tokio::time::sleep;
@@ -167,3 +296,64 @@ pub async fn sort_by_latency(
 //         assert!(sorted_rpc_list.is_empty());
 //     }
 // }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unanimous() {
+        assert_eq!(unanimous([Some(1), Some(1), Some(1)].into_iter()), Some(1));
+        assert_eq!(unanimous([Some(1), Some(5)].into_iter()), None);
+        assert_eq!(unanimous([Some(1), None].into_iter()), None);
+        assert_eq!(unanimous([None, Some(1)].into_iter()), None);
+        assert_eq!(unanimous(std::iter::empty::<Option<u64>>()), None);
+    }
+
+    #[test]
+    fn test_majority() {
+        assert_eq!(majority([Some(1), Some(5), Some(1)].into_iter()), Some(1));
+        assert_eq!(majority([Some(1), None, None].into_iter()), Some(1));
+        assert_eq!(majority([Some(1), Some(5)].into_iter()), None);
+        assert_eq!(majority([None, None].into_iter()), None);
+    }
+
+    #[test]
+    fn test_expected_chain_id() {
+        // Fresh cache, go with the nodes
+        assert_eq!(
+            expected_chain_id([Some(1), Some(1)].into_iter(), None).unwrap(),
+            1
+        );
+        // One node timing out doesn't matter
+        assert_eq!(
+            expected_chain_id([Some(1), None].into_iter(), None).unwrap(),
+            1
+        );
+        // Nodes can't agree, the cache breaks the tie
+        assert_eq!(
+            expected_chain_id([Some(1), Some(5)].into_iter(), Some(5)).unwrap(),
+            5
+        );
+        assert_eq!(
+            expected_chain_id([None, None].into_iter(), Some(5)).unwrap(),
+            5
+        );
+        // Nobody to break the tie
+        assert!(matches!(
+            expected_chain_id([Some(1), Some(5)].into_iter(), None),
+            Err(ConfigError::UnknownChain)
+        ));
+        assert!(matches!(
+            expected_chain_id([None, None].into_iter(), None),
+            Err(ConfigError::UnknownChain)
+        ));
+        assert!(matches!(
+            expected_chain_id([Some(11155111), Some(11155111)].into_iter(), Some(1)),
+            Err(ConfigError::ChainMismatch {
+                cache: 1,
+                nodes: 11155111
+            })
+        ));
+    }
+}
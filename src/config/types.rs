@@ -3,15 +3,35 @@ use crate::{
         cli_args::{
             self,
             Blutgang,
+            CacheCommand,
+            Command,
             TERM_STYLE,
         },
         error::ConfigError,
         setup::sort_by_latency,
         types::{
-            rocksdb_config::RocksDbOptionsRepr,
+            dedup_config::DedupConfigRepr,
+            eviction_config::EvictionConfigRepr,
+            memory_config::MemoryConfigRepr,
+            rocksdb_config::{
+                RocksDbOptionsRepr,
+                RocksDbThreadMode,
+            },
             sled_config::SledConfigRepr,
+            write_behind_config::WriteBehindConfigRepr,
         },
     },
+    database::{
+        accept::{
+            default_read_concurrency,
+            DEFAULT_QUEUE_CAPACITY,
+        },
+        dedup::DedupSettings,
+        eviction::EvictionSettings,
+        memory::MemoryConfig,
+        write_behind::WriteBehindSettings,
+    },
+    health::check::HeadTolerance,
     Rpc,
 };
 use clap::{
@@ -32,8 +52,12 @@ use std::{
 
 use toml::Value;
 
+pub(crate) mod dedup_config;
+pub(crate) mod eviction_config;
+pub(crate) mod memory_config;
 pub(crate) mod rocksdb_config;
 pub(crate) mod sled_config;
+pub(crate) mod write_behind_config;
 
 #[derive(Clone)]
 pub struct AdminSettings {
@@ -70,11 +94,13 @@ impl Debug for AdminSettings {
 #[derive(Clone)]
 pub enum CacheSettings {
     Sled(sled::Config),
-    RocksDB(rocksdb::Options),
+    RocksDB(rocksdb::Options, RocksDbThreadMode),
+    Memory(MemoryConfig),
 }
 
 #[derive(Clone)]
 pub struct Settings {
+    pub command: Option<Command>,
     pub rpc_list: Vec<Rpc>,
     pub sort_on_startup: bool,
     pub ma_length: f64,
@@ -89,13 +115,36 @@ pub struct Settings {
     pub supress_rpc_check: bool,
     pub max_retries: u32,
     pub health_check_ttl: u64,
+    pub head_tolerance: HeadTolerance,
+    /// Block times the head can stand still before we consider it stuck. 0 turns it off.
+    pub stale_head_blocks: u64,
+    /// Longest we wait between probes of a node in the poverty list, in ms.
+    pub max_probe_interval: u64,
+    /// Blocks per chunk when splitting `eth_getLogs` ranges. 0 disables splitting.
+    pub logs_chunk_size: u64,
+    /// Ranges that would need more chunks than this are forwarded whole.
+    pub logs_max_chunks: usize,
     pub cache: CacheSettings,
+    /// Source and destination backends for `cache migrate`.
+    pub migration: Option<(CacheSettings, CacheSettings)>,
+    pub eviction: Option<EvictionSettings>,
+    pub write_behind: Option<WriteBehindSettings>,
+    pub dedup: DedupSettings,
+    /// Size of the in-process cache in front of the DB, in bytes. 0 disables it.
+    pub hot_cache_bytes: usize,
+    /// Store requests next to cached responses and check them on every hit.
+    pub verify_cache_hits: bool,
+    /// How many DB requests can be queued before senders have to wait.
+    pub db_queue_capacity: usize,
+    /// How many reads we let hit the DB at once.
+    pub db_read_concurrency: usize,
     pub admin: AdminSettings,
 }
 
 impl Default for Settings {
     fn default() -> Self {
         Self {
+            command: None,
             rpc_list: Vec::new(),
             sort_on_startup: false,
             ma_length: 100.0,
@@ -110,7 +159,20 @@ impl Default for Settings {
             supress_rpc_check: true,
             max_retries: 32,
             health_check_ttl: 1000,
+            head_tolerance: HeadTolerance::default(),
+            stale_head_blocks: 5,
+            max_probe_interval: 60000,
+            logs_chunk_size: 2000,
+            logs_max_chunks: 64,
             cache: CacheSettings::Sled(sled::Config::default()),
+            migration: None,
+            eviction: None,
+            write_behind: None,
+            dedup: DedupSettings::default(),
+            hot_cache_bytes: 64 * 1024 * 1024,
+            verify_cache_hits: false,
+            db_queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            db_read_concurrency: default_read_concurrency(),
             admin: AdminSettings::default(),
         }
     }
@@ -144,7 +206,10 @@ impl Settings {
         let args =
             Blutgang::from_arg_matches(&matches()).expect("failed to parse command line args");
 
-        let mut settings = Self::default();
+        let mut settings = Self {
+            command: args.command,
+            ..Self::default()
+        };
 
         let spanned_config = if let Some(config_path) = args
             .config
@@ -177,38 +242,104 @@ impl Settings {
             .and_then(|config| config.get("blutgang"))
             .and_then(|blutgang| blutgang.as_table());
 
+        // Parse the config options for a db, otherwise use default.
+        let cache_settings = |db: cli_args::Db| {
+            match db {
+                cli_args::Db::Sled => {
+                    let sled_config: SledConfigRepr = blutgang
+                        .and_then(|blutgang| blutgang.get("sled"))
+                        .and_then(|config| config.clone().try_into().ok())
+                        .flatten()
+                        .unwrap_or_default();
+
+                    CacheSettings::Sled(sled_config.into())
+                }
+                cli_args::Db::RocksDb => {
+                    let rocksdb_config: RocksDbOptionsRepr = blutgang
+                        .and_then(|blutgang| blutgang.get("rocksdb"))
+                        .and_then(|config| config.clone().try_into().ok())
+                        .flatten()
+                        .unwrap_or_default();
+
+                    let thread_mode = rocksdb_config.thread_mode();
+                    CacheSettings::RocksDB(rocksdb_config.into(), thread_mode)
+                }
+                cli_args::Db::Memory => {
+                    let memory_config: MemoryConfigRepr = blutgang
+                        .and_then(|blutgang| blutgang.get("memory"))
+                        .and_then(|config| config.clone().try_into().ok())
+                        .flatten()
+                        .unwrap_or_default();
+
+                    CacheSettings::Memory(memory_config.into())
+                }
+            }
+        };
+
         // Get the db type from the command line args, or the config, otherwise use default.
-        // Parse the config options for the db, otherwise use default.
-        match args
-            .db
-            .or_else(|| {
-                blutgang.and_then(|blutgang| {
-                    blutgang.get("db").and_then(|db| {
-                        db.as_str()
-                            .and_then(|db| cli_args::Db::from_str(db, true).ok())
+        settings.cache = cache_settings(
+            args.db
+                .or_else(|| {
+                    blutgang.and_then(|blutgang| {
+                        blutgang.get("db").and_then(|db| {
+                            db.as_str()
+                                .and_then(|db| cli_args::Db::from_str(db, true).ok())
+                        })
                     })
                 })
-            })
-            .unwrap_or_default()
+                .unwrap_or_default(),
+        );
+
+        // Migrating needs both backends, regardless of which one we'd normally use
+        if let Some(Command::Cache {
+            action: CacheCommand::Migrate { from, to },
+        }) = settings.command
         {
-            cli_args::Db::Sled => {
-                let sled_config: SledConfigRepr = blutgang
-                    .and_then(|blutgang| blutgang.get("sled"))
-                    .and_then(|config| config.clone().try_into().ok())
-                    .flatten()
-                    .unwrap_or_default();
-
-                settings.cache = CacheSettings::Sled(sled_config.into());
+            if from == to {
+                return Err(ConfigError::SameMigrationBackend);
             }
-            cli_args::Db::RocksDb => {
-                let rocksdb_config: RocksDbOptionsRepr = blutgang
-                    .and_then(|blutgang| blutgang.get("rocksdb"))
-                    .and_then(|config| config.clone().try_into().ok())
-                    .flatten()
-                    .unwrap_or_default();
-
-                settings.cache = CacheSettings::RocksDB(rocksdb_config.into());
+            if from == cli_args::Db::Memory || to == cli_args::Db::Memory {
+                return Err(ConfigError::MemoryMigration);
             }
+            settings.migration = Some((cache_settings(from), cache_settings(to)));
+        }
+
+        let eviction_config: EvictionConfigRepr = blutgang
+            .and_then(|blutgang| blutgang.get("eviction"))
+            .and_then(|config| config.clone().try_into().ok())
+            .flatten()
+            .unwrap_or_default();
+        settings.eviction = eviction_config.into_settings();
+
+        let write_behind_config: WriteBehindConfigRepr = blutgang
+            .and_then(|blutgang| blutgang.get("write_behind"))
+            .and_then(|config| config.clone().try_into().ok())
+            .flatten()
+            .unwrap_or_default();
+        settings.write_behind = write_behind_config.into_settings();
+
+        let dedup_config: DedupConfigRepr = blutgang
+            .and_then(|blutgang| blutgang.get("dedup"))
+            .and_then(|config| config.clone().try_into().ok())
+            .flatten()
+            .unwrap_or_default();
+        settings.dedup = dedup_config.into();
+
+        // The memory backend evicts on its own, which would leave bodies nothing points to
+        if settings.dedup.enable && matches!(settings.cache, CacheSettings::Memory(_)) {
+            tracing::warn!("Deduplication isn't supported with the memory backend, disabling it.");
+            settings.dedup.enable = false;
+        }
+
+        // Eviction only sees the pointers, bodies are stored once no matter how many
+        // entries share them, so it can't tell how many bytes dropping an entry frees
+        if settings.dedup.enable
+            && settings
+                .eviction
+                .as_ref()
+                .is_some_and(|eviction| eviction.max_bytes.is_some())
+        {
+            return Err(ConfigError::DedupWithByteBudget);
         }
 
         let mut is_ws = true;
@@ -294,6 +425,120 @@ impl Settings {
             settings.health_check_ttl = health_check_ttl;
         }
 
+        if let Some(max_lag) = blutgang.and_then(|blutgang| {
+            blutgang.get("head_lag_tolerance").and_then(|lag| {
+                lag.as_integer().map(|lag| {
+                    lag.try_into()
+                        .expect("failed to convert `head_lag_tolerance` into `u64`")
+                })
+            })
+        }) {
+            settings.head_tolerance.max_lag = max_lag;
+        }
+
+        if let Some(max_lead) = blutgang.and_then(|blutgang| {
+            blutgang.get("head_outlier_threshold").and_then(|lead| {
+                lead.as_integer().map(|lead| {
+                    lead.try_into()
+                        .expect("failed to convert `head_outlier_threshold` into `u64`")
+                })
+            })
+        }) {
+            settings.head_tolerance.max_lead = max_lead;
+        }
+
+        if let Some(stale_head_blocks) = blutgang.and_then(|blutgang| {
+            blutgang.get("stale_head_blocks").and_then(|blocks| {
+                blocks.as_integer().map(|blocks| {
+                    blocks
+                        .try_into()
+                        .expect("failed to convert `stale_head_blocks` into `u64`")
+                })
+            })
+        }) {
+            settings.stale_head_blocks = stale_head_blocks;
+        }
+
+        if let Some(max_probe_interval) = blutgang.and_then(|blutgang| {
+            blutgang.get("max_probe_interval").and_then(|interval| {
+                interval.as_integer().map(|interval| {
+                    interval
+                        .try_into()
+                        .expect("failed to convert `max_probe_interval` into `u64`")
+                })
+            })
+        }) {
+            settings.max_probe_interval = max_probe_interval;
+        }
+
+        if let Some(logs_chunk_size) = blutgang.and_then(|blutgang| {
+            blutgang.get("logs_chunk_size").and_then(|size| {
+                size.as_integer().map(|size| {
+                    size.try_into()
+                        .expect("failed to convert `logs_chunk_size` into `u64`")
+                })
+            })
+        }) {
+            settings.logs_chunk_size = logs_chunk_size;
+        }
+
+        if let Some(logs_max_chunks) = blutgang.and_then(|blutgang| {
+            blutgang.get("logs_max_chunks").and_then(|chunks| {
+                chunks.as_integer().map(|chunks| {
+                    chunks
+                        .try_into()
+                        .expect("failed to convert `logs_max_chunks` into `usize`")
+                })
+            })
+        }) {
+            settings.logs_max_chunks = logs_max_chunks;
+        }
+
+        if let Some(hot_cache_bytes) = blutgang.and_then(|blutgang| {
+            blutgang.get("hot_cache_bytes").and_then(|bytes| {
+                bytes.as_integer().map(|bytes| {
+                    bytes
+                        .try_into()
+                        .expect("failed to convert `hot_cache_bytes` into `usize`")
+                })
+            })
+        }) {
+            settings.hot_cache_bytes = hot_cache_bytes;
+        }
+
+        if let Some(verify_cache_hits) = blutgang.and_then(|blutgang| {
+            blutgang
+                .get("verify_cache_hits")
+                .and_then(|verify| verify.as_bool())
+        }) {
+            settings.verify_cache_hits = verify_cache_hits;
+        }
+
+        if let Some(db_queue_capacity) = blutgang.and_then(|blutgang| {
+            blutgang.get("db_queue_capacity").and_then(|capacity| {
+                capacity.as_integer().map(|capacity| {
+                    capacity
+                        .try_into()
+                        .expect("failed to convert `db_queue_capacity` into `usize`")
+                })
+            })
+        }) {
+            // Tokio panics on a zero capacity channel
+            settings.db_queue_capacity = usize::max(db_queue_capacity, 1);
+        }
+
+        if let Some(db_read_concurrency) = blutgang.and_then(|blutgang| {
+            blutgang.get("db_read_concurrency").and_then(|concurrency| {
+                concurrency.as_integer().map(|concurrency| {
+                    concurrency
+                        .try_into()
+                        .expect("failed to convert `db_read_concurrency` into `usize`")
+                })
+            })
+        }) {
+            settings.db_read_concurrency = usize::max(db_read_concurrency, 1);
+        }
+
         if args.clear_cache {
             settings.do_clear = args.clear_cache;
         } else if args.no_clear_cache {
@@ -493,6 +738,18 @@ impl Settings {
 
         Ok(settings)
     }
+
+    /// How long the head can stand still before we consider it stuck, if we check at all.
+    pub fn stale_head_after(&self) -> Option<std::time::Duration> {
+        match (self.expected_block_time, self.stale_head_blocks) {
+            (0, _) | (_, 0) => None,
+            (block_time, blocks) => {
+                Some(std::time::Duration::from_millis(
+                    block_time.saturating_mul(blocks),
+                ))
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -552,4 +809,106 @@ mod tests {
             rpc_url
         );
     }
+
+    #[test]
+    fn test_cache_subcommands() {
+        use crate::config::cli_args::{
+            CacheCommand,
+            Command,
+        };
+
+        let settings = super::Settings::try_parse(|| {
+            command(
+                ["cache", "export", "--out", "snapshot.blut"]
+                    .map(ToString::to_string)
+                    .to_vec(),
+                false,
+            )
+        })
+        .unwrap();
+        assert_eq!(
+            settings.command,
+            Some(Command::Cache {
+                action: CacheCommand::Export {
+                    out: "snapshot.blut".into()
+                }
+            })
+        );
+
+        let settings = super::Settings::try_parse(|| {
+            command(
+                ["cache", "import", "--in", "snapshot.blut"]
+                    .map(ToString::to_string)
+                    .to_vec(),
+                false,
+            )
+        })
+        .unwrap();
+        assert_eq!(
+            settings.command,
+            Some(Command::Cache {
+                action: CacheCommand::Import {
+                    input: "snapshot.blut".into(),
+                    allow_other_hash: false,
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_migrate_subcommand() {
+        let migrate = |from: &str, to: &str| {
+            super::Settings::try_parse(|| {
+                command(
+                    ["cache", "migrate", "--from", from, "--to", to]
+                        .map(ToString::to_string)
+                        .to_vec(),
+                    true,
+                )
+            })
+        };
+
+        let settings = migrate("sled", "rocksdb").unwrap();
+        assert!(matches!(
+            settings.migration,
+            Some((
+                super::CacheSettings::Sled(_),
+                super::CacheSettings::RocksDB(..)
+            ))
+        ));
+
+        assert!(matches!(
+            migrate("sled", "sled"),
+            Err(crate::config::error::ConfigError::SameMigrationBackend)
+        ));
+    }
+
+    #[test]
+    fn test_dedup_with_byte_budget() {
+        // Eviction and dedup are both off in the example, with a byte budget set
+        let example = std::fs::read_to_string(config_path_str()).unwrap();
+        let config = example
+            .replace(
+                "[blutgang.eviction]\n# Enable evicting entries from the cache\nenable = false",
+                "[blutgang.eviction]\nenable = true",
+            )
+            .replace(
+                "enable = false\n# Smaller bodies are stored inline",
+                "enable = true\n# Smaller bodies are stored inline",
+            );
+        assert_eq!(config.matches("enable = true").count(), 2);
+
+        let path = std::env::temp_dir().join("blutgang_dedup_with_byte_budget.toml");
+        std::fs::write(&path, config).unwrap();
+        let settings = super::Settings::try_parse(|| {
+            let cmd = ["blutgang", "-c", path.to_str().unwrap()];
+            Blutgang::command().get_matches_from(cmd)
+        });
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(matches!(
+            settings,
+            Err(crate::config::error::ConfigError::DedupWithByteBudget)
+        ));
+    }
 }
@@ -0,0 +1,27 @@
+use crate::database::dedup::DedupSettings;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// Options for storing identical response bodies once.
+#[non_exhaustive]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct DedupConfigRepr {
+    pub enable: Option<bool>,
+    /// Bodies smaller than this many **bytes** are stored inline.
+    pub min_bytes: Option<usize>,
+}
+
+impl From<DedupConfigRepr> for DedupSettings {
+    fn from(repr: DedupConfigRepr) -> Self {
+        let mut settings = DedupSettings::default();
+        if let Some(enable) = repr.enable {
+            settings.enable = enable;
+        }
+        if let Some(min_bytes) = repr.min_bytes {
+            settings.min_bytes = min_bytes;
+        }
+        settings
+    }
+}
@@ -0,0 +1,64 @@
+use crate::database::eviction::{
+    EvictionPolicy,
+    EvictionSettings,
+};
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::time::Duration;
+
+/// Options for evicting entries from the cache DB.
+#[non_exhaustive]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct EvictionConfigRepr {
+    pub enable: Option<bool>,
+    /// `lru` to drop the least recently used entries first, `ttl` to only
+    /// count from when an entry was cached. Defaults to `lru`.
+    pub policy: Option<String>,
+    /// Max size of cached keys and values in **bytes**.
+    pub max_bytes: Option<u64>,
+    /// Max amount of cached entries.
+    pub max_entries: Option<u64>,
+    /// Drop entries that weren't used for this many seconds.
+    pub ttl_secs: Option<u64>,
+    /// How often to check the limits in ms. Defaults to every 10s.
+    pub interval_ms: Option<u64>,
+}
+
+impl EvictionConfigRepr {
+    /// Returns the settings to evict with, or `None` if eviction is off
+    /// or there's nothing to enforce.
+    pub fn into_settings(self) -> Option<EvictionSettings> {
+        if !self.enable.unwrap_or(false) {
+            return None;
+        }
+        if self.max_bytes.is_none() && self.max_entries.is_none() && self.ttl_secs.is_none() {
+            tracing::warn!("Eviction is enabled without any limits, ignoring it.");
+            return None;
+        }
+
+        let mut settings = EvictionSettings {
+            max_bytes: self.max_bytes,
+            max_entries: self.max_entries,
+            ttl: self.ttl_secs.map(Duration::from_secs),
+            ..Default::default()
+        };
+
+        if let Some(policy) = self.policy {
+            settings.policy = match policy.to_lowercase().as_str() {
+                "lru" => EvictionPolicy::Lru,
+                "ttl" => EvictionPolicy::Ttl,
+                _ => {
+                    tracing::warn!(policy, "Unknown eviction policy, defaulting to `lru`.");
+                    EvictionPolicy::Lru
+                }
+            };
+        }
+        if let Some(interval_ms) = self.interval_ms.filter(|&ms| ms != 0) {
+            settings.interval = Duration::from_millis(interval_ms);
+        }
+
+        Some(settings)
+    }
+}
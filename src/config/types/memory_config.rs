@@ -0,0 +1,25 @@
+use crate::database::memory::MemoryConfig;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+
+/// A list of options that can be applied to [`MemoryConfig`].
+#[non_exhaustive]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct MemoryConfigRepr {
+    /// Cache size in **bytes**. Default is 512mb.
+    pub capacity_bytes: Option<u64>,
+}
+
+impl From<MemoryConfigRepr> for MemoryConfig {
+    fn from(repr: MemoryConfigRepr) -> Self {
+        let mut config = Self::default();
+
+        if let Some(to) = repr.capacity_bytes {
+            config.capacity_bytes = to;
+        }
+
+        config
+    }
+}
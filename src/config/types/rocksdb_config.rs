@@ -9,6 +9,8 @@ use std::path::PathBuf;
 #[derive(Debug, Default, Serialize, Deserialize, Clone)]
 pub struct RocksDbOptionsRepr {
     pub enable: Option<bool>,
+    /// `single` or `multi`, see [`RocksDbThreadMode`].
+    pub thread_mode: Option<String>,
     // General options
     pub create_if_missing: Option<bool>,
     pub create_missing_column_families: Option<bool>,
@@ -48,6 +50,35 @@ pub struct RocksDbOptionsRepr {
     pub bottommost_compression_type: Option<String>,
 }
 
+/// Which [`rocksdb::ThreadMode`] to open the DB with.
+///
+/// Both allow concurrent reads and writes. `Multi` also allows creating and dropping
+/// column families through a shared handle, at the cost of an extra lock around them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum RocksDbThreadMode {
+    #[default]
+    Single,
+    Multi,
+}
+
+impl RocksDbOptionsRepr {
+    pub fn thread_mode(&self) -> RocksDbThreadMode {
+        match self
+            .thread_mode
+            .as_deref()
+            .map(str::to_lowercase)
+            .as_deref()
+        {
+            None | Some("single") => RocksDbThreadMode::Single,
+            Some("multi") => RocksDbThreadMode::Multi,
+            Some(mode) => {
+                tracing::warn!(mode, "Unknown RocksDB thread mode, defaulting to `single`.");
+                RocksDbThreadMode::Single
+            }
+        }
+    }
+}
+
 impl From<RocksDbOptionsRepr> for rocksdb::Options {
     fn from(repr: RocksDbOptionsRepr) -> Self {
         let mut opts = Self::default();
@@ -0,0 +1,41 @@
+use crate::database::write_behind::WriteBehindSettings;
+use serde::{
+    Deserialize,
+    Serialize,
+};
+use std::time::Duration;
+
+/// Options for batching cache inserts before they're written to the DB.
+#[non_exhaustive]
+#[derive(Debug, Serialize, Deserialize, Clone, Default)]
+pub struct WriteBehindConfigRepr {
+    pub enable: Option<bool>,
+    /// Max amount of buffered inserts.
+    pub max_entries: Option<usize>,
+    /// Max size of buffered keys and values in **bytes**.
+    pub max_bytes: Option<usize>,
+    /// Max time an insert stays buffered in ms.
+    pub interval_ms: Option<u64>,
+}
+
+impl WriteBehindConfigRepr {
+    /// Returns the settings to batch with, or `None` if batching is off.
+    pub fn into_settings(self) -> Option<WriteBehindSettings> {
+        if !self.enable.unwrap_or(false) {
+            return None;
+        }
+
+        let mut settings = WriteBehindSettings::default();
+        if let Some(max_entries) = self.max_entries.filter(|&n| n != 0) {
+            settings.max_entries = max_entries;
+        }
+        if let Some(max_bytes) = self.max_bytes.filter(|&n| n != 0) {
+            settings.max_bytes = max_bytes;
+        }
+        if let Some(interval_ms) = self.interval_ms.filter(|&ms| ms != 0) {
+            settings.interval = Duration::from_millis(interval_ms);
+        }
+
+        Some(settings)
+    }
+}
@@ -1,43 +1,277 @@
-use crate::database::types::{
-    Batch,
-    DbRequest,
-    GenericBytes,
-    GenericDatabase,
-    RequestKind,
+use crate::database::{
+    envelope,
+    types::{
+        Batch,
+        BatchOp,
+        DbRequest,
+        GenericBytes,
+        GenericDatabase,
+        RequestKind,
+        RequestSender,
+        DB_QUEUE_DEPTH,
+        DB_READS_IN_FLIGHT,
+    },
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+    },
+    time::Duration,
 };
+
+use rust_tracing::deps::metrics;
 use tokio::sync::{
-    mpsc::UnboundedSender,
+    mpsc::{
+        self,
+        Receiver as MpscReceiver,
+        Sender,
+    },
     oneshot::{
         self,
         Receiver,
     },
+    Semaphore,
 };
 
+/// Default capacity of the request bus, see [`database_processing`].
+pub const DEFAULT_QUEUE_CAPACITY: usize = 4096;
+/// How often we sample metrics that are too expensive to update per write.
+const METRICS_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Work for the writer thread.
+enum WriteJob<K, V>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    /// Along with the keys it writes, to take out of [`Pending`] once it's done.
+    Request(RequestKind<K, V>, RequestSender, Vec<Vec<u8>>),
+    Maintain,
+    SampleMetrics,
+}
+
+/// How many queued writes touch each key.
+type Pending = Arc<Mutex<HashMap<Vec<u8>, usize>>>;
+
+fn lock_pending(pending: &Pending) -> std::sync::MutexGuard<'_, HashMap<Vec<u8>, usize>> {
+    pending.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// Returns the keys `request` writes to.
+fn written_keys<K, V>(request: &RequestKind<K, V>) -> Vec<Vec<u8>>
+where
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    match request {
+        RequestKind::Write(key, _) => vec![key.as_ref().to_vec()],
+        RequestKind::Batch(batch) => {
+            batch
+                .ops()
+                .map(|op| {
+                    match op {
+                        BatchOp::Insert(key, _) | BatchOp::Delete(key) => key.as_ref().to_vec(),
+                    }
+                })
+                .collect()
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Read `key`, without the metadata we stored with it.
+///
+/// With `request`, values cached for a different request are a miss.
+fn read<K, DB>(cache: &DB, key: K, request: Option<&[u8]>) -> Result<Option<Vec<u8>>, DB::Error>
+where
+    DB: GenericDatabase,
+    K: GenericBytes,
+{
+    cache.read(key).map(|value| {
+        value.and_then(|raw| {
+            match request {
+                Some(request) => envelope::strip_matching(raw, request),
+                None => envelope::strip(raw),
+            }
+        })
+    })
+}
+
+/// Default amount of reads we let hit the DB at once.
+pub fn default_read_concurrency() -> usize {
+    std::thread::available_parallelism()
+        .map(|threads| threads.get() * 2)
+        .unwrap_or(8)
+}
+
+fn respond<E: std::fmt::Debug>(sender: RequestSender, result: Result<Option<Vec<u8>>, E>) {
+    match result {
+        Ok(value) => {
+            let _ = sender.send(value);
+        }
+        Err(err) => {
+            tracing::error!("Db failed to send response back: {:?}", err);
+            let _ = sender.send(None);
+        }
+    }
+}
+
+/// Applies writes in the order they were sent, so a reorg deleting a key
+/// can't be overtaken by the write it's meant to undo.
+///
+/// Reads of keys that still had a write queued when they came in are sent here too,
+/// so they see it.
+fn write_lane<K, V, DB>(mut jobs: MpscReceiver<WriteJob<K, V>>, cache: Arc<DB>, pending: Pending)
+where
+    DB: GenericDatabase,
+    K: GenericBytes,
+    V: GenericBytes,
+{
+    while let Some(job) = jobs.blocking_recv() {
+        metrics::gauge!(DB_QUEUE_DEPTH, "queue" => "writes").set(jobs.len() as f64);
+
+        match job {
+            WriteJob::Request(request, sender, keys) => {
+                let result = match request {
+                    RequestKind::Write(key, val) => cache.write(key, val).map(|_| None),
+                    RequestKind::Batch(b) => cache.batch(b).map(|_| None),
+                    RequestKind::Flush => cache.flush().map(|_| None),
+                    RequestKind::Read(key) => read(cache.as_ref(), key, None),
+                    RequestKind::ReadMatching(key, request) => {
+                        read(cache.as_ref(), key, Some(&request))
+                    }
+                };
+
+                if !keys.is_empty() {
+                    let mut pending = lock_pending(&pending);
+                    for key in keys {
+                        if let Some(count) = pending.get_mut(&key) {
+                            *count -= 1;
+                            if *count == 0 {
+                                pending.remove(&key);
+                            }
+                        }
+                    }
+                }
+                respond(sender, result);
+            }
+            WriteJob::Maintain => {
+                if let Err(err) = cache.maintain() {
+                    tracing::error!(?err, "Db maintenance failed");
+                }
+            }
+            WriteJob::SampleMetrics => cache.sample_metrics(),
+        }
+    }
+
+    // Commit anything the DB is still holding on to before we go
+    if let Err(err) = cache.flush() {
+        tracing::error!(?err, "Db failed to flush on shutdown");
+    }
+}
+
 /// Processes incoming requests from clients and returns responses
+///
+/// Reads run concurrently on the blocking pool, up to `max_concurrent_reads` at a time.
+/// Writes, batches, flushes and maintenance go through a single writer thread in the
+/// order we received them. Reads of a key with a write still queued go through it as
+/// well, after that write, so a read always sees the writes sent before it.
 pub async fn database_processing<K, V, DB>(
-    mut rax: tokio::sync::mpsc::UnboundedReceiver<DbRequest<K, V>>,
+    mut rax: MpscReceiver<DbRequest<K, V>>,
     cache: DB,
+    max_concurrent_reads: usize,
 ) where
-    DB: GenericDatabase,
-    K: GenericBytes,
-    V: GenericBytes,
+    DB: GenericDatabase + 'static,
+    K: GenericBytes + 'static,
+    V: GenericBytes + 'static,
 {
-    while let Some(incoming) = rax.recv().await {
-        let result = match incoming.request {
-            RequestKind::Read(k) => cache.read(k),
-            RequestKind::Write(key, val) => cache.write(key, val).map(|_| None),
-            RequestKind::Batch(b) => cache.batch(b).map(|_| None),
-            RequestKind::Flush => cache.flush().map(|_| None),
+    let cache = Arc::new(cache);
+    let max_concurrent_reads = max_concurrent_reads.max(1);
+    let reads = Arc::new(Semaphore::new(max_concurrent_reads));
+
+    let pending = Pending::default();
+    let (write_tx, write_rx) = mpsc::channel(rax.max_capacity());
+    let writer = {
+        let cache = cache.clone();
+        let pending = pending.clone();
+        std::thread::Builder::new()
+            .name("blutgang-db-writer".to_string())
+            .spawn(move || write_lane(write_rx, cache, pending))
+            .expect("failed to spawn db writer thread")
+    };
+
+    // Backends without housekeeping never tick, so this just waits on requests
+    let mut maintenance = cache.maintenance_interval().map(|period| {
+        let mut interval = tokio::time::interval(period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    });
+    let mut metrics_interval = tokio::time::interval(METRICS_INTERVAL);
+    metrics_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let incoming = tokio::select! {
+            incoming = rax.recv() => match incoming {
+                Some(incoming) => incoming,
+                None => break,
+            },
+            _ = async { maintenance.as_mut().unwrap().tick().await }, if maintenance.is_some() => {
+                let _ = write_tx.send(WriteJob::Maintain).await;
+                continue;
+            }
+            _ = metrics_interval.tick() => {
+                let _ = write_tx.send(WriteJob::SampleMetrics).await;
+                continue;
+            }
         };
+        metrics::gauge!(DB_QUEUE_DEPTH, "queue" => "requests").set(rax.len() as f64);
 
-        if result.is_err() {
-            tracing::error!("Db failed to send response back: {:?}", result.err());
-            let _ = incoming.sender.send(None);
-            continue;
-        }
+        let (k, request) = match incoming.request {
+            RequestKind::Read(k) if !lock_pending(&pending).contains_key(k.as_ref()) => (k, None),
+            RequestKind::ReadMatching(k, request)
+                if !lock_pending(&pending).contains_key(k.as_ref()) =>
+            {
+                (k, Some(request))
+            }
+            request => {
+                let keys = written_keys(&request);
+                if !keys.is_empty() {
+                    let mut pending = lock_pending(&pending);
+                    for key in &keys {
+                        *pending.entry(key.clone()).or_default() += 1;
+                    }
+                }
+
+                let _ = write_tx
+                    .send(WriteJob::Request(request, incoming.sender, keys))
+                    .await;
+                continue;
+            }
+        };
 
-        let _ = incoming.sender.send(result.ok().flatten());
+        let permit = reads
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("read semaphore is never closed");
+        metrics::gauge!(DB_READS_IN_FLIGHT)
+            .set((max_concurrent_reads - reads.available_permits()) as f64);
+
+        let cache = cache.clone();
+        tokio::task::spawn_blocking(move || {
+            // Callers only want the response, not the metadata we stored with it
+            let result = read(cache.as_ref(), k, request.as_deref());
+            drop(permit);
+            respond(incoming.sender, result);
+        });
     }
+
+    // Let the writer drain whatever is still queued before we return
+    drop(write_tx);
+    let _ = tokio::task::spawn_blocking(move || writer.join()).await;
 }
 
 /// Macro to abstract getting the data from the DB.
@@ -45,6 +279,8 @@ pub async fn database_processing<K, V, DB>(
 /// Returns `Option<V>`, where the result is `None` if
 /// there was an error or data isn't present, or `Some` if the operation
 /// completed successfully.
+///
+/// With `matching`, values cached for a different request are a miss.
 #[macro_export]
 macro_rules! db_get {
     (
@@ -59,7 +295,24 @@ macro_rules! db_get {
         let (tx, rx) = tokio::sync::oneshot::channel();
         let req = DbRequest::new(RequestKind::Read($data), tx);
 
-        let _ = $channel.send(req);
+        let _ = $channel.send(req).await;
+
+        rx.await
+    }};
+    (
+        $channel:expr,
+        $data:expr,
+        matching $request:expr
+    ) => {{
+        use $crate::database::types::{
+            DbRequest,
+            RequestKind,
+        };
+
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let req = DbRequest::new(RequestKind::ReadMatching($data, $request), tx);
+
+        let _ = $channel.send(req).await;
 
         rx.await
     }};
@@ -67,7 +320,7 @@ macro_rules! db_get {
 
 /// Abstracts inserting data into the DB.
 pub async fn db_insert<K, V>(
-    channel: &UnboundedSender<DbRequest<K, V>>,
+    channel: &Sender<DbRequest<K, V>>,
     key: K,
     value: V,
 ) -> Receiver<Option<Vec<u8>>>
@@ -77,13 +330,13 @@ where
 {
     let (tx, rx) = oneshot::channel();
     let req = DbRequest::new(RequestKind::Write(key, value), tx);
-    let _ = channel.send(req);
+    let _ = channel.send(req).await;
     rx
 }
 
 /// Abstracts writing batch data to the DB.
 pub async fn db_batch<K, V>(
-    channel: &UnboundedSender<DbRequest<K, V>>,
+    channel: &Sender<DbRequest<K, V>>,
     batch: Batch<K, V>,
 ) -> Receiver<Option<Vec<u8>>>
 where
@@ -92,7 +345,7 @@ where
 {
     let (tx, rx) = oneshot::channel();
     let req = DbRequest::new(RequestKind::Batch(batch), tx);
-    let _ = channel.send(req);
+    let _ = channel.send(req).await;
     rx
 }
 
@@ -108,8 +361,83 @@ macro_rules! db_flush {
         let (tx, rx) = tokio::sync::oneshot::channel();
         let req: DbRequest<_, _> = DbRequest::new(RequestKind::Flush, tx);
 
-        let _ = $channel.send(req);
+        let _ = $channel.send(req).await;
 
         rx
     }};
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::memory::{
+        MemoryConfig,
+        MemoryDb,
+    };
+
+    #[tokio::test]
+    async fn test_writes_keep_order() {
+        let (db_tx, db_rx) = mpsc::channel(4);
+        tokio::task::spawn(database_processing(
+            db_rx,
+            MemoryDb::new(&MemoryConfig::default()),
+            4,
+        ));
+
+        // More writes than the queue holds, so senders have to wait on the bus
+        for i in 0..64u8 {
+            drop(db_insert(&db_tx, b"key".to_vec(), vec![i]).await);
+        }
+        let mut batch = Batch::with_capacity(1);
+        batch.delete(b"key".to_vec());
+        db_batch(&db_tx, batch).await.await.unwrap();
+
+        assert!(crate::db_get!(db_tx, b"key".to_vec()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_reads_see_queued_writes() {
+        let (db_tx, db_rx) = mpsc::channel(64);
+        tokio::task::spawn(database_processing(
+            db_rx,
+            MemoryDb::new(&MemoryConfig::default()),
+            4,
+        ));
+
+        // Nobody waits for the writes to land before reading
+        for i in 0..32u8 {
+            drop(db_insert(&db_tx, b"key".to_vec(), vec![i]).await);
+            assert_eq!(
+                crate::db_get!(db_tx, b"key".to_vec()).unwrap().unwrap(),
+                vec![i]
+            );
+        }
+
+        let mut batch = Batch::with_capacity(1);
+        batch.delete(b"key".to_vec());
+        drop(db_batch(&db_tx, batch).await);
+        assert!(crate::db_get!(db_tx, b"key".to_vec()).unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn test_concurrent_reads() {
+        let (db_tx, db_rx) = mpsc::channel(64);
+        tokio::task::spawn(database_processing(
+            db_rx,
+            MemoryDb::new(&MemoryConfig::default()),
+            4,
+        ));
+        db_insert(&db_tx, b"key".to_vec(), b"value".to_vec())
+            .await
+            .await
+            .unwrap();
+
+        let reads = (0..32).map(|_| {
+            let db_tx = db_tx.clone();
+            tokio::task::spawn(async move { crate::db_get!(db_tx, b"key".to_vec()) })
+        });
+        for read in reads {
+            assert_eq!(read.await.unwrap().unwrap().unwrap(), b"value");
+        }
+    }
+}
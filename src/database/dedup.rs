@@ -0,0 +1,430 @@
+//! Stores identical response bodies once.
+//!
+//! Lots of requests share a response, like a block fetched by number and by hash.
+//! [`Dedup`] wraps any [`GenericDatabase`] and moves bodies of at least `min_bytes`
+//! under a key derived from their blake3 hash, leaving only the envelope and a pointer
+//! under the request key:
+//!
+//! ```text
+//! | envelope | pointer magic (2) | body hash (32) |
+//! ```
+//!
+//! Bodies are reference counted and deleted along with their last pointer, so reorgs
+//! and eviction keep working on request keys as usual. Reads put the value back
+//! together, so nothing above this layer can tell the difference.
+//!
+//! Refcounts are read, modified and written back, so all writes have to come from one
+//! thread. The DB task's writer takes care of that.
+
+use crate::{
+    config::cache_setup::is_reserved_key,
+    database::{
+        envelope,
+        types::{
+            Batch,
+            BatchOp,
+            GenericBytes,
+            GenericDatabase,
+            KvIter,
+            DB_DEDUP_HITS,
+        },
+    },
+};
+
+use std::{
+    collections::HashMap,
+    time::Duration,
+};
+
+use rust_tracing::deps::metrics;
+
+/// Reserved key prefix for deduplicated bodies, followed by their hash.
+pub const CONTENT_PREFIX: &[u8; 16] = b"blutgang_content";
+/// Reserved key prefix for body refcounts, followed by the body's hash.
+pub const REFS_PREFIX: &[u8; 16] = b"blutgang_refs\0\0\0";
+
+/// Marks a body as moved. JSON can't start with it, just like the envelope magic.
+const POINTER_MAGIC: [u8; 2] = [0xb1, 0x0d];
+const POINTER_LEN: usize = POINTER_MAGIC.len() + 32;
+
+/// Which bodies to deduplicate.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DedupSettings {
+    /// Deduplicate new bodies. Bodies that already are still get resolved either way.
+    pub enable: bool,
+    /// Smaller bodies are stored inline, since a pointer and refcount cost ~90 bytes.
+    pub min_bytes: usize,
+}
+
+impl Default for DedupSettings {
+    fn default() -> Self {
+        Self {
+            enable: false,
+            min_bytes: 1024,
+        }
+    }
+}
+
+fn content_key(hash: &[u8; 32]) -> Vec<u8> {
+    [CONTENT_PREFIX.as_slice(), hash].concat()
+}
+
+fn refs_key(hash: &[u8; 32]) -> Vec<u8> {
+    [REFS_PREFIX.as_slice(), hash].concat()
+}
+
+/// Returns the hash of the body `raw` points to, if it points to one.
+fn pointee(raw: &[u8]) -> Option<[u8; 32]> {
+    let (_, body) = envelope::split(raw)?;
+    if body.len() != POINTER_LEN || !body.starts_with(&POINTER_MAGIC) {
+        return None;
+    }
+    body[POINTER_MAGIC.len()..].try_into().ok()
+}
+
+/// Refcount and pointer changes of a batch we're still planning.
+struct Plan {
+    ops: Batch<Vec<u8>, Vec<u8>>,
+    /// Refcounts as of the ops planned so far.
+    refs: HashMap<[u8; 32], u64>,
+    /// What keys we already planned point to.
+    pointees: HashMap<Vec<u8>, Option<[u8; 32]>>,
+}
+
+impl Plan {
+    fn new(capacity: usize) -> Self {
+        Self {
+            ops: Batch::with_capacity(capacity),
+            refs: HashMap::new(),
+            pointees: HashMap::new(),
+        }
+    }
+}
+
+/// A [`GenericDatabase`] that stores identical response bodies once.
+#[derive(Debug)]
+pub struct Dedup<DB> {
+    inner: DB,
+    settings: DedupSettings,
+}
+
+impl<DB: GenericDatabase> Dedup<DB> {
+    pub fn new(inner: DB, settings: DedupSettings) -> Self {
+        if settings.enable {
+            tracing::info!(?settings, "Response deduplication enabled");
+        } else {
+            tracing::info!("Cache has deduplicated responses, new ones will be stored inline");
+        }
+        Self { inner, settings }
+    }
+
+    /// Returns true if `cache` holds deduplicated bodies, so it needs a [`Dedup`]
+    /// to be read even if we aren't deduplicating anymore.
+    pub fn in_use(cache: &DB) -> bool {
+        cache.iter_prefix(REFS_PREFIX).next().is_some()
+    }
+
+    fn pointee_of(&self, plan: &mut Plan, key: &[u8]) -> Result<Option<[u8; 32]>, DB::Error> {
+        if let Some(pointee) = plan.pointees.get(key) {
+            return Ok(*pointee);
+        }
+        Ok(self.inner.read(key)?.and_then(|raw| pointee(&raw)))
+    }
+
+    fn refs_of<'p>(&self, plan: &'p mut Plan, hash: [u8; 32]) -> Result<&'p mut u64, DB::Error> {
+        let stored = match plan.refs.contains_key(&hash) {
+            true => 0,
+            false => {
+                self.inner
+                    .read(refs_key(&hash))?
+                    .and_then(|refs| refs.try_into().ok())
+                    .map_or(0, u64::from_be_bytes)
+            }
+        };
+        Ok(plan.refs.entry(hash).or_insert(stored))
+    }
+
+    fn release(&self, plan: &mut Plan, key: &[u8]) -> Result<(), DB::Error> {
+        if let Some(hash) = self.pointee_of(plan, key)? {
+            let refs = self.refs_of(plan, hash)?;
+            *refs = refs.saturating_sub(1);
+        }
+        Ok(())
+    }
+
+    fn plan_insert(&self, plan: &mut Plan, key: &[u8], value: &[u8]) -> Result<(), DB::Error> {
+        let split = envelope::split(value)
+            .filter(|(_, body)| self.settings.enable && body.len() >= self.settings.min_bytes);
+        let Some((header, body)) = split else {
+            self.release(plan, key)?;
+            plan.ops.insert(key.to_vec(), value.to_vec());
+            plan.pointees.insert(key.to_vec(), None);
+            return Ok(());
+        };
+
+        let hash = *blake3::hash(body).as_bytes();
+        if self.pointee_of(plan, key)? != Some(hash) {
+            self.release(plan, key)?;
+
+            let refs = self.refs_of(plan, hash)?;
+            *refs += 1;
+            if *refs == 1 {
+                plan.ops.insert(content_key(&hash), body.to_vec());
+            } else {
+                metrics::counter!(DB_DEDUP_HITS).increment(1);
+            }
+        }
+
+        let mut pointer = Vec::with_capacity(header.len() + POINTER_LEN);
+        pointer.extend_from_slice(header);
+        pointer.extend_from_slice(&POINTER_MAGIC);
+        pointer.extend_from_slice(&hash);
+        plan.ops.insert(key.to_vec(), pointer);
+        plan.pointees.insert(key.to_vec(), Some(hash));
+        Ok(())
+    }
+
+    /// Rewrite `ops` into what we actually store, refcounts included.
+    fn plan<'a, K, V>(
+        &self,
+        ops: impl ExactSizeIterator<Item = &'a BatchOp<K, V>>,
+    ) -> Result<Batch<Vec<u8>, Vec<u8>>, DB::Error>
+    where
+        K: GenericBytes + 'a,
+        V: GenericBytes + 'a,
+    {
+        let mut plan = Plan::new(ops.len());
+        for op in ops {
+            match op {
+                BatchOp::Insert(key, value) if is_reserved_key(key.as_ref()) => {
+                    plan.ops
+                        .insert(key.as_ref().to_vec(), value.as_ref().to_vec())
+                }
+                BatchOp::Insert(key, value) => {
+                    self.plan_insert(&mut plan, key.as_ref(), value.as_ref())?
+                }
+                BatchOp::Delete(key) => {
+                    if !is_reserved_key(key.as_ref()) {
+                        self.release(&mut plan, key.as_ref())?;
+                        plan.pointees.insert(key.as_ref().to_vec(), None);
+                    }
+                    plan.ops.delete(key.as_ref().to_vec());
+                }
+            }
+        }
+
+        // Bodies go once nothing points to them anymore
+        for (hash, refs) in plan.refs {
+            match refs {
+                0 => {
+                    plan.ops.delete(content_key(&hash));
+                    plan.ops.delete(refs_key(&hash));
+                }
+                refs => {
+                    plan.ops
+                        .insert(refs_key(&hash), refs.to_be_bytes().to_vec())
+                }
+            }
+        }
+        Ok(plan.ops)
+    }
+}
+
+impl<DB: GenericDatabase> GenericDatabase for Dedup<DB> {
+    type Error = DB::Error;
+    type Config = (DB::Config, DedupSettings);
+
+    fn open(config: &Self::Config) -> Result<Self, Self::Error> {
+        let (config, settings) = config;
+        DB::open(config).map(|inner| Self::new(inner, settings.clone()))
+    }
+
+    fn read<K: GenericBytes>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        let Some(raw) = self.inner.read(key)? else {
+            return Ok(None);
+        };
+        let Some(hash) = pointee(&raw) else {
+            return Ok(Some(raw));
+        };
+
+        // Put the envelope back in front of the body. The body can only be
+        // missing if its last pointer was deleted since we read this one.
+        let header_len = raw.len() - POINTER_LEN;
+        Ok(self.inner.read(content_key(&hash))?.map(|body| {
+            let mut value = raw;
+            value.truncate(header_len);
+            value.extend_from_slice(&body);
+            value
+        }))
+    }
+
+    fn write<K, V>(&self, key: K, val: V) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        let op = BatchOp::Insert(key, val);
+        let batch = self.plan(std::iter::once(&op))?;
+        self.inner.batch(batch)
+    }
+
+    fn batch<K, V>(&self, batch: Batch<K, V>) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        let batch = self.plan(batch.ops())?;
+        self.inner.batch(batch)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        self.inner.clear()
+    }
+
+    /// Values are returned as stored, pointers and all.
+    fn iter_prefix(&self, prefix: &[u8]) -> KvIter<'_, Self::Error> {
+        self.inner.iter_prefix(prefix)
+    }
+
+    fn maintenance_interval(&self) -> Option<Duration> {
+        self.inner.maintenance_interval()
+    }
+
+    fn maintain(&self) -> Result<(), Self::Error> {
+        self.inner.maintain()
+    }
+
+    fn sample_metrics(&self) {
+        self.inner.sample_metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        envelope::{
+            EntryMeta,
+            Finality,
+        },
+        memory::{
+            MemoryConfig,
+            MemoryDb,
+        },
+        test_utils::key,
+    };
+
+    fn value(block_number: u64, body: &[u8]) -> Vec<u8> {
+        let meta = EntryMeta::now(
+            "eth_getBlockByNumber",
+            block_number,
+            "test",
+            Finality::Unknown,
+        );
+        envelope::encode(&meta, body)
+    }
+
+    fn value_small() -> Vec<u8> {
+        value(0x10, br#"{"result":1}"#)
+    }
+
+    fn dedup() -> Dedup<MemoryDb> {
+        Dedup::new(
+            MemoryDb::new(&MemoryConfig::default()),
+            DedupSettings {
+                enable: true,
+                min_bytes: 16,
+            },
+        )
+    }
+
+    fn bodies(cache: &Dedup<MemoryDb>) -> usize {
+        cache.inner.iter_prefix(CONTENT_PREFIX).count()
+    }
+
+    #[test]
+    fn test_shares_bodies() {
+        let cache = dedup();
+        let body = br#"{"result":{"number":"0x10","hash":"0xabc"}}"#;
+        let stored = value(0x10, body);
+        cache.write(key(1), stored.clone()).unwrap();
+        cache.write(key(2), value(0x11, body)).unwrap();
+
+        assert_eq!(bodies(&cache), 1);
+        assert_eq!(cache.read(key(1)).unwrap().unwrap(), stored);
+        let value = cache.read(key(2)).unwrap().unwrap();
+        let envelope = envelope::decode(&value).unwrap();
+        assert_eq!(envelope.meta.unwrap().block_number, 0x11);
+        assert_eq!(envelope.body, body);
+
+        // Small bodies stay inline
+        let small = value_small();
+        cache.write(key(3), small.clone()).unwrap();
+        assert_eq!(bodies(&cache), 1);
+        assert_eq!(cache.read(key(3)).unwrap().unwrap(), small);
+    }
+
+    #[test]
+    fn test_deletes_with_last_reference() {
+        let cache = dedup();
+        let body = br#"{"result":{"number":"0x10","hash":"0xabc"}}"#;
+        let stored = value(0x10, body);
+        cache.write(key(1), stored.clone()).unwrap();
+        cache.write(key(2), stored.clone()).unwrap();
+
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(1);
+        batch.delete(key(1));
+        cache.batch(batch).unwrap();
+        assert_eq!(cache.read(key(2)).unwrap().unwrap(), stored);
+
+        // Overwriting drops the reference too
+        cache.write(key(2), value_small()).unwrap();
+        assert_eq!(bodies(&cache), 0);
+        assert_eq!(cache.inner.iter_prefix(REFS_PREFIX).count(), 0);
+        assert!(!Dedup::in_use(&cache.inner));
+    }
+
+    #[test]
+    fn test_refcounts_within_a_batch() {
+        let cache = dedup();
+        let body = br#"{"result":{"number":"0x10","hash":"0xabc"}}"#;
+
+        let stored = value(0x10, body);
+
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(4);
+        batch.insert(key(1), stored.clone());
+        batch.insert(key(2), stored.clone());
+        batch.insert(key(1), stored.clone());
+        batch.delete(key(2));
+        cache.batch(batch).unwrap();
+
+        assert!(Dedup::in_use(&cache.inner));
+        assert_eq!(
+            cache
+                .inner
+                .read(refs_key(blake3::hash(body).as_bytes()))
+                .unwrap(),
+            Some(1u64.to_be_bytes().to_vec())
+        );
+        assert_eq!(cache.read(key(1)).unwrap().unwrap(), stored);
+    }
+
+    #[test]
+    fn test_disabled_still_resolves() {
+        let cache = dedup();
+        let body = br#"{"result":{"number":"0x10","hash":"0xabc"}}"#;
+        let stored = value(0x10, body);
+        cache.write(key(1), stored.clone()).unwrap();
+
+        let cache = Dedup::new(cache.inner, DedupSettings::default());
+        assert_eq!(cache.read(key(1)).unwrap().unwrap(), stored);
+
+        cache.write(key(1), stored.clone()).unwrap();
+        assert_eq!(bodies(&cache), 0);
+        assert_eq!(cache.read(key(1)).unwrap().unwrap(), stored);
+    }
+}
@@ -0,0 +1,368 @@
+//! Versioned metadata header stored in front of cached responses.
+//!
+//! ```text
+//! | magic (2) | version (1) | header len (2, BE) | header | response JSON |
+//! ```
+//!
+//! The header length lets us skip headers from newer versions we can't parse and
+//! still serve the response. Values written before we had envelopes are plain JSON,
+//! which can't start with the magic, so they're passed through as-is.
+//!
+//! The v1 header may end with the request the response answers, so hits can be checked
+//! against it. Parsers that predate the field just ignore it.
+
+use crate::database::types::CACHE_COLLISIONS;
+
+use rust_tracing::deps::metrics;
+
+use std::time::{
+    SystemTime,
+    UNIX_EPOCH,
+};
+
+/// Marks a value as enveloped. JSON always starts with `{`, `[`, whitespace or a scalar.
+const ENVELOPE_MAGIC: [u8; 2] = [0xb1, 0x06];
+const ENVELOPE_VERSION: u8 = 1;
+/// Magic, version and header length.
+const PREAMBLE_LEN: usize = 5;
+/// Largest request we can store in a header, leaving room for the other fields.
+pub const MAX_REQUEST_LEN: usize = u16::MAX as usize - 1024;
+
+/// Finality of the block a response belongs to, at the time we cached it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Finality {
+    /// We didn't know the finalized block yet.
+    Unknown,
+    Unfinalized,
+    Finalized,
+}
+
+impl Finality {
+    /// Returns the finality of `block_number` given the latest `finalized` block.
+    pub fn of(block_number: u64, finalized: u64) -> Self {
+        match finalized {
+            0 => Finality::Unknown,
+            finalized if block_number <= finalized => Finality::Finalized,
+            _ => Finality::Unfinalized,
+        }
+    }
+
+    fn to_byte(self) -> u8 {
+        match self {
+            Finality::Unknown => 0,
+            Finality::Unfinalized => 1,
+            Finality::Finalized => 2,
+        }
+    }
+
+    fn from_byte(byte: u8) -> Self {
+        match byte {
+            1 => Finality::Unfinalized,
+            2 => Finality::Finalized,
+            _ => Finality::Unknown,
+        }
+    }
+}
+
+/// Metadata about a cached response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EntryMeta {
+    /// Unix timestamp in ms of when we cached the response.
+    pub inserted_at: u64,
+    /// Method of the request.
+    pub method: String,
+    /// Block the response belongs to.
+    pub block_number: u64,
+    /// Name of the node that answered.
+    pub node: String,
+    pub finality: Finality,
+    /// Canonical request the response answers, if we're verifying hits.
+    pub request: Option<Vec<u8>>,
+}
+
+impl EntryMeta {
+    /// Metadata for a response we're caching right now.
+    pub fn now(method: &str, block_number: u64, node: &str, finality: Finality) -> Self {
+        EntryMeta {
+            inserted_at: unix_millis(),
+            method: method.to_string(),
+            block_number,
+            node: node.to_string(),
+            finality,
+            request: None,
+        }
+    }
+
+    /// Store `request` with the response, so hits can be checked against it.
+    pub fn with_request(mut self, request: &[u8]) -> Self {
+        self.request = Some(request.to_vec());
+        self
+    }
+
+    fn parse(mut header: &[u8]) -> Option<Self> {
+        let inserted_at = u64::from_be_bytes(take(&mut header, 8)?.try_into().ok()?);
+        let block_number = u64::from_be_bytes(take(&mut header, 8)?.try_into().ok()?);
+        let finality = Finality::from_byte(*take(&mut header, 1)?.first()?);
+        let method = take_str(&mut header)?;
+        let node = take_str(&mut header)?;
+        let request = match header.is_empty() {
+            true => None,
+            false => {
+                let len = u32::from_be_bytes(take(&mut header, 4)?.try_into().ok()?);
+                Some(take(&mut header, len as usize)?.to_vec())
+            }
+        };
+
+        Some(EntryMeta {
+            inserted_at,
+            method,
+            block_number,
+            node,
+            finality,
+            request,
+        })
+    }
+}
+
+/// A decoded cache value.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Envelope<'a> {
+    /// `None` for values written before envelopes, or by a newer version.
+    pub meta: Option<EntryMeta>,
+    pub body: &'a [u8],
+}
+
+/// Returns the current unix timestamp in ms.
+pub fn unix_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|since| since.as_millis() as u64)
+        .unwrap_or_default()
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Option<&'a [u8]> {
+    if buf.len() < len {
+        return None;
+    }
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Some(taken)
+}
+
+fn take_str(buf: &mut &[u8]) -> Option<String> {
+    let len = *take(buf, 1)?.first()?;
+    let bytes = take(buf, len as usize)?;
+    Some(String::from_utf8_lossy(bytes).into_owned())
+}
+
+/// Writes `bytes` with a u8 length prefix, truncating them to 255 bytes.
+fn put_str(buf: &mut Vec<u8>, bytes: &[u8]) {
+    let bytes = &bytes[..bytes.len().min(u8::MAX as usize)];
+    buf.push(bytes.len() as u8);
+    buf.extend_from_slice(bytes);
+}
+
+/// Prepends `meta` to the response `body`.
+///
+/// Requests longer than [`MAX_REQUEST_LEN`] are left out.
+pub fn encode(meta: &EntryMeta, body: &[u8]) -> Vec<u8> {
+    let mut header = Vec::with_capacity(19 + meta.method.len() + meta.node.len());
+    header.extend_from_slice(&meta.inserted_at.to_be_bytes());
+    header.extend_from_slice(&meta.block_number.to_be_bytes());
+    header.push(meta.finality.to_byte());
+    put_str(&mut header, meta.method.as_bytes());
+    put_str(&mut header, meta.node.as_bytes());
+    if let Some(request) = meta
+        .request
+        .as_ref()
+        .filter(|request| request.len() <= MAX_REQUEST_LEN)
+    {
+        header.extend_from_slice(&(request.len() as u32).to_be_bytes());
+        header.extend_from_slice(request);
+    }
+
+    let mut buf = Vec::with_capacity(PREAMBLE_LEN + header.len() + body.len());
+    buf.extend_from_slice(&ENVELOPE_MAGIC);
+    buf.push(ENVELOPE_VERSION);
+    buf.extend_from_slice(&(header.len() as u16).to_be_bytes());
+    buf.extend_from_slice(&header);
+    buf.extend_from_slice(body);
+    buf
+}
+
+/// Splits a cache value into its raw envelope, preamble included, and the response.
+///
+/// Values written before envelopes have an empty one. Returns `None` if the value
+/// looks enveloped but is truncated.
+pub fn split(raw: &[u8]) -> Option<(&[u8], &[u8])> {
+    if !raw.starts_with(&ENVELOPE_MAGIC) {
+        return Some((&[], raw));
+    }
+
+    let header_len = u16::from_be_bytes(raw.get(3..PREAMBLE_LEN)?.try_into().ok()?);
+    let len = PREAMBLE_LEN + header_len as usize;
+    (raw.len() >= len).then(|| raw.split_at(len))
+}
+
+/// Splits a cache value into its metadata and response.
+///
+/// Returns `None` if the value looks enveloped but is truncated.
+pub fn decode(raw: &[u8]) -> Option<Envelope<'_>> {
+    if !raw.starts_with(&ENVELOPE_MAGIC) {
+        return Some(Envelope {
+            meta: None,
+            body: raw,
+        });
+    }
+
+    let mut rest = &raw[ENVELOPE_MAGIC.len()..];
+    let version = *take(&mut rest, 1)?.first()?;
+    let header_len = u16::from_be_bytes(take(&mut rest, 2)?.try_into().ok()?);
+    let header = take(&mut rest, header_len as usize)?;
+
+    let meta = match version {
+        ENVELOPE_VERSION => Some(EntryMeta::parse(header)?),
+        _ => None,
+    };
+
+    Some(Envelope { meta, body: rest })
+}
+
+/// Strips the envelope from a value read from the DB, leaving just the response.
+///
+/// Corrupt values are treated as a cache miss.
+pub fn strip(raw: Vec<u8>) -> Option<Vec<u8>> {
+    if !raw.starts_with(&ENVELOPE_MAGIC) {
+        return Some(raw);
+    }
+
+    match decode(&raw) {
+        Some(envelope) => Some(envelope.body.to_vec()),
+        None => {
+            tracing::warn!("Corrupt cache entry, ignoring it");
+            None
+        }
+    }
+}
+
+/// Like [`strip`], but only if the value was cached for exactly `request`.
+///
+/// A different request means two requests hashed to the same key, which is counted.
+/// Values we can't check because they don't have a request are a miss too.
+pub fn strip_matching(raw: Vec<u8>, request: &[u8]) -> Option<Vec<u8>> {
+    let Some(envelope) = decode(&raw) else {
+        tracing::warn!("Corrupt cache entry, ignoring it");
+        return None;
+    };
+
+    match envelope.meta.and_then(|meta| meta.request) {
+        Some(stored) if stored == request => Some(envelope.body.to_vec()),
+        Some(_) => {
+            metrics::counter!(CACHE_COLLISIONS).increment(1);
+            tracing::warn!("Cache key collision, treating it as a miss");
+            None
+        }
+        None => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> EntryMeta {
+        EntryMeta {
+            inserted_at: 1_700_000_000_000,
+            method: "eth_getBlockByNumber".to_string(),
+            block_number: 0x10,
+            node: "https://eth.merkle.io/".to_string(),
+            finality: Finality::Finalized,
+            request: None,
+        }
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        let body = br#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#;
+        let raw = encode(&meta(), body);
+
+        let envelope = decode(&raw).unwrap();
+        assert_eq!(envelope.meta, Some(meta()));
+        assert_eq!(envelope.body, body);
+        assert_eq!(strip(raw).unwrap(), body);
+    }
+
+    #[test]
+    fn test_request_roundtrip() {
+        let body = br#"{"result":"0x1"}"#;
+        let request = br#"{"method":"eth_blockNumber","params":[]}"#;
+        let raw = encode(&meta().with_request(request), body);
+
+        let envelope = decode(&raw).unwrap();
+        assert_eq!(
+            envelope.meta.unwrap().request.as_deref(),
+            Some(&request[..])
+        );
+        assert_eq!(envelope.body, body);
+
+        assert_eq!(strip_matching(raw.clone(), request).unwrap(), body);
+        assert!(strip_matching(raw.clone(), br#"{"method":"eth_chainId"}"#).is_none());
+        assert_eq!(strip(raw).unwrap(), body);
+
+        // Nothing to check against, so it can't be served
+        assert!(strip_matching(encode(&meta(), body), request).is_none());
+        assert!(strip_matching(body.to_vec(), request).is_none());
+
+        // Too long to store, so it's left out
+        let long = vec![b'a'; MAX_REQUEST_LEN + 1];
+        let raw = encode(&meta().with_request(&long), body);
+        assert!(decode(&raw).unwrap().meta.unwrap().request.is_none());
+    }
+
+    #[test]
+    fn test_legacy_values_pass_through() {
+        let body = br#"{"id":null,"jsonrpc":"2.0","result":"0x1"}"#;
+
+        let envelope = decode(body).unwrap();
+        assert!(envelope.meta.is_none());
+        assert_eq!(envelope.body, body);
+        assert_eq!(strip(body.to_vec()).unwrap(), body);
+    }
+
+    #[test]
+    fn test_newer_version_keeps_body() {
+        let body = br#"{"result":"0x1"}"#;
+        let mut raw = encode(&meta(), body);
+        raw[2] = ENVELOPE_VERSION + 1;
+
+        let envelope = decode(&raw).unwrap();
+        assert!(envelope.meta.is_none());
+        assert_eq!(envelope.body, body);
+    }
+
+    #[test]
+    fn test_split() {
+        let body = br#"{"result":"0x1"}"#;
+        let raw = encode(&meta(), body);
+
+        let (header, rest) = split(&raw).unwrap();
+        assert_eq!(rest, body);
+        assert_eq!(decode(header).unwrap().meta, Some(meta()));
+        assert_eq!(split(body).unwrap(), (&[][..], &body[..]));
+        assert!(split(&raw[..PREAMBLE_LEN + 4]).is_none());
+    }
+
+    #[test]
+    fn test_truncated_is_corrupt() {
+        let raw = encode(&meta(), b"{}");
+        assert!(decode(&raw[..PREAMBLE_LEN + 4]).is_none());
+        assert!(strip(raw[..PREAMBLE_LEN + 4].to_vec()).is_none());
+    }
+
+    #[test]
+    fn test_finality() {
+        assert_eq!(Finality::of(10, 0), Finality::Unknown);
+        assert_eq!(Finality::of(10, 10), Finality::Finalized);
+        assert_eq!(Finality::of(11, 10), Finality::Unfinalized);
+    }
+}
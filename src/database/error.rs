@@ -1 +1,50 @@
+use std::io;
+
 pub type DbError = Box<dyn std::error::Error>;
+
+/// Errors from exporting or importing cache snapshots.
+#[derive(Debug, thiserror::Error)]
+pub enum SnapshotError {
+    #[error(transparent)]
+    Io(#[from] io::Error),
+
+    #[error("not a blutgang cache snapshot")]
+    BadMagic,
+
+    #[error("unsupported snapshot version {0}")]
+    UnsupportedVersion(u8),
+
+    #[error("snapshot keys are hashed with {snapshot}, but this build uses {ours}")]
+    HashMismatch {
+        snapshot: String,
+        ours: &'static str,
+    },
+
+    #[error("snapshot is for chain ID {snapshot}, but the cache is for chain ID {ours}")]
+    ChainMismatch { snapshot: u64, ours: u64 },
+
+    #[error("snapshot is corrupt, checksum mismatch in block {0}")]
+    Corrupt(u64),
+
+    #[error("cache entry is too large to snapshot")]
+    TooLarge,
+
+    #[error("database error: {0}")]
+    Db(String),
+}
+
+/// Errors from migrating the cache between DB backends.
+#[derive(Debug, thiserror::Error)]
+pub enum MigrateError {
+    #[error("failed to read from the source db: {0}")]
+    Source(String),
+
+    #[error("failed to write to the destination db: {0}")]
+    Destination(String),
+
+    #[error("the destination db isn't empty, refusing to migrate into it")]
+    DestinationNotEmpty,
+
+    #[error("the destination db doesn't match the source after copying ({reason})")]
+    Mismatch { reason: String },
+}
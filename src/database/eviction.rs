@@ -0,0 +1,733 @@
+//! Keeps the cache DB within a size budget.
+//!
+//! [`Evicting`] wraps any [`GenericDatabase`] and keeps track of when every cache entry
+//! was last written (or read, for [`EvictionPolicy::Lru`]) and how big it is, in
+//! reserved keys next to the entries themselves:
+//!
+//! ```text
+//! | ORDER_PREFIX | touched (8) | seq (8) | key | -> ()
+//! | META_PREFIX  | key |                       -> | touched (8) | seq (8) | size (8) |
+//! | TOTALS_KEY |                               -> | entries (8) | bytes (8) | next seq (8) |
+//! ```
+//!
+//! Order keys sort from least to most recently used, so the DB task periodically calls
+//! [`GenericDatabase::maintain`], which walks them from the start, deleting entries that
+//! outlived their TTL and then the least recently used ones until we're back under budget.
+//! Nothing is kept in memory besides the totals.
+
+use crate::{
+    config::cache_setup::is_reserved_key,
+    database::{
+        envelope::{
+            self,
+            unix_millis,
+        },
+        types::{
+            Batch,
+            BatchOp,
+            GenericBytes,
+            GenericDatabase,
+            KvIter,
+            DB_EVICTIONS,
+            DB_TRACKED_ENTRIES,
+            DB_TRACKED_SIZE_MB,
+        },
+    },
+};
+
+use std::{
+    collections::BTreeMap,
+    sync::{
+        Mutex,
+        MutexGuard,
+    },
+    time::Duration,
+};
+
+use rust_tracing::deps::metrics;
+
+/// Reserved key prefix for everything [`Evicting`] keeps about cache entries.
+pub const EVICTION_PREFIX: &[u8; 15] = b"blutgang_evict_";
+/// Followed by when an entry was last used, a sequence number and its key.
+const ORDER_PREFIX: &[u8; 16] = b"blutgang_evict_o";
+/// Followed by an entry's key.
+const META_PREFIX: &[u8; 16] = b"blutgang_evict_m";
+const TOTALS_KEY: &[u8; 16] = b"blutgang_evict_t";
+
+/// Max amount of entries we delete in a single maintenance round.
+///
+/// Lowering the budget on a big DB can leave millions of entries to delete,
+/// and the DB task can't serve requests while we're at it.
+const MAX_EVICTIONS_PER_ROUND: usize = 10_000;
+
+/// What counts as "using" a cache entry.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Reads and writes both refresh an entry.
+    #[default]
+    Lru,
+    /// Only writes refresh an entry, so the TTL counts from when it was cached.
+    Ttl,
+}
+
+/// Limits enforced by [`Evicting`]. Unset limits aren't enforced.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvictionSettings {
+    pub policy: EvictionPolicy,
+    /// Max size of keys and values we keep, in bytes.
+    pub max_bytes: Option<u64>,
+    /// Max amount of entries we keep.
+    pub max_entries: Option<u64>,
+    /// How long an entry can go without being used before we drop it.
+    pub ttl: Option<Duration>,
+    /// How often we check the limits.
+    pub interval: Duration,
+}
+
+impl Default for EvictionSettings {
+    fn default() -> Self {
+        Self {
+            policy: EvictionPolicy::default(),
+            max_bytes: None,
+            max_entries: None,
+            ttl: None,
+            interval: Duration::from_secs(10),
+        }
+    }
+}
+
+/// What we know about a tracked entry.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Meta {
+    /// Unix timestamp in ms.
+    touched: u64,
+    /// Orders entries used in the same ms.
+    seq: u64,
+    size: u64,
+}
+
+impl Meta {
+    fn encode(&self) -> Vec<u8> {
+        [self.touched, self.seq, self.size]
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect()
+    }
+
+    fn decode(raw: &[u8]) -> Option<Self> {
+        let [touched, seq, size] = decode_u64s(raw)?;
+        Some(Self { touched, seq, size })
+    }
+}
+
+/// Sums over every tracked entry.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Totals {
+    entries: u64,
+    bytes: u64,
+    next_seq: u64,
+}
+
+impl Totals {
+    fn encode(&self) -> Vec<u8> {
+        [self.entries, self.bytes, self.next_seq]
+            .iter()
+            .flat_map(|field| field.to_be_bytes())
+            .collect()
+    }
+
+    fn decode(raw: &[u8]) -> Option<Self> {
+        let [entries, bytes, next_seq] = decode_u64s(raw)?;
+        Some(Self {
+            entries,
+            bytes,
+            next_seq,
+        })
+    }
+
+    /// Start tracking an entry, used as of `now`.
+    fn add(&mut self, size: u64, now: u64) -> Meta {
+        let meta = Meta {
+            touched: now,
+            seq: self.next_seq,
+            size,
+        };
+        self.next_seq += 1;
+        self.entries += 1;
+        self.bytes += size;
+        meta
+    }
+
+    fn remove(&mut self, meta: &Meta) {
+        self.entries = self.entries.saturating_sub(1);
+        self.bytes = self.bytes.saturating_sub(meta.size);
+    }
+}
+
+fn decode_u64s(raw: &[u8]) -> Option<[u64; 3]> {
+    if raw.len() != 24 {
+        return None;
+    }
+    let field = |i: usize| u64::from_be_bytes(raw[i * 8..(i + 1) * 8].try_into().unwrap());
+    Some([field(0), field(1), field(2)])
+}
+
+fn meta_key(key: &[u8]) -> Vec<u8> {
+    [META_PREFIX.as_slice(), key].concat()
+}
+
+fn order_key(meta: &Meta, key: &[u8]) -> Vec<u8> {
+    [
+        ORDER_PREFIX.as_slice(),
+        &meta.touched.to_be_bytes(),
+        &meta.seq.to_be_bytes(),
+        key,
+    ]
+    .concat()
+}
+
+/// Makes the next [`Evicting`] opened on `cache` count its entries again.
+///
+/// For when entries were written behind its back, like by a snapshot import.
+pub fn forget_totals<DB: GenericDatabase>(cache: &DB) -> Result<(), DB::Error> {
+    let mut batch = Batch::<&[u8], &[u8]>::with_capacity(1);
+    batch.delete(TOTALS_KEY.as_slice());
+    cache.batch(batch)
+}
+
+/// A [`GenericDatabase`] that evicts entries according to [`EvictionSettings`].
+///
+/// Reserved keys, like the ones written by `setup_data` or the persisted head cache,
+/// are never tracked or evicted.
+pub struct Evicting<DB: GenericDatabase> {
+    inner: DB,
+    settings: EvictionSettings,
+    /// Also held while reading and updating the metadata of an entry, so writes
+    /// and reads touching the same entry can't interleave.
+    totals: Mutex<Totals>,
+}
+
+impl<DB: GenericDatabase> Evicting<DB> {
+    /// Wrap `inner`, using the metadata we left in it last time.
+    ///
+    /// The first time around, or after a snapshot import, we go through every entry
+    /// once to track the ones we don't know about. They're aged by the insert timestamp
+    /// stored in their envelope. We don't know when entries from before envelopes were
+    /// last used, so they start out as if they were used just now.
+    pub fn new(inner: DB, settings: EvictionSettings) -> Self {
+        let stored = match inner.read(TOTALS_KEY.as_slice()) {
+            Ok(stored) => stored.as_deref().and_then(Totals::decode),
+            Err(err) => {
+                tracing::error!(?err, "Failed to read cache eviction totals");
+                None
+            }
+        };
+
+        let evicting = Self {
+            inner,
+            settings,
+            totals: Mutex::new(stored.unwrap_or_default()),
+        };
+
+        if stored.is_none() {
+            if let Err(err) = evicting.recount() {
+                tracing::error!(?err, "Failed to index cache entries for eviction");
+            }
+        }
+
+        let totals = *evicting.totals();
+        tracing::info!(
+            entries = totals.entries,
+            bytes = totals.bytes,
+            settings = ?evicting.settings,
+            "Cache eviction enabled"
+        );
+        evicting.update_gauges(&totals);
+        evicting
+    }
+
+    fn totals(&self) -> MutexGuard<'_, Totals> {
+        self.totals.lock().unwrap_or_else(|e| {
+            // Handle the case where the Mutex is poisoned
+            e.into_inner()
+        })
+    }
+
+    fn update_gauges(&self, totals: &Totals) {
+        metrics::gauge!(DB_TRACKED_ENTRIES).set(totals.entries as f64);
+        metrics::gauge!(DB_TRACKED_SIZE_MB).set((totals.bytes / (1024 * 1024)) as f64);
+    }
+
+    fn meta(&self, key: &[u8]) -> Result<Option<Meta>, DB::Error> {
+        Ok(self
+            .inner
+            .read(meta_key(key))?
+            .as_deref()
+            .and_then(Meta::decode))
+    }
+
+    /// Track every entry in the DB, without holding more than a batch in memory.
+    fn recount(&self) -> Result<(), DB::Error> {
+        let now = unix_millis();
+        let mut totals = self.totals();
+        *totals = Totals::default();
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(0);
+        let mut pending = 0;
+
+        for item in self.inner.iter_prefix(&[]) {
+            let (key, value) = item?;
+            if is_reserved_key(&key) {
+                continue;
+            }
+
+            match self.meta(&key)? {
+                Some(meta) => {
+                    totals.entries += 1;
+                    totals.bytes += meta.size;
+                    totals.next_seq = totals.next_seq.max(meta.seq + 1);
+                }
+                None => {
+                    let touched = envelope::decode(&value)
+                        .and_then(|envelope| envelope.meta)
+                        .map_or(now, |meta| meta.inserted_at.min(now));
+                    let meta = totals.add((key.len() + value.len()) as u64, touched);
+                    batch.insert(order_key(&meta, &key), Vec::new());
+                    batch.insert(meta_key(&key), meta.encode());
+                    pending += 1;
+                }
+            }
+
+            if pending == MAX_EVICTIONS_PER_ROUND {
+                self.inner
+                    .batch(std::mem::replace(&mut batch, Batch::with_capacity(0)))?;
+                pending = 0;
+            }
+        }
+
+        batch.insert(TOTALS_KEY.to_vec(), totals.encode());
+        self.inner.batch(batch)
+    }
+
+    /// Add to `batch` whatever it takes to track `key` with `size`, or stop tracking
+    /// it if that's `None`.
+    fn retrack(
+        &self,
+        totals: &mut Totals,
+        batch: &mut Batch<Vec<u8>, Vec<u8>>,
+        key: &[u8],
+        size: Option<u64>,
+        now: u64,
+    ) -> Result<(), DB::Error> {
+        if let Some(old) = self.meta(key)? {
+            batch.delete(order_key(&old, key));
+            totals.remove(&old);
+        }
+
+        match size {
+            Some(size) => {
+                let meta = totals.add(size, now);
+                batch.insert(order_key(&meta, key), Vec::new());
+                batch.insert(meta_key(key), meta.encode());
+            }
+            None => batch.delete(meta_key(key)),
+        }
+        Ok(())
+    }
+
+    /// Mark `key` as used, if we're tracking it.
+    ///
+    /// Entries are only looked at every `interval`, so there's no point in moving
+    /// them more often than that.
+    fn touch(&self, key: &[u8], now: u64) -> Result<(), DB::Error> {
+        let mut totals = self.totals();
+        let Some(old) = self.meta(key)? else {
+            return Ok(());
+        };
+        if now.saturating_sub(old.touched) < self.settings.interval.as_millis() as u64 {
+            return Ok(());
+        }
+
+        let mut updated = *totals;
+        let mut batch = Batch::with_capacity(5);
+        self.retrack(&mut updated, &mut batch, key, Some(old.size), now)?;
+        batch.insert(TOTALS_KEY.to_vec(), updated.encode());
+        self.inner.batch(batch)?;
+        *totals = updated;
+        Ok(())
+    }
+
+    /// Delete everything that's expired or over budget as of `now`.
+    fn evict(&self, now: u64) -> Result<(), DB::Error> {
+        let mut totals = self.totals();
+        let mut updated = *totals;
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(0);
+        let (mut expired, mut oversize) = (0, 0);
+
+        for item in self.inner.iter_prefix(ORDER_PREFIX) {
+            if expired + oversize >= MAX_EVICTIONS_PER_ROUND {
+                break;
+            }
+
+            let (order, _) = item?;
+            let Some(key) = order.get(ORDER_PREFIX.len() + 16..) else {
+                batch.delete(order);
+                continue;
+            };
+            // Left behind by something that wrote around us, it's not tracked anymore
+            let Some(meta) = self.meta(key)?.filter(|meta| order_key(meta, key) == order) else {
+                batch.delete(order);
+                continue;
+            };
+
+            let is_expired = self
+                .settings
+                .ttl
+                .is_some_and(|ttl| now.saturating_sub(meta.touched) >= ttl.as_millis() as u64);
+            let is_oversize = self
+                .settings
+                .max_entries
+                .is_some_and(|max| updated.entries > max)
+                || self
+                    .settings
+                    .max_bytes
+                    .is_some_and(|max| updated.bytes > max);
+
+            if is_expired {
+                expired += 1;
+            } else if is_oversize {
+                oversize += 1;
+            } else {
+                // Everything after this was used more recently
+                break;
+            }
+
+            batch.delete(key.to_vec());
+            batch.delete(meta_key(key));
+            batch.delete(order);
+            updated.remove(&meta);
+        }
+
+        if batch.ops().len() == 0 {
+            return Ok(());
+        }
+        batch.insert(TOTALS_KEY.to_vec(), updated.encode());
+        self.inner.batch(batch)?;
+        *totals = updated;
+        self.update_gauges(&totals);
+
+        metrics::counter!(DB_EVICTIONS, "reason" => "ttl").increment(expired as u64);
+        metrics::counter!(DB_EVICTIONS, "reason" => "size").increment(oversize as u64);
+        tracing::debug!(expired, oversize, "Evicted cache entries");
+
+        Ok(())
+    }
+}
+
+impl<DB: GenericDatabase> GenericDatabase for Evicting<DB> {
+    type Error = DB::Error;
+    type Config = (DB::Config, EvictionSettings);
+
+    fn open(config: &Self::Config) -> Result<Self, Self::Error> {
+        let (config, settings) = config;
+        DB::open(config).map(|inner| Self::new(inner, settings.clone()))
+    }
+
+    fn read<K: GenericBytes>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        let value = self.inner.read(key.clone())?;
+        if value.is_some()
+            && self.settings.policy == EvictionPolicy::Lru
+            && !is_reserved_key(key.as_ref())
+        {
+            // The read itself went fine, so this shouldn't fail it
+            if let Err(err) = self.touch(key.as_ref(), unix_millis()) {
+                tracing::warn!(?err, "Failed to mark a cache entry as used");
+            }
+        }
+        Ok(value)
+    }
+
+    fn write<K, V>(&self, key: K, val: V) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        if is_reserved_key(key.as_ref()) {
+            return self.inner.write(key, val);
+        }
+
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(1);
+        batch.insert(key.as_ref().to_vec(), val.as_ref().to_vec());
+        self.batch(batch)
+    }
+
+    fn batch<K, V>(&self, batch: Batch<K, V>) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        // Only the last op on a key counts
+        let tracked = batch
+            .ops()
+            .map(|op| {
+                match op {
+                    BatchOp::Insert(key, value) => {
+                        (
+                            key.as_ref(),
+                            Some((key.as_ref().len() + value.as_ref().len()) as u64),
+                        )
+                    }
+                    BatchOp::Delete(key) => (key.as_ref(), None),
+                }
+            })
+            .filter(|(key, _)| !is_reserved_key(key))
+            .collect::<BTreeMap<_, _>>();
+
+        // Written along with the metadata, so they can't get out of sync
+        let mut combined = Batch::<Vec<u8>, Vec<u8>>::with_capacity(batch.ops().len() * 3 + 1);
+        for op in batch.ops() {
+            match op {
+                BatchOp::Insert(key, value) => {
+                    combined.insert(key.as_ref().to_vec(), value.as_ref().to_vec())
+                }
+                BatchOp::Delete(key) => combined.delete(key.as_ref().to_vec()),
+            }
+        }
+        if tracked.is_empty() {
+            return self.inner.batch(combined);
+        }
+
+        let now = unix_millis();
+        let mut totals = self.totals();
+        let mut updated = *totals;
+        for (key, size) in tracked {
+            self.retrack(&mut updated, &mut combined, key, size, now)?;
+        }
+        combined.insert(TOTALS_KEY.to_vec(), updated.encode());
+
+        self.inner.batch(combined)?;
+        *totals = updated;
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.inner.flush()
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        let mut totals = self.totals();
+        self.inner.clear()?;
+
+        *totals = Totals::default();
+        self.inner.write(TOTALS_KEY.as_slice(), totals.encode())?;
+        self.update_gauges(&totals);
+        Ok(())
+    }
+
+    fn iter_prefix(&self, prefix: &[u8]) -> KvIter<'_, Self::Error> {
+        self.inner.iter_prefix(prefix)
+    }
+
+    fn maintenance_interval(&self) -> Option<Duration> {
+        Some(self.settings.interval)
+    }
+
+    fn maintain(&self) -> Result<(), Self::Error> {
+        self.evict(unix_millis())
+    }
+
+    fn sample_metrics(&self) {
+        self.inner.sample_metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::cache_setup::{
+        setup_data,
+        stored_chain_id,
+    };
+    use crate::database::test_utils::{
+        db,
+        key,
+    };
+    use crate::health::head_cache::head_cache_key;
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        let cache = Evicting::new(
+            db(),
+            EvictionSettings {
+                max_entries: Some(2),
+                // Reads move entries right away
+                interval: Duration::ZERO,
+                ..Default::default()
+            },
+        );
+
+        cache.write(key(1), b"one".to_vec()).unwrap();
+        cache.write(key(2), b"two".to_vec()).unwrap();
+        cache.write(key(3), b"three".to_vec()).unwrap();
+
+        // Reading 1 makes 2 the least recently used
+        assert!(cache.read(key(1)).unwrap().is_some());
+        cache.evict(unix_millis()).unwrap();
+
+        assert!(cache.read(key(1)).unwrap().is_some());
+        assert!(cache.read(key(2)).unwrap().is_none());
+        assert!(cache.read(key(3)).unwrap().is_some());
+        assert_eq!(cache.totals().entries, 2);
+    }
+
+    #[test]
+    fn test_evicts_over_byte_budget() {
+        let cache = Evicting::new(
+            db(),
+            EvictionSettings {
+                max_bytes: Some(100),
+                ..Default::default()
+            },
+        );
+
+        for i in 1..=3 {
+            cache.write(key(i), vec![0; 18]).unwrap();
+        }
+        assert_eq!(cache.totals().bytes, 150);
+
+        cache.evict(unix_millis()).unwrap();
+        assert!(cache.read(key(1)).unwrap().is_none());
+        assert!(cache.read(key(2)).unwrap().is_some());
+        assert_eq!(cache.totals().bytes, 100);
+    }
+
+    #[test]
+    fn test_ttl_policy_ignores_reads() {
+        let ttl = Duration::from_secs(60);
+        let cache = Evicting::new(
+            db(),
+            EvictionSettings {
+                policy: EvictionPolicy::Ttl,
+                ttl: Some(ttl),
+                ..Default::default()
+            },
+        );
+
+        cache.write(key(1), b"one".to_vec()).unwrap();
+        cache.evict(unix_millis()).unwrap();
+        assert!(cache.read(key(1)).unwrap().is_some());
+
+        cache.evict(unix_millis() + ttl.as_millis() as u64).unwrap();
+        assert!(cache.read(key(1)).unwrap().is_none());
+    }
+
+    #[test]
+    fn test_reserved_keys_are_kept() {
+        let cache = Evicting::new(
+            db(),
+            EvictionSettings {
+                max_entries: Some(0),
+                ..Default::default()
+            },
+        );
+
+        setup_data(&cache, false, Some(1));
+        cache
+            .write(head_cache_key(10, &[1; 32]).to_vec(), vec![0])
+            .unwrap();
+        cache.write(key(1), b"one".to_vec()).unwrap();
+        cache.evict(unix_millis()).unwrap();
+
+        assert!(cache.read(key(1)).unwrap().is_none());
+        assert!(cache
+            .read(head_cache_key(10, &[1; 32]).to_vec())
+            .unwrap()
+            .is_some());
+        let hash_marker = if cfg!(feature = "xxhash") {
+            b"xxhash".to_vec()
+        } else {
+            b"blake3".to_vec()
+        };
+        assert!(cache.read(hash_marker).unwrap().is_some());
+        assert_eq!(stored_chain_id(&cache), Some(1));
+        assert_eq!(cache.totals().entries, 0);
+    }
+
+    #[test]
+    fn test_ages_existing_entries_from_envelope() {
+        use crate::database::envelope::{
+            EntryMeta,
+            Finality,
+        };
+
+        let ttl = Duration::from_secs(60);
+        let inner = db();
+        let mut meta = EntryMeta::now("eth_getBalance", 1, "node", Finality::Finalized);
+        meta.inserted_at -= 2 * ttl.as_millis() as u64;
+        inner.write(key(1), envelope::encode(&meta, b"{}")).unwrap();
+        inner.write(key(2), b"{}".to_vec()).unwrap();
+
+        let cache = Evicting::new(
+            inner,
+            EvictionSettings {
+                policy: EvictionPolicy::Ttl,
+                ttl: Some(ttl),
+                ..Default::default()
+            },
+        );
+        cache.evict(unix_millis()).unwrap();
+
+        assert!(cache.read(key(1)).unwrap().is_none());
+        assert!(cache.read(key(2)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_indexes_existing_entries() {
+        let inner = db();
+        inner.write(key(1), b"one".to_vec()).unwrap();
+        inner.write(key(2), b"two".to_vec()).unwrap();
+        inner.write(b"blake3".to_vec(), b"true".to_vec()).unwrap();
+
+        let cache = Evicting::new(
+            inner,
+            EvictionSettings {
+                max_entries: Some(1),
+                ..Default::default()
+            },
+        );
+        assert_eq!(cache.totals().entries, 2);
+
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(1);
+        batch.delete(key(2));
+        cache.batch(batch).unwrap();
+        assert_eq!(cache.totals().entries, 1);
+
+        cache.evict(unix_millis()).unwrap();
+        assert!(cache.read(key(1)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_reopening_keeps_metadata() {
+        let settings = EvictionSettings {
+            max_entries: Some(1),
+            ..Default::default()
+        };
+        let cache = Evicting::new(db(), settings.clone());
+        cache.write(key(1), b"one".to_vec()).unwrap();
+        cache.write(key(2), b"two".to_vec()).unwrap();
+
+        // Written behind its back, so it's not tracked until the next recount
+        cache.inner.write(key(3), b"three".to_vec()).unwrap();
+
+        let cache = Evicting::new(cache.inner, settings.clone());
+        assert_eq!(cache.totals().entries, 2);
+        cache.evict(unix_millis()).unwrap();
+        assert!(cache.read(key(1)).unwrap().is_none());
+        assert!(cache.read(key(2)).unwrap().is_some());
+        assert!(cache.read(key(3)).unwrap().is_some());
+
+        forget_totals(&cache.inner).unwrap();
+        let cache = Evicting::new(cache.inner, settings);
+        assert_eq!(cache.totals().entries, 2);
+        assert_eq!(cache.totals().bytes, 32 + 3 + 32 + 5);
+    }
+}
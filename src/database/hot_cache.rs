@@ -0,0 +1,304 @@
+//! In-process LRU of hot cache entries, checked before we ever send a `DbRequest`.
+//!
+//! The DB is the second tier. Responses are stored without their metadata envelope,
+//! exactly as the DB task would hand them back, along with the request they answer
+//! when we're verifying hits.
+
+use std::{
+    collections::{
+        BTreeMap,
+        HashMap,
+    },
+    sync::{
+        atomic::{
+            AtomicU64,
+            Ordering,
+        },
+        Arc,
+        Mutex,
+    },
+};
+
+use crate::database::types::CACHE_COLLISIONS;
+
+use rust_tracing::deps::metrics;
+
+const HOT_CACHE_HITS: &str = "hot_cache_hits";
+const HOT_CACHE_MISSES: &str = "hot_cache_misses";
+
+/// Keys are hashes, so folding their bytes spreads them evenly over the shards.
+const SHARDS: usize = 16;
+
+#[derive(Debug)]
+struct Slot {
+    seq: u64,
+    value: Arc<Vec<u8>>,
+    request: Option<Arc<[u8]>>,
+}
+
+impl Slot {
+    fn size(&self) -> usize {
+        self.value.len() + self.request.as_ref().map_or(0, |request| request.len())
+    }
+}
+
+type Hit = (Arc<Vec<u8>>, Option<Arc<[u8]>>);
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: HashMap<Vec<u8>, Slot>,
+    order: BTreeMap<u64, Vec<u8>>,
+    next_seq: u64,
+    bytes: usize,
+}
+
+impl Shard {
+    fn get(&mut self, key: &[u8]) -> Option<Hit> {
+        let seq = self.next_seq;
+        let slot = self.entries.get_mut(key)?;
+
+        let key = self.order.remove(&slot.seq)?;
+        self.order.insert(seq, key);
+        slot.seq = seq;
+        self.next_seq += 1;
+
+        Some((slot.value.clone(), slot.request.clone()))
+    }
+
+    fn insert(&mut self, key: &[u8], value: Vec<u8>, request: Option<&[u8]>, capacity: usize) {
+        let slot = Slot {
+            seq: self.next_seq,
+            value: Arc::new(value),
+            request: request.map(Arc::from),
+        };
+        let size = key.len() + slot.size();
+        self.remove(key);
+        if size > capacity {
+            return;
+        }
+
+        self.next_seq += 1;
+        self.bytes += size;
+        self.order.insert(slot.seq, key.to_vec());
+        self.entries.insert(key.to_vec(), slot);
+
+        while self.bytes > capacity {
+            let Some((_, oldest)) = self.order.pop_first() else {
+                break;
+            };
+            if let Some(slot) = self.entries.remove(&oldest) {
+                self.bytes -= oldest.len() + slot.size();
+            }
+        }
+    }
+
+    fn remove(&mut self, key: &[u8]) {
+        if let Some(slot) = self.entries.remove(key) {
+            self.order.remove(&slot.seq);
+            self.bytes -= key.len() + slot.size();
+        }
+    }
+}
+
+/// Bounded LRU of responses, split into shards so requests don't all fight over one lock.
+#[derive(Debug)]
+pub struct HotCache {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    /// Bumped by [`HotCache::invalidate`], so responses read from the DB before that
+    /// can't be promoted after it.
+    generation: AtomicU64,
+}
+
+impl HotCache {
+    /// A hot cache holding up to `capacity_bytes` of keys and responses. 0 disables it.
+    pub fn new(capacity_bytes: usize) -> Self {
+        Self {
+            shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+            shard_capacity: capacity_bytes / SHARDS,
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn shard(&self, key: &[u8]) -> std::sync::MutexGuard<'_, Shard> {
+        // Cache keys start with a fixed version and algorithm tag, so use all of it
+        let index = key.iter().fold(0, |acc, byte| acc ^ byte) as usize % SHARDS;
+        self.shards[index].lock().unwrap_or_else(|e| {
+            // Handle the case where the Mutex is poisoned
+            e.into_inner()
+        })
+    }
+
+    fn lookup(&self, key: &[u8], request: Option<&[u8]>) -> Option<Vec<u8>> {
+        if self.shard_capacity == 0 {
+            return None;
+        }
+
+        // Clone the response outside of the lock
+        let hit = self.shard(key).get(key).and_then(|(value, stored)| {
+            let Some(request) = request else {
+                return Some(value);
+            };
+            match stored {
+                Some(stored) if *stored == *request => Some(value),
+                Some(_) => {
+                    metrics::counter!(CACHE_COLLISIONS).increment(1);
+                    tracing::warn!("Cache key collision, treating it as a miss");
+                    None
+                }
+                None => None,
+            }
+        });
+
+        match hit {
+            Some(value) => {
+                metrics::counter!(HOT_CACHE_HITS).increment(1);
+                Some(value.as_ref().clone())
+            }
+            None => {
+                metrics::counter!(HOT_CACHE_MISSES).increment(1);
+                None
+            }
+        }
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.lookup(key, None)
+    }
+
+    /// Like [`HotCache::get`], but only hit if `key` was inserted for exactly `request`.
+    pub fn get_matching(&self, key: &[u8], request: &[u8]) -> Option<Vec<u8>> {
+        self.lookup(key, Some(request))
+    }
+
+    pub fn insert(&self, key: &[u8], value: Vec<u8>) {
+        if self.shard_capacity != 0 {
+            self.shard(key)
+                .insert(key, value, None, self.shard_capacity);
+        }
+    }
+
+    /// Insert a response along with the `request` it answers, for [`HotCache::get_matching`].
+    pub fn insert_with_request(&self, key: &[u8], request: &[u8], value: Vec<u8>) {
+        if self.shard_capacity != 0 {
+            self.shard(key)
+                .insert(key, value, Some(request), self.shard_capacity);
+        }
+    }
+
+    pub fn remove(&self, key: &[u8]) {
+        if self.shard_capacity != 0 {
+            self.shard(key).remove(key);
+        }
+    }
+
+    /// Take before reading something from the DB that you want to [`HotCache::promote`].
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::SeqCst)
+    }
+
+    /// Insert a response read from the DB, unless something was invalidated since
+    /// `generation`. It may have been read before the invalidation reached the DB.
+    pub fn promote(&self, key: &[u8], request: Option<&[u8]>, value: Vec<u8>, generation: u64) {
+        if self.shard_capacity == 0 {
+            return;
+        }
+
+        // Checked under the shard lock, so `invalidate` either sees this insert or we see it
+        let mut shard = self.shard(key);
+        if self.generation() == generation {
+            shard.insert(key, value, request, self.shard_capacity);
+        }
+    }
+
+    /// Remove `keys` once they're gone from the DB, and stop in-flight reads from
+    /// promoting what they got before that.
+    pub fn invalidate<'a>(&self, keys: impl IntoIterator<Item = &'a [u8]>) {
+        self.generation.fetch_add(1, Ordering::SeqCst);
+        for key in keys {
+            self.remove(key);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(i: u8) -> Vec<u8> {
+        // Bytes fold to 0 so everything lands in one shard
+        let mut key = vec![0; 32];
+        key[30] = i;
+        key[31] = i;
+        key
+    }
+
+    #[test]
+    fn test_get_insert_remove() {
+        let hot_cache = HotCache::new(1024 * 1024);
+        assert!(hot_cache.get(&key(1)).is_none());
+
+        hot_cache.insert(&key(1), b"one".to_vec());
+        assert_eq!(hot_cache.get(&key(1)), Some(b"one".to_vec()));
+
+        hot_cache.remove(&key(1));
+        assert!(hot_cache.get(&key(1)).is_none());
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        // Room for 2 entries of 32 + 18 bytes per shard
+        let hot_cache = HotCache::new(100 * SHARDS);
+        hot_cache.insert(&key(1), vec![0; 18]);
+        hot_cache.insert(&key(2), vec![0; 18]);
+
+        // Reading 1 makes 2 the least recently used
+        assert!(hot_cache.get(&key(1)).is_some());
+        hot_cache.insert(&key(3), vec![0; 18]);
+
+        assert!(hot_cache.get(&key(1)).is_some());
+        assert!(hot_cache.get(&key(2)).is_none());
+        assert!(hot_cache.get(&key(3)).is_some());
+    }
+
+    #[test]
+    fn test_get_matching() {
+        let hot_cache = HotCache::new(1024 * 1024);
+        hot_cache.insert_with_request(&key(1), b"request", b"one".to_vec());
+        hot_cache.insert(&key(2), b"two".to_vec());
+
+        assert_eq!(
+            hot_cache.get_matching(&key(1), b"request"),
+            Some(b"one".to_vec())
+        );
+        assert!(hot_cache
+            .get_matching(&key(1), b"another request")
+            .is_none());
+        // Nothing to check against
+        assert!(hot_cache.get_matching(&key(2), b"request").is_none());
+        assert_eq!(hot_cache.get(&key(1)), Some(b"one".to_vec()));
+    }
+
+    #[test]
+    fn test_disabled() {
+        let hot_cache = HotCache::new(0);
+        hot_cache.insert(&key(1), b"one".to_vec());
+        assert!(hot_cache.get(&key(1)).is_none());
+    }
+
+    #[test]
+    fn test_invalidate_stops_stale_promotions() {
+        let hot_cache = HotCache::new(1024 * 1024);
+        hot_cache.insert(&key(1), b"one".to_vec());
+
+        // Read from the DB before the reorg, promoted after it
+        let generation = hot_cache.generation();
+        hot_cache.invalidate([key(1).as_slice(), key(2).as_slice()]);
+        hot_cache.promote(&key(2), None, b"orphaned".to_vec(), generation);
+        assert!(hot_cache.get(&key(1)).is_none());
+        assert!(hot_cache.get(&key(2)).is_none());
+
+        hot_cache.promote(&key(2), None, b"two".to_vec(), hot_cache.generation());
+        assert_eq!(hot_cache.get(&key(2)), Some(b"two".to_vec()));
+    }
+}
@@ -0,0 +1,93 @@
+//! Layout of the keys cached responses are stored under.
+//!
+//! ```text
+//! | layout version (1) | hash algorithm (1) | digest, truncated or zero padded (30) |
+//! ```
+//!
+//! Tagging keys with the hash algorithm lets blake3 and xxhash builds share a cache
+//! without ever hitting each other's entries, so switching doesn't need a wipe.
+//! Keys written before the layout was versioned are a bare blake3 digest, see
+//! [`CacheKey::legacy`].
+
+/// Version of the key layout. Bump it if the layout or the request encoding changes.
+pub const KEY_LAYOUT_VERSION: u8 = 1;
+
+const BLAKE3_TAG: u8 = 1;
+const XXH3_TAG: u8 = 2;
+const DIGEST_LEN: usize = 30;
+
+/// Key of a cached response, along with the request it was made from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CacheKey {
+    key: [u8; 32],
+    request: String,
+}
+
+impl CacheKey {
+    /// Key for `request`, which must already be canonical: no `id`, and block tags
+    /// replaced with the numbers they point to.
+    pub fn new(request: String) -> Self {
+        let mut key = [0; 32];
+        key[0] = KEY_LAYOUT_VERSION;
+
+        #[cfg(not(feature = "xxhash"))]
+        {
+            key[1] = BLAKE3_TAG;
+            key[2..].copy_from_slice(&blake3::hash(request.as_bytes()).as_bytes()[..DIGEST_LEN]);
+        }
+        #[cfg(feature = "xxhash")]
+        {
+            key[1] = XXH3_TAG;
+            key[2..10]
+                .copy_from_slice(&xxhash_rust::xxh3::xxh3_64(request.as_bytes()).to_be_bytes());
+        }
+
+        Self { key, request }
+    }
+
+    /// The DB key.
+    pub fn as_bytes(&self) -> &[u8; 32] {
+        &self.key
+    }
+
+    /// The canonical request bytes the key was hashed from.
+    pub fn request(&self) -> &[u8] {
+        self.request.as_bytes()
+    }
+
+    /// Where this request was cached before keys were versioned, if it could've been.
+    ///
+    /// Only blake3 builds ever wrote unversioned keys.
+    pub fn legacy(&self) -> Option<[u8; 32]> {
+        match self.key[1] {
+            BLAKE3_TAG => Some(*blake3::hash(self.request.as_bytes()).as_bytes()),
+            XXH3_TAG => None,
+            _ => unreachable!("unknown hash algorithm tag"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        let request = r#"{"method":"eth_getBlockByNumber","params":["0x10",false]}"#;
+        let key = CacheKey::new(request.to_string());
+
+        assert_eq!(key.as_bytes()[0], KEY_LAYOUT_VERSION);
+        assert_eq!(key.request(), request.as_bytes());
+        assert_eq!(key, CacheKey::new(request.to_string()));
+        assert_ne!(
+            key.as_bytes(),
+            CacheKey::new(request.replace("0x10", "0x11")).as_bytes()
+        );
+
+        // Can't be mistaken for an unversioned entry of the same request
+        if let Some(legacy) = key.legacy() {
+            assert_ne!(&legacy, key.as_bytes());
+            assert_eq!(&legacy, blake3::hash(request.as_bytes()).as_bytes());
+        }
+    }
+}
@@ -0,0 +1,338 @@
+//! In-memory cache backend, for CI, preview environments and tests.
+//!
+//! Nothing touches the disk, so everything is gone on restart. Size is bounded by
+//! `capacity_bytes`: once a write takes us over, we drop entries until we're at 90%
+//! of it. Which ones is decided by a CLOCK sweep, which approximates LRU without
+//! reads having to take a lock or eviction having to sort everything.
+
+use crate::{
+    config::cache_setup::is_reserved_key,
+    database::types::{
+        Batch,
+        BatchOp,
+        GenericBytes,
+        GenericDatabase,
+        KvIter,
+        CACHE_HITS,
+        CACHE_MISSES,
+        DB_EVICTIONS,
+        DB_SIZE_MB,
+    },
+};
+
+use std::{
+    collections::VecDeque,
+    convert::Infallible,
+    sync::{
+        atomic::{
+            AtomicBool,
+            AtomicU64,
+            Ordering,
+        },
+        Mutex,
+        MutexGuard,
+    },
+};
+
+use dashmap::DashMap;
+use rust_tracing::deps::metrics;
+
+/// Options for [`MemoryDb`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MemoryConfig {
+    /// Max size of keys and values we keep, in bytes.
+    pub capacity_bytes: u64,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            capacity_bytes: 512 * 1024 * 1024,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    value: Vec<u8>,
+    /// Set when read, so the clock hand gives the entry a second chance.
+    referenced: AtomicBool,
+    /// Tells this write apart from older ones of the same key still on the clock.
+    seq: u64,
+}
+
+/// A bounded [`GenericDatabase`] backed by a concurrent hashmap.
+#[derive(Debug)]
+pub struct MemoryDb {
+    entries: DashMap<Vec<u8>, Entry>,
+    bytes: AtomicU64,
+    /// Evictable keys in the order the clock hand visits them, with the `seq` of the
+    /// write that put them there.
+    clock: Mutex<VecDeque<(u64, Vec<u8>)>>,
+    next_seq: AtomicU64,
+    capacity_bytes: u64,
+}
+
+impl MemoryDb {
+    pub fn new(config: &MemoryConfig) -> Self {
+        Self {
+            entries: DashMap::new(),
+            bytes: AtomicU64::new(0),
+            clock: Mutex::new(VecDeque::new()),
+            next_seq: AtomicU64::new(0),
+            capacity_bytes: config.capacity_bytes,
+        }
+    }
+
+    fn clock(&self) -> MutexGuard<'_, VecDeque<(u64, Vec<u8>)>> {
+        self.clock.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn update_gauge(&self) {
+        let size = self.bytes.load(Ordering::Relaxed) / (1024 * 1024);
+        metrics::gauge!(DB_SIZE_MB).set(size as f64);
+    }
+
+    fn insert(&self, key: &[u8], value: &[u8]) {
+        let size = (key.len() + value.len()) as u64;
+        let seq = self.next_seq.fetch_add(1, Ordering::Relaxed);
+        let entry = Entry {
+            value: value.to_vec(),
+            referenced: AtomicBool::new(false),
+            seq,
+        };
+
+        match self.entries.insert(key.to_vec(), entry) {
+            Some(old) => {
+                let old_size = (key.len() + old.value.len()) as u64;
+                self.bytes.fetch_add(size, Ordering::Relaxed);
+                self.bytes.fetch_sub(old_size, Ordering::Relaxed);
+            }
+            None => {
+                self.bytes.fetch_add(size, Ordering::Relaxed);
+            }
+        }
+
+        if is_reserved_key(key) {
+            return;
+        }
+        let mut clock = self.clock();
+        clock.push_back((seq, key.to_vec()));
+
+        // Overwrites and deletes leave their old position behind. Once those are most
+        // of the clock, drop them so it can't grow past the amount of entries for long.
+        if clock.len() > 2 * self.entries.len() + 64 {
+            clock.retain(|(seq, key)| self.is_current(key, *seq));
+        }
+    }
+
+    /// Returns true if `seq` is the latest write to `key`, and it's still there.
+    fn is_current(&self, key: &[u8], seq: u64) -> bool {
+        self.entries.get(key).is_some_and(|entry| entry.seq == seq)
+    }
+
+    fn remove(&self, key: &[u8]) {
+        if let Some((key, old)) = self.entries.remove(key) {
+            self.bytes
+                .fetch_sub((key.len() + old.value.len()) as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Drop the least recently used entries if we're over capacity.
+    fn enforce_capacity(&self) {
+        if self.bytes.load(Ordering::Relaxed) <= self.capacity_bytes {
+            self.update_gauge();
+            return;
+        }
+
+        // Leave some headroom so we don't have to do this on every write
+        let target = self.capacity_bytes / 10 * 9;
+        let mut clock = self.clock();
+        let mut evicted = 0;
+
+        // Every entry that gets a second chance loses its reference bit, so this
+        // ends after at most two trips around the clock.
+        while self.bytes.load(Ordering::Relaxed) > target {
+            let Some((seq, key)) = clock.pop_front() else {
+                break;
+            };
+
+            let referenced = match self.entries.get(&key) {
+                Some(entry) if entry.seq == seq => entry.referenced.swap(false, Ordering::Relaxed),
+                _ => continue,
+            };
+            if referenced {
+                clock.push_back((seq, key));
+                continue;
+            }
+
+            // Unless it was overwritten since we looked
+            if let Some((key, old)) = self.entries.remove_if(&key, |_, entry| entry.seq == seq) {
+                self.bytes
+                    .fetch_sub((key.len() + old.value.len()) as u64, Ordering::Relaxed);
+                evicted += 1;
+            }
+        }
+
+        metrics::counter!(DB_EVICTIONS, "reason" => "size").increment(evicted);
+        self.update_gauge();
+    }
+}
+
+impl GenericDatabase for MemoryDb {
+    type Error = Infallible;
+    type Config = MemoryConfig;
+
+    fn open(config: &Self::Config) -> Result<Self, Self::Error> {
+        Ok(Self::new(config))
+    }
+
+    fn read<K: GenericBytes>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        let value = self.entries.get(key.as_ref()).map(|entry| {
+            entry.referenced.store(true, Ordering::Relaxed);
+            entry.value.clone()
+        });
+
+        if value.is_some() {
+            metrics::counter!(CACHE_HITS).increment(1);
+        } else {
+            metrics::counter!(CACHE_MISSES).increment(1);
+        }
+        Ok(value)
+    }
+
+    fn write<K, V>(&self, key: K, val: V) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        self.insert(key.as_ref(), val.as_ref());
+        self.enforce_capacity();
+        Ok(())
+    }
+
+    fn batch<K, V>(&self, batch: Batch<K, V>) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        for op in batch.ops() {
+            match op {
+                BatchOp::Insert(key, value) => self.insert(key.as_ref(), value.as_ref()),
+                BatchOp::Delete(key) => self.remove(key.as_ref()),
+            }
+        }
+        self.enforce_capacity();
+        Ok(())
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        let mut clock = self.clock();
+        self.entries.clear();
+        clock.clear();
+        self.bytes.store(0, Ordering::Relaxed);
+        self.update_gauge();
+        Ok(())
+    }
+
+    // Hashmaps aren't ordered, so we collect the matches and sort them.
+    // Prefix scans only happen on startup and for snapshots, so this is fine.
+    fn iter_prefix(&self, prefix: &[u8]) -> KvIter<'_, Self::Error> {
+        let mut matches = self
+            .entries
+            .iter()
+            .filter(|entry| entry.key().starts_with(prefix))
+            .map(|entry| (entry.key().clone(), entry.value().value.clone()))
+            .collect::<Vec<_>>();
+        matches.sort_unstable_by(|a, b| a.0.cmp(&b.0));
+
+        Box::new(matches.into_iter().map(Ok))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_utils::key;
+
+    #[test]
+    fn test_read_write() {
+        let db = MemoryDb::new(&MemoryConfig::default());
+        db.write(key(1), b"one".to_vec()).unwrap();
+        db.write(key(1), b"uno".to_vec()).unwrap();
+
+        assert_eq!(db.read(key(1)).unwrap(), Some(b"uno".to_vec()));
+        assert_eq!(db.read(key(2)).unwrap(), None);
+        assert_eq!(db.bytes.load(Ordering::Relaxed), 35);
+
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(2);
+        batch.insert(key(2), b"two".to_vec());
+        batch.delete(key(1));
+        db.batch(batch).unwrap();
+
+        assert_eq!(db.read(key(1)).unwrap(), None);
+        assert_eq!(db.read(key(2)).unwrap(), Some(b"two".to_vec()));
+        assert_eq!(db.bytes.load(Ordering::Relaxed), 35);
+    }
+
+    #[test]
+    fn test_evicts_least_recently_used() {
+        // Room for 3 entries of 32 + 18 bytes, and the hash marker
+        let db = MemoryDb::new(&MemoryConfig {
+            capacity_bytes: 160,
+        });
+        for i in 1..=3 {
+            db.write(key(i), vec![0; 18]).unwrap();
+        }
+        db.write(b"blake3".to_vec(), b"true".to_vec()).unwrap();
+
+        // Reading 1 makes 2 the least recently used
+        assert!(db.read(key(1)).unwrap().is_some());
+        db.write(key(4), vec![0; 18]).unwrap();
+
+        assert!(db.read(key(2)).unwrap().is_none());
+        assert!(db.read(key(1)).unwrap().is_some());
+        assert!(db.read(key(4)).unwrap().is_some());
+        assert!(db.read(b"blake3".to_vec()).unwrap().is_some());
+        assert!(db.bytes.load(Ordering::Relaxed) <= 160);
+    }
+
+    #[test]
+    fn test_iter_prefix_is_ordered() {
+        let db = MemoryDb::new(&MemoryConfig::default());
+        for key in [b"ab2", b"ab1", b"ac1", b"aa1"] {
+            db.write(key.to_vec(), b"v".to_vec()).unwrap();
+        }
+
+        let keys = db
+            .iter_prefix(b"ab")
+            .map(|item| item.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, vec![b"ab1".to_vec(), b"ab2".to_vec()]);
+    }
+
+    #[test]
+    fn test_overwrites_dont_pile_up() {
+        let db = MemoryDb::new(&MemoryConfig {
+            capacity_bytes: 160,
+        });
+        for _ in 0..1000 {
+            db.write(key(1), vec![0; 18]).unwrap();
+        }
+        assert!(db.clock().len() <= 2 * db.entries.len() + 64);
+
+        // The old positions of 1 don't count as it being older than 2 and 3
+        db.write(key(2), vec![0; 18]).unwrap();
+        db.write(key(3), vec![0; 18]).unwrap();
+        db.write(key(1), vec![0; 18]).unwrap();
+        db.write(key(4), vec![0; 18]).unwrap();
+
+        assert!(db.read(key(1)).unwrap().is_some());
+        assert!(db.read(key(2)).unwrap().is_none());
+        assert!(db.read(key(4)).unwrap().is_some());
+    }
+}
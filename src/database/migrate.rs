@@ -0,0 +1,156 @@
+//! Offline copying of the cache between DB backends, for `blutgang cache migrate`.
+
+use crate::{
+    config::cache_setup::stored_hash_algorithm,
+    database::{
+        error::MigrateError,
+        types::{
+            Batch,
+            GenericDatabase,
+        },
+    },
+};
+
+/// Entries we copy per batch.
+const MIGRATE_BATCH_LEN: usize = 4096;
+
+/// What we copied. Both DBs iterate in key order, so a digest over every
+/// entry in iteration order has to match on both sides.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct MigrationSummary {
+    pub entries: u64,
+    pub digest: [u8; 32],
+}
+
+/// Hashes entries in order, length prefixing both halves so entries can't run into each other.
+#[derive(Default)]
+struct Digest {
+    hasher: blake3::Hasher,
+    entries: u64,
+}
+
+impl Digest {
+    fn update(&mut self, key: &[u8], value: &[u8]) {
+        self.hasher.update(&(key.len() as u64).to_be_bytes());
+        self.hasher.update(key);
+        self.hasher.update(&(value.len() as u64).to_be_bytes());
+        self.hasher.update(value);
+        self.entries += 1;
+    }
+
+    fn finalize(self) -> MigrationSummary {
+        MigrationSummary {
+            entries: self.entries,
+            digest: *self.hasher.finalize().as_bytes(),
+        }
+    }
+}
+
+/// Copy every entry in `from` into the empty `to`, then check that `to` holds exactly
+/// what we read from `from`.
+///
+/// The hash marker keys are regular entries, so they get carried over with everything else.
+pub fn migrate<S, D>(from: &S, to: &D) -> Result<MigrationSummary, MigrateError>
+where
+    S: GenericDatabase,
+    D: GenericDatabase,
+{
+    if to.iter_prefix(&[]).next().is_some() {
+        return Err(MigrateError::DestinationNotEmpty);
+    }
+
+    let mut digest = Digest::default();
+    let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(MIGRATE_BATCH_LEN);
+    let mut pending = 0;
+
+    for item in from.iter_prefix(&[]) {
+        let (key, value) = item.map_err(|err| MigrateError::Source(format!("{err:?}")))?;
+        digest.update(&key, &value);
+        batch.insert(key, value);
+        pending += 1;
+
+        if pending == MIGRATE_BATCH_LEN {
+            let full = std::mem::replace(&mut batch, Batch::with_capacity(MIGRATE_BATCH_LEN));
+            to.batch(full)
+                .map_err(|err| MigrateError::Destination(format!("{err:?}")))?;
+            pending = 0;
+
+            if digest.entries % (100 * MIGRATE_BATCH_LEN as u64) == 0 {
+                tracing::info!(entries = digest.entries, "Migrating cache...");
+            }
+        }
+    }
+
+    to.batch(batch)
+        .map_err(|err| MigrateError::Destination(format!("{err:?}")))?;
+    to.flush()
+        .map_err(|err| MigrateError::Destination(format!("{err:?}")))?;
+    let copied = digest.finalize();
+
+    let mut verify = Digest::default();
+    for item in to.iter_prefix(&[]) {
+        let (key, value) = item.map_err(|err| MigrateError::Destination(format!("{err:?}")))?;
+        verify.update(&key, &value);
+    }
+    let verified = verify.finalize();
+
+    if verified.entries != copied.entries {
+        return Err(MigrateError::Mismatch {
+            reason: format!(
+                "copied {} entries, found {}",
+                copied.entries, verified.entries
+            ),
+        });
+    }
+    if verified.digest != copied.digest {
+        return Err(MigrateError::Mismatch {
+            reason: "entry hashes differ".to_string(),
+        });
+    }
+    if stored_hash_algorithm(from) != stored_hash_algorithm(to) {
+        return Err(MigrateError::Mismatch {
+            reason: "hash algorithm markers differ".to_string(),
+        });
+    }
+
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::test_utils::db;
+
+    #[test]
+    fn test_migrate() {
+        let from = db();
+        from.write(b"blake3".to_vec(), b"true".to_vec()).unwrap();
+        for i in 0..(MIGRATE_BATCH_LEN as u64 + 10) {
+            from.write(i.to_be_bytes().to_vec(), format!("value {i}").into_bytes())
+                .unwrap();
+        }
+
+        let to = db();
+        let summary = migrate(&from, &to).unwrap();
+        assert_eq!(summary.entries, MIGRATE_BATCH_LEN as u64 + 11);
+        assert_eq!(to.read(b"blake3".to_vec()).unwrap(), Some(b"true".to_vec()));
+        assert_eq!(
+            to.read(7u64.to_be_bytes().to_vec()).unwrap(),
+            Some(b"value 7".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_refuses_non_empty_destination() {
+        let from = db();
+        from.write(b"key".to_vec(), b"value".to_vec()).unwrap();
+        let to = db();
+        to.write(b"other".to_vec(), b"value".to_vec()).unwrap();
+
+        assert!(matches!(
+            migrate(&from, &to),
+            Err(MigrateError::DestinationNotEmpty)
+        ));
+        assert!(to.read(b"key".to_vec()).unwrap().is_none());
+    }
+}
@@ -1,3 +1,30 @@
 pub mod accept;
+pub mod dedup;
+pub mod envelope;
 pub mod error;
+pub mod eviction;
+pub mod hot_cache;
+pub mod key;
+pub mod memory;
+pub mod migrate;
+pub mod snapshot;
 pub mod types;
+pub mod write_behind;
+
+#[cfg(test)]
+pub(crate) mod test_utils {
+    use sled::{
+        Config,
+        Db,
+    };
+
+    /// Opens a sled DB in a temporary directory.
+    pub fn db() -> Db<{ crate::FANOUT }> {
+        Db::open_with_config(&Config::tmp().unwrap()).unwrap()
+    }
+
+    /// A key as long as the hash of a request.
+    pub fn key(i: u8) -> Vec<u8> {
+        vec![i; 32]
+    }
+}
@@ -0,0 +1,373 @@
+//! Portable cache snapshots for `blutgang cache export` and `blutgang cache import`.
+//!
+//! ```text
+//! | magic (8) | version (1) | hash algo len (1) | hash algo | chain id (8) | zstd stream of blocks |
+//! ```
+//!
+//! The chain ID is 0 if the exported cache didn't know which chain it was for.
+//! Each block is `| entries (4) | payload len (4) | payload | crc32 of payload (4) |`, and the
+//! payload is `| key len (4) | key | value len (4) | value |` per entry. An empty block marks
+//! the end of the snapshot. Checksumming per block lets imports stream huge snapshots
+//! without writing anything from a corrupt block.
+
+use crate::{
+    config::cache_setup::{
+        hash_algorithm,
+        stored_chain_id,
+        stored_hash_algorithm,
+    },
+    database::{
+        error::SnapshotError,
+        eviction,
+        types::{
+            Batch,
+            GenericDatabase,
+        },
+    },
+};
+
+use std::{
+    fs::File,
+    io::{
+        BufReader,
+        BufWriter,
+        Read,
+        Write,
+    },
+    path::Path,
+};
+
+const SNAPSHOT_MAGIC: &[u8; 8] = b"BLUTSNAP";
+const SNAPSHOT_VERSION: u8 = 1;
+/// Entries per block, and per DB batch on import.
+const BLOCK_ENTRIES: usize = 4096;
+const ZSTD_LEVEL: i32 = 3;
+
+fn write_u32<W: Write>(writer: &mut W, n: usize) -> Result<(), SnapshotError> {
+    let n = u32::try_from(n).map_err(|_| SnapshotError::TooLarge)?;
+    writer.write_all(&n.to_be_bytes())?;
+    Ok(())
+}
+
+fn read_u32<R: Read>(reader: &mut R) -> Result<u32, SnapshotError> {
+    let mut buf = [0; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_be_bytes(buf))
+}
+
+fn write_block<W: Write>(
+    writer: &mut W,
+    entries: usize,
+    payload: &[u8],
+) -> Result<(), SnapshotError> {
+    write_u32(writer, entries)?;
+    write_u32(writer, payload.len())?;
+    writer.write_all(payload)?;
+    writer.write_all(&crc32fast::hash(payload).to_be_bytes())?;
+    Ok(())
+}
+
+/// Splits the next `len` prefixed byte string off `payload`.
+fn take_field<'a>(payload: &mut &'a [u8], block: u64) -> Result<&'a [u8], SnapshotError> {
+    let corrupt = || SnapshotError::Corrupt(block);
+
+    let len = payload.get(..4).ok_or_else(corrupt)?;
+    let len = u32::from_be_bytes(len.try_into().map_err(|_| corrupt())?) as usize;
+    let field = payload.get(4..4 + len).ok_or_else(corrupt)?;
+    *payload = &payload[4 + len..];
+    Ok(field)
+}
+
+/// Write every entry in `cache` to `writer`. Returns the amount of entries written.
+pub fn export_to<DB: GenericDatabase, W: Write>(
+    cache: &DB,
+    mut writer: W,
+) -> Result<u64, SnapshotError> {
+    // Record what the DB was actually written with, which may not be what we're built with
+    let algo = stored_hash_algorithm(cache).unwrap_or(hash_algorithm());
+    writer.write_all(SNAPSHOT_MAGIC)?;
+    writer.write_all(&[SNAPSHOT_VERSION, algo.len() as u8])?;
+    writer.write_all(algo.as_bytes())?;
+    writer.write_all(&stored_chain_id(cache).unwrap_or(0).to_be_bytes())?;
+
+    let mut encoder = zstd::stream::Encoder::new(writer, ZSTD_LEVEL)?;
+    let mut payload = Vec::new();
+    let mut entries = 0;
+    let mut total = 0;
+
+    for item in cache.iter_prefix(&[]) {
+        let (key, value) = item.map_err(|err| SnapshotError::Db(format!("{err:?}")))?;
+        write_u32(&mut payload, key.len())?;
+        payload.extend_from_slice(&key);
+        write_u32(&mut payload, value.len())?;
+        payload.extend_from_slice(&value);
+        entries += 1;
+
+        if entries == BLOCK_ENTRIES {
+            write_block(&mut encoder, entries, &payload)?;
+            total += entries as u64;
+            payload.clear();
+            entries = 0;
+        }
+    }
+
+    if entries != 0 {
+        write_block(&mut encoder, entries, &payload)?;
+        total += entries as u64;
+    }
+    write_block(&mut encoder, 0, &[])?;
+    encoder.finish()?.flush()?;
+
+    Ok(total)
+}
+
+/// Load a snapshot from `reader` into `cache`. Returns the amount of entries imported.
+///
+/// Refuses snapshots keyed with a different hash algorithm than ours, since none
+/// of their entries would ever get hit. Unless `allow_other_hash` is set, in which
+/// case we only warn.
+///
+/// Snapshots for another chain than the one `cache` is for are always refused.
+pub fn import_from<DB: GenericDatabase, R: Read>(
+    cache: &DB,
+    mut reader: R,
+    allow_other_hash: bool,
+) -> Result<u64, SnapshotError> {
+    let mut magic = [0; 8];
+    reader.read_exact(&mut magic)?;
+    if &magic != SNAPSHOT_MAGIC {
+        return Err(SnapshotError::BadMagic);
+    }
+
+    let mut preamble = [0; 2];
+    reader.read_exact(&mut preamble)?;
+    let [version, algo_len] = preamble;
+    if version != SNAPSHOT_VERSION {
+        return Err(SnapshotError::UnsupportedVersion(version));
+    }
+
+    let mut algo = vec![0; algo_len as usize];
+    reader.read_exact(&mut algo)?;
+    let algo = String::from_utf8_lossy(&algo).into_owned();
+    if algo != hash_algorithm() {
+        if !allow_other_hash {
+            return Err(SnapshotError::HashMismatch {
+                snapshot: algo,
+                ours: hash_algorithm(),
+            });
+        }
+        tracing::warn!(
+            snapshot = algo,
+            ours = hash_algorithm(),
+            "Snapshot was written with a different hash algorithm! Its entries won't be served, \
+            clear the cache to reclaim their space."
+        );
+    }
+
+    let mut chain_id = [0; 8];
+    reader.read_exact(&mut chain_id)?;
+    let chain_id = u64::from_be_bytes(chain_id);
+    match stored_chain_id(cache) {
+        Some(ours) if chain_id != 0 && chain_id != ours => {
+            return Err(SnapshotError::ChainMismatch {
+                snapshot: chain_id,
+                ours,
+            });
+        }
+        _ => {}
+    }
+
+    let mut decoder = zstd::stream::Decoder::new(reader)?;
+    let mut total = 0;
+
+    for block in 0.. {
+        let entries = read_u32(&mut decoder)? as usize;
+        let len = read_u32(&mut decoder)? as usize;
+        let mut payload = vec![0; len];
+        decoder.read_exact(&mut payload)?;
+        if read_u32(&mut decoder)? != crc32fast::hash(&payload) {
+            return Err(SnapshotError::Corrupt(block));
+        }
+
+        if entries == 0 {
+            break;
+        }
+
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(entries);
+        let mut rest = payload.as_slice();
+        for _ in 0..entries {
+            let key = take_field(&mut rest, block)?;
+            let value = take_field(&mut rest, block)?;
+            batch.insert(key.to_vec(), value.to_vec());
+        }
+
+        cache
+            .batch(batch)
+            .map_err(|err| SnapshotError::Db(format!("{err:?}")))?;
+        total += entries as u64;
+    }
+
+    // Eviction metadata in the snapshot may not add up with what was already here
+    eviction::forget_totals(cache)
+        .and_then(|_| cache.flush())
+        .map_err(|err| SnapshotError::Db(format!("{err:?}")))?;
+
+    Ok(total)
+}
+
+/// Write every entry in `cache` to a snapshot file at `out`.
+pub fn export<DB: GenericDatabase>(cache: &DB, out: &Path) -> Result<u64, SnapshotError> {
+    let mut writer = BufWriter::new(File::create(out)?);
+    let total = export_to(cache, &mut writer)?;
+    writer.flush()?;
+    Ok(total)
+}
+
+/// Load the snapshot file at `input` into `cache`.
+pub fn import<DB: GenericDatabase>(
+    cache: &DB,
+    input: &Path,
+    allow_other_hash: bool,
+) -> Result<u64, SnapshotError> {
+    import_from(cache, BufReader::new(File::open(input)?), allow_other_hash)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        config::cache_setup::setup_data,
+        database::test_utils::db,
+    };
+
+    fn snapshot_of(entries: usize) -> Vec<u8> {
+        let cache = db();
+        cache
+            .write(hash_algorithm().as_bytes(), b"true".as_slice())
+            .unwrap();
+        for i in 0..entries {
+            cache
+                .write((i as u64).to_be_bytes(), format!("value {i}").into_bytes())
+                .unwrap();
+        }
+
+        let mut snapshot = Vec::new();
+        assert_eq!(
+            export_to(&cache, &mut snapshot).unwrap(),
+            entries as u64 + 1
+        );
+        snapshot
+    }
+
+    #[test]
+    fn test_roundtrip() {
+        // Spans more than one block
+        let entries = BLOCK_ENTRIES + 10;
+        let snapshot = snapshot_of(entries);
+
+        let cache = db();
+        assert_eq!(
+            import_from(&cache, snapshot.as_slice(), false).unwrap(),
+            entries as u64 + 1
+        );
+        assert_eq!(cache.read(5u64.to_be_bytes()).unwrap().unwrap(), b"value 5");
+        assert_eq!(
+            cache
+                .read((entries as u64 - 1).to_be_bytes())
+                .unwrap()
+                .unwrap(),
+            format!("value {}", entries - 1).into_bytes()
+        );
+    }
+
+    #[test]
+    fn test_refuses_other_hash_algorithm() {
+        let mut snapshot = snapshot_of(1);
+        let other = if hash_algorithm() == "blake3" {
+            b"xxhash"
+        } else {
+            b"blake3"
+        };
+        snapshot[10..16].copy_from_slice(other);
+
+        assert!(matches!(
+            import_from(&db(), snapshot.as_slice(), false),
+            Err(SnapshotError::HashMismatch { .. })
+        ));
+    }
+
+    #[test]
+    fn test_imports_other_hash_algorithm_if_allowed() {
+        let mut snapshot = snapshot_of(1);
+        let other = if hash_algorithm() == "blake3" {
+            b"xxhash"
+        } else {
+            b"blake3"
+        };
+        snapshot[10..16].copy_from_slice(other);
+
+        let cache = db();
+        assert_eq!(import_from(&cache, snapshot.as_slice(), true).unwrap(), 2);
+        assert_eq!(cache.read(0u64.to_be_bytes()).unwrap().unwrap(), b"value 0");
+    }
+
+    #[test]
+    fn test_refuses_other_chain() {
+        let source = db();
+        setup_data(&source, false, Some(1));
+        source
+            .write(b"key".as_slice(), b"value".as_slice())
+            .unwrap();
+        let mut snapshot = Vec::new();
+        export_to(&source, &mut snapshot).unwrap();
+
+        let cache = db();
+        setup_data(&cache, false, Some(2));
+        assert!(matches!(
+            import_from(&cache, snapshot.as_slice(), false),
+            Err(SnapshotError::ChainMismatch {
+                snapshot: 1,
+                ours: 2
+            })
+        ));
+        assert!(cache.read(b"key").unwrap().is_none());
+        assert_eq!(stored_chain_id(&cache), Some(2));
+
+        // Same chain, or a cache that doesn't know yet, is fine
+        let cache = db();
+        setup_data(&cache, false, Some(1));
+        assert!(import_from(&cache, snapshot.as_slice(), false).is_ok());
+        let cache = db();
+        assert!(import_from(&cache, snapshot.as_slice(), false).is_ok());
+        assert_eq!(stored_chain_id(&cache), Some(1));
+    }
+
+    #[test]
+    fn test_refuses_garbage() {
+        assert!(matches!(
+            import_from(&db(), b"definitely not a snapshot".as_slice(), false),
+            Err(SnapshotError::BadMagic)
+        ));
+    }
+
+    #[test]
+    fn test_detects_corruption() {
+        let cache = db();
+        let mut raw = Vec::new();
+        write_block(&mut raw, 1, b"\0\0\0\x01k\0\0\0\x01v").unwrap();
+        // Flip a byte in the payload, after the entry count and length
+        raw[9] ^= 0xff;
+
+        let mut snapshot = SNAPSHOT_MAGIC.to_vec();
+        snapshot.extend_from_slice(&[SNAPSHOT_VERSION, hash_algorithm().len() as u8]);
+        snapshot.extend_from_slice(hash_algorithm().as_bytes());
+        snapshot.extend_from_slice(&0u64.to_be_bytes());
+        snapshot.extend_from_slice(&zstd::encode_all(raw.as_slice(), ZSTD_LEVEL).unwrap());
+
+        assert!(matches!(
+            import_from(&cache, snapshot.as_slice(), false),
+            Err(SnapshotError::Corrupt(0))
+        ));
+        assert!(cache.read(b"k").unwrap().is_none());
+    }
+}
@@ -4,18 +4,29 @@ use tokio::sync::{
     oneshot,
 };
 
-const CACHE_HITS: &str = "cache_hits";
-const CACHE_MISSES: &str = "cache_misses";
-const DB_SIZE_MB: &str = "db_size_mb";
+pub(super) const CACHE_HITS: &str = "cache_hits";
+pub(super) const CACHE_MISSES: &str = "cache_misses";
+pub(super) const DB_SIZE_MB: &str = "db_size_mb";
+pub(super) const DB_TRACKED_ENTRIES: &str = "db_tracked_entries";
+pub(super) const DB_TRACKED_SIZE_MB: &str = "db_tracked_size_mb";
+pub(super) const DB_EVICTIONS: &str = "db_evictions";
+pub(super) const DB_QUEUE_DEPTH: &str = "db_queue_depth";
+pub(super) const DB_READS_IN_FLIGHT: &str = "db_reads_in_flight";
+pub(super) const CACHE_COLLISIONS: &str = "cache_collisions";
+pub(super) const DB_DEDUP_HITS: &str = "db_dedup_hits";
 const ROCKSDB_SIZE_PROPERTY: &str = "rocksdb.total-sst-files-size";
 
-/// Channel for sending requests to the database thread
+/// Channel for sending requests to the database task
 ///
 /// The enclosing struct contains the request and a oneshot sender
-/// for sending back a response.
-pub type RequestBus<K, V> = mpsc::UnboundedSender<DbRequest<K, V>>;
+/// for sending back a response. It's bounded, so senders wait
+/// instead of piling up requests when the DB falls behind.
+pub type RequestBus<K, V> = mpsc::Sender<DbRequest<K, V>>;
 pub type RequestSender = oneshot::Sender<Option<Vec<u8>>>;
 
+/// Iterator over key/value pairs returned by `GenericDatabase::iter_prefix`.
+pub type KvIter<'a, E> = Box<dyn Iterator<Item = Result<(Vec<u8>, Vec<u8>), E>> + 'a>;
+
 /// Shared bytes trait for key value trait constraints on `sled` and `rocksdb` functions.
 pub trait GenericBytes:
     Clone + std::cmp::Ord + AsRef<[u8]> + Into<Vec<u8>> + Sized + Send + Sync
@@ -27,7 +38,7 @@ impl<T> GenericBytes for T where
 }
 
 /// Generic batch operation.
-enum BatchOp<K, V>
+pub(crate) enum BatchOp<K, V>
 where
     K: GenericBytes,
     V: GenericBytes,
@@ -49,13 +60,15 @@ where
     pub fn with_capacity(capacity: usize) -> Self {
         Self(Vec::with_capacity(capacity))
     }
-    #[allow(dead_code)]
     pub fn insert(&mut self, key: K, value: V) {
         self.0.push(BatchOp::Insert(key, value))
     }
     pub fn delete(&mut self, key: K) {
         self.0.push(BatchOp::Delete(key))
     }
+    pub(crate) fn ops(&self) -> impl ExactSizeIterator<Item = &BatchOp<K, V>> {
+        self.0.iter()
+    }
 }
 impl<K, V> From<Vec<BatchOp<K, V>>> for Batch<K, V>
 where
@@ -68,7 +81,9 @@ where
 }
 
 /// A generic database layer abstraction.
-pub trait GenericDatabase: Send {
+///
+/// Handles are shared between threads, so reads can run concurrently.
+pub trait GenericDatabase: Send + Sync {
     type Error: std::fmt::Debug;
     type Config;
 
@@ -96,6 +111,24 @@ pub trait GenericDatabase: Send {
     fn flush(&self) -> Result<(), Self::Error>;
 
     fn clear(&self) -> Result<(), Self::Error>;
+
+    /// Iterate over all key/value pairs whose key starts with `prefix`, in key order.
+    fn iter_prefix(&self, prefix: &[u8]) -> KvIter<'_, Self::Error>;
+
+    /// How often the DB task should call `maintain`, if at all.
+    fn maintenance_interval(&self) -> Option<std::time::Duration> {
+        None
+    }
+
+    /// Periodic housekeeping, ran by the DB task in between writes.
+    fn maintain(&self) -> Result<(), Self::Error> {
+        Ok(())
+    }
+
+    /// Update gauges that are too expensive to update on every write, like the size on disk.
+    ///
+    /// Called by the DB task on a timer.
+    fn sample_metrics(&self) {}
 }
 
 impl GenericDatabase for sled::Db<{ crate::FANOUT }> {
@@ -130,12 +163,7 @@ impl GenericDatabase for sled::Db<{ crate::FANOUT }> {
     {
         // NOTE: Not sure if we ever used the returned value of a write for sled,
         // but we can't do that for rockdb so I'm assuming this is fine.
-        self.insert(key, val.as_ref()).map(|_| ()).inspect(|_| {
-            match self.size_on_disk().map(|size| size / (1024 * 1024)) {
-                Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size as f64),
-                Err(err) => tracing::warn!(?err, "failed to gauge database size"),
-            }
-        })
+        self.insert(key, val.as_ref()).map(|_| ())
     }
 
     fn batch<K, V>(&self, batch: Batch<K, V>) -> Result<(), Self::Error>
@@ -150,33 +178,33 @@ impl GenericDatabase for sled::Db<{ crate::FANOUT }> {
                 BatchOp::Delete(key) => buf.remove(key.as_ref()),
             }
         });
-        self.apply_batch(buf).inspect(|_| {
-            match self.size_on_disk().map(|size| size / (1024 * 1024)) {
-                Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size as f64),
-                Err(err) => tracing::warn!(?err, "failed to gauge database size"),
-            }
-        })
+        self.apply_batch(buf)
     }
 
     // TODO: Consider collecting other metrics from flush.
     fn flush(&self) -> Result<(), Self::Error> {
         sled::Tree::<{ crate::FANOUT }>::flush(self)
             .map(|_| ())
-            .inspect(|_| {
-                match self.size_on_disk().map(|size| size / (1024 * 1024)) {
-                    Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size as f64),
-                    Err(err) => tracing::warn!(?err, "failed to gauge database size"),
-                }
-            })
+            .inspect(|_| GenericDatabase::sample_metrics(self))
     }
 
     fn clear(&self) -> Result<(), Self::Error> {
-        sled::Tree::<{ crate::FANOUT }>::clear(self).inspect(|_| {
-            match self.size_on_disk().map(|size| size / (1024 * 1024)) {
-                Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size as f64),
-                Err(err) => tracing::warn!(?err, "failed to gauge database size"),
-            }
-        })
+        sled::Tree::<{ crate::FANOUT }>::clear(self)
+            .inspect(|_| GenericDatabase::sample_metrics(self))
+    }
+
+    fn iter_prefix(&self, prefix: &[u8]) -> KvIter<'_, Self::Error> {
+        Box::new(
+            sled::Tree::<{ crate::FANOUT }>::scan_prefix(self, prefix.to_vec())
+                .map(|item| item.map(|(key, value)| (key.to_vec(), value.to_vec()))),
+        )
+    }
+
+    fn sample_metrics(&self) {
+        match self.size_on_disk().map(|size| size / (1024 * 1024)) {
+            Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size as f64),
+            Err(err) => tracing::warn!(?err, "failed to gauge database size"),
+        }
     }
 }
 
@@ -218,15 +246,7 @@ impl<T: rocksdb::ThreadMode + Send> GenericDatabase for rocksdb::DBWithThreadMod
         K: GenericBytes,
         V: GenericBytes,
     {
-        self.put(key, val).inspect(|_| {
-            match self
-                .property_int_value(ROCKSDB_SIZE_PROPERTY)
-                .map(|opt| opt.map(|size| size / (1024 * 1024)))
-            {
-                Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size.unwrap_or_default() as f64),
-                Err(err) => tracing::warn!(?err, "failed to gauge database size"),
-            }
-        })
+        self.put(key, val)
     }
 
     fn batch<K, V>(&self, batch: Batch<K, V>) -> Result<(), Self::Error>
@@ -241,37 +261,50 @@ impl<T: rocksdb::ThreadMode + Send> GenericDatabase for rocksdb::DBWithThreadMod
                 BatchOp::Delete(key) => buf.delete(key),
             }
         });
-        self.write(buf).inspect(|_| {
-            match self
-                .property_int_value(ROCKSDB_SIZE_PROPERTY)
-                .map(|opt| opt.map(|size| size / (1024 * 1024)))
-            {
-                Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size.unwrap_or_default() as f64),
-                Err(err) => tracing::warn!(?err, "failed to gauge database size"),
-            }
-        })
+        self.write(buf)
     }
 
     fn flush(&self) -> Result<(), Self::Error> {
-        self.flush().inspect(|_| {
-            match self
-                .property_int_value(ROCKSDB_SIZE_PROPERTY)
-                .map(|opt| opt.map(|size| size / (1024 * 1024)))
-            {
-                Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size.unwrap_or_default() as f64),
-                Err(err) => tracing::warn!(?err, "failed to gauge database size"),
-            }
-        })
+        self.flush()
+            .inspect(|_| GenericDatabase::sample_metrics(self))
     }
 
     // Here we do a batch delete instead of `DB::destroy` to remove all SSTs but preserve everything else.
-    // Since we call `GenericDatabase::batch`, metrics are collected there to avoid duplication.
     fn clear(&self) -> Result<(), Self::Error> {
         self.batch(Batch::<_, Box<[u8]>>::from(
             self.iterator(rocksdb::IteratorMode::Start)
                 .filter_map(|item| item.map(|(key, _)| BatchOp::Delete(key)).ok())
                 .collect::<Vec<BatchOp<_, _>>>(),
         ))
+        .inspect(|_| GenericDatabase::sample_metrics(self))
+    }
+
+    // `prefix_iterator` only respects the prefix with a prefix extractor set,
+    // so we seek to the prefix and stop once keys stop matching it.
+    fn iter_prefix(&self, prefix: &[u8]) -> KvIter<'_, Self::Error> {
+        let prefix = prefix.to_vec();
+        let iter = self.iterator(rocksdb::IteratorMode::From(
+            &prefix,
+            rocksdb::Direction::Forward,
+        ));
+
+        Box::new(
+            iter.take_while(move |item| {
+                item.as_ref()
+                    .map_or(true, |(key, _)| key.starts_with(&prefix))
+            })
+            .map(|item| item.map(|(key, value)| (key.into_vec(), value.into_vec()))),
+        )
+    }
+
+    fn sample_metrics(&self) {
+        match self
+            .property_int_value(ROCKSDB_SIZE_PROPERTY)
+            .map(|opt| opt.map(|size| size / (1024 * 1024)))
+        {
+            Ok(size) => metrics::gauge!(DB_SIZE_MB).set(size.unwrap_or_default() as f64),
+            Err(err) => tracing::warn!(?err, "failed to gauge database size"),
+        }
     }
 }
 
@@ -282,6 +315,8 @@ where
     V: GenericBytes,
 {
     Read(K),
+    /// Read, but only hit if the value was cached for exactly this request.
+    ReadMatching(K, Vec<u8>),
     Write(K, V),
     Batch(Batch<K, V>),
     Flush,
@@ -0,0 +1,303 @@
+//! Groups cache inserts into batches before they hit the disk.
+//!
+//! [`WriteBehind`] wraps any [`GenericDatabase`] and buffers single writes in memory,
+//! handing them to the inner DB as one [`Batch`] once the buffer is full, or on the next
+//! maintenance tick after `interval` has passed. Reads check the buffer first, so a
+//! buffered write is visible right away.
+//!
+//! Batches and deletes aren't buffered. They drain the buffer first so everything
+//! still reaches the DB in the order it was sent.
+
+use crate::database::types::{
+    Batch,
+    GenericBytes,
+    GenericDatabase,
+    KvIter,
+};
+
+use std::{
+    collections::HashMap,
+    sync::{
+        Arc,
+        Mutex,
+        MutexGuard,
+    },
+    time::{
+        Duration,
+        Instant,
+    },
+};
+
+/// When to hand buffered writes to the DB.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WriteBehindSettings {
+    /// Max amount of buffered writes.
+    pub max_entries: usize,
+    /// Max size of buffered keys and values, in bytes.
+    pub max_bytes: usize,
+    /// Max time a write can stay buffered, give or take a maintenance tick.
+    pub interval: Duration,
+}
+
+impl Default for WriteBehindSettings {
+    fn default() -> Self {
+        Self {
+            max_entries: 512,
+            max_bytes: 4 * 1024 * 1024,
+            interval: Duration::from_millis(100),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Buffer {
+    /// Writes we haven't handed to the DB yet.
+    pending: HashMap<Vec<u8>, Vec<u8>>,
+    /// Writes the DB is committing right now. Still checked on reads until they land.
+    flushing: Arc<HashMap<Vec<u8>, Vec<u8>>>,
+    bytes: usize,
+    /// When the oldest pending write was buffered.
+    since: Option<Instant>,
+}
+
+impl Buffer {
+    fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.pending
+            .get(key)
+            .or_else(|| self.flushing.get(key))
+            .cloned()
+    }
+}
+
+/// A [`GenericDatabase`] that commits single writes in groups.
+#[derive(Debug)]
+pub struct WriteBehind<DB> {
+    inner: DB,
+    settings: WriteBehindSettings,
+    buffer: Mutex<Buffer>,
+    /// Last time we ran the inner DB's own maintenance.
+    inner_maintained: Mutex<Instant>,
+}
+
+impl<DB: GenericDatabase> WriteBehind<DB> {
+    pub fn new(inner: DB, settings: WriteBehindSettings) -> Self {
+        tracing::info!(?settings, "Write-behind batching enabled");
+
+        Self {
+            inner,
+            settings,
+            buffer: Mutex::default(),
+            inner_maintained: Mutex::new(Instant::now()),
+        }
+    }
+
+    fn buffer(&self) -> MutexGuard<'_, Buffer> {
+        self.buffer.lock().unwrap_or_else(|e| {
+            // Handle the case where the Mutex is poisoned
+            e.into_inner()
+        })
+    }
+
+    /// Hand every buffered write to the inner DB as a single batch.
+    fn drain(&self) -> Result<(), DB::Error> {
+        let flushing = {
+            let mut buffer = self.buffer();
+            if buffer.pending.is_empty() {
+                return Ok(());
+            }
+
+            let pending = Arc::new(std::mem::take(&mut buffer.pending));
+            buffer.flushing = pending.clone();
+            buffer.bytes = 0;
+            buffer.since = None;
+            pending
+        };
+
+        let mut batch = Batch::<&[u8], &[u8]>::with_capacity(flushing.len());
+        for (key, value) in flushing.iter() {
+            batch.insert(key, value);
+        }
+        let result = self.inner.batch(batch);
+
+        let mut buffer = self.buffer();
+        if result.is_err() {
+            // Put them back so we retry on the next drain. Anything written
+            // since is newer, so it wins.
+            for (key, value) in flushing.iter() {
+                if !buffer.pending.contains_key(key) {
+                    buffer.bytes += key.len() + value.len();
+                    buffer.pending.insert(key.clone(), value.clone());
+                }
+            }
+            buffer.since.get_or_insert_with(Instant::now);
+        }
+        buffer.flushing = Arc::default();
+
+        result
+    }
+}
+
+impl<DB: GenericDatabase> GenericDatabase for WriteBehind<DB> {
+    type Error = DB::Error;
+    type Config = (DB::Config, WriteBehindSettings);
+
+    fn open(config: &Self::Config) -> Result<Self, Self::Error> {
+        let (config, settings) = config;
+        DB::open(config).map(|inner| Self::new(inner, settings.clone()))
+    }
+
+    fn read<K: GenericBytes>(&self, key: K) -> Result<Option<Vec<u8>>, Self::Error> {
+        if let Some(value) = self.buffer().get(key.as_ref()) {
+            return Ok(Some(value));
+        }
+        self.inner.read(key)
+    }
+
+    fn write<K, V>(&self, key: K, val: V) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        let full = {
+            let mut buffer = self.buffer();
+            let key_len = key.as_ref().len();
+            buffer.bytes += key_len + val.as_ref().len();
+            if let Some(old) = buffer.pending.insert(key.into(), val.into()) {
+                buffer.bytes -= key_len + old.len();
+            }
+            buffer.since.get_or_insert_with(Instant::now);
+
+            buffer.pending.len() >= self.settings.max_entries
+                || buffer.bytes >= self.settings.max_bytes
+        };
+
+        if full {
+            self.drain()?;
+        }
+        Ok(())
+    }
+
+    fn batch<K, V>(&self, batch: Batch<K, V>) -> Result<(), Self::Error>
+    where
+        K: GenericBytes,
+        V: GenericBytes,
+    {
+        self.drain()?;
+        self.inner.batch(batch)
+    }
+
+    fn flush(&self) -> Result<(), Self::Error> {
+        self.drain()?;
+        self.inner.flush()
+    }
+
+    fn clear(&self) -> Result<(), Self::Error> {
+        *self.buffer() = Buffer::default();
+        self.inner.clear()
+    }
+
+    fn iter_prefix(&self, prefix: &[u8]) -> KvIter<'_, Self::Error> {
+        // Scans are rare, so make them see everything by committing first
+        if let Err(err) = self.drain() {
+            return Box::new(std::iter::once(Err(err)));
+        }
+        self.inner.iter_prefix(prefix)
+    }
+
+    fn maintenance_interval(&self) -> Option<Duration> {
+        match self.inner.maintenance_interval() {
+            Some(inner) => Some(inner.min(self.settings.interval)),
+            None => Some(self.settings.interval),
+        }
+    }
+
+    fn maintain(&self) -> Result<(), Self::Error> {
+        let due = self
+            .buffer()
+            .since
+            .is_some_and(|since| since.elapsed() >= self.settings.interval);
+        if due {
+            self.drain()?;
+        }
+
+        // We tick at our own pace, so only pass it on when the inner DB is due
+        if let Some(interval) = self.inner.maintenance_interval() {
+            let mut maintained = self
+                .inner_maintained
+                .lock()
+                .unwrap_or_else(|e| e.into_inner());
+            if maintained.elapsed() >= interval {
+                *maintained = Instant::now();
+                drop(maintained);
+                self.inner.maintain()?;
+            }
+        }
+        Ok(())
+    }
+
+    fn sample_metrics(&self) {
+        self.inner.sample_metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::database::{
+        memory::{
+            MemoryConfig,
+            MemoryDb,
+        },
+        test_utils::key,
+    };
+
+    fn write_behind(max_entries: usize) -> WriteBehind<MemoryDb> {
+        WriteBehind::new(
+            MemoryDb::new(&MemoryConfig::default()),
+            WriteBehindSettings {
+                max_entries,
+                interval: Duration::ZERO,
+                ..Default::default()
+            },
+        )
+    }
+
+    #[test]
+    fn test_reads_see_buffered_writes() {
+        let cache = write_behind(16);
+        cache.write(key(1), b"one".to_vec()).unwrap();
+
+        assert!(cache.inner.read(key(1)).unwrap().is_none());
+        assert_eq!(cache.read(key(1)).unwrap(), Some(b"one".to_vec()));
+
+        cache.maintain().unwrap();
+        assert_eq!(cache.inner.read(key(1)).unwrap(), Some(b"one".to_vec()));
+        assert!(cache.buffer().pending.is_empty());
+    }
+
+    #[test]
+    fn test_drains_when_full() {
+        let cache = write_behind(2);
+        cache.write(key(1), b"one".to_vec()).unwrap();
+        assert!(cache.inner.read(key(1)).unwrap().is_none());
+
+        cache.write(key(2), b"two".to_vec()).unwrap();
+        assert!(cache.inner.read(key(1)).unwrap().is_some());
+        assert!(cache.inner.read(key(2)).unwrap().is_some());
+    }
+
+    #[test]
+    fn test_batches_keep_order() {
+        let cache = write_behind(16);
+        cache.write(key(1), b"one".to_vec()).unwrap();
+
+        let mut batch = Batch::<Vec<u8>, Vec<u8>>::with_capacity(1);
+        batch.delete(key(1));
+        cache.batch(batch).unwrap();
+
+        // The buffered write went out before the delete, not after
+        assert!(cache.read(key(1)).unwrap().is_none());
+        cache.flush().unwrap();
+        assert!(cache.read(key(1)).unwrap().is_none());
+    }
+}
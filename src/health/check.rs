@@ -25,11 +25,15 @@ use crate::{
 };
 
 use std::{
+    collections::HashMap,
     sync::{
         Arc,
         RwLock,
     },
-    time::Duration,
+    time::{
+        Duration,
+        Instant,
+    },
 };
 
 use rust_tracing::deps::metrics;
@@ -45,20 +49,169 @@ use tokio::{
     },
 };
 
+/// How far a node's head can stray from the head the others agree on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HeadTolerance {
+    /// Blocks a node can fall behind before it's sent to the poverty list.
+    pub max_lag: u64,
+    /// Blocks a node can be ahead before we consider its head bogus.
+    pub max_lead: u64,
+}
+
+impl Default for HeadTolerance {
+    fn default() -> Self {
+        Self {
+            max_lag: 2,
+            max_lead: 64,
+        }
+    }
+}
+
+impl HeadTolerance {
+    fn is_behind(&self, head: u64, agreed_head: u64) -> bool {
+        head.saturating_add(self.max_lag) < agreed_head
+    }
+
+    /// We don't know where the head is if nobody agrees on one, so nobody is an outlier.
+    fn is_outlier(&self, head: u64, agreed_head: u64) -> bool {
+        agreed_head != 0 && head > agreed_head.saturating_add(self.max_lead)
+    }
+}
+
+/// Judges the heads nodes report, and remembers when they last moved to catch single
+/// nodes or the whole chain getting stuck.
+#[derive(Debug, Default)]
+pub struct HeadTracker {
+    tolerance: HeadTolerance,
+    /// How long a head can stand still before it's stuck. `None` turns the checks off.
+    stale_after: Option<Duration>,
+    /// The agreed head and when it last changed.
+    agreed: Option<(u64, Instant)>,
+    /// Same thing for each node, by URL.
+    nodes: HashMap<url::Url, NodeHead>,
+}
+
+#[derive(Debug)]
+struct NodeHead {
+    head: u64,
+    since: Instant,
+    /// When the agreed head first moved on without it.
+    left_behind: Option<Instant>,
+}
+
+impl HeadTracker {
+    pub fn new(tolerance: HeadTolerance, stale_after: Option<Duration>) -> Self {
+        Self {
+            tolerance,
+            stale_after,
+            ..Default::default()
+        }
+    }
+
+    fn observe_agreed(&mut self, agreed_head: u64, now: Instant) {
+        // Nobody answering is a different problem
+        if agreed_head == 0 {
+            return;
+        }
+
+        match self.agreed {
+            Some((head, _)) if head == agreed_head => {}
+            _ => self.agreed = Some((agreed_head, now)),
+        }
+    }
+
+    /// The agreed head hasn't moved in `stale_after`.
+    fn is_stale(&self, now: Instant) -> bool {
+        match (self.stale_after, self.agreed) {
+            (Some(stale_after), Some((_, since))) => now.duration_since(since) > stale_after,
+            _ => false,
+        }
+    }
+
+    /// Records the head `rpc` reported and returns if it's stuck, meaning the agreed
+    /// head kept moving for `stale_after` while this one didn't and stayed behind it.
+    ///
+    /// When everyone is stuck, the chain is stale and nobody gets singled out. Neither
+    /// does a node ahead of everyone else, the others are catching up to it.
+    fn is_stuck(&mut self, rpc: &Rpc, reported_head: u64, now: Instant) -> bool {
+        let node = self.nodes.entry(rpc.get_url()).or_insert(NodeHead {
+            head: reported_head,
+            since: now,
+            left_behind: None,
+        });
+        if node.head != reported_head {
+            *node = NodeHead {
+                head: reported_head,
+                since: now,
+                left_behind: None,
+            };
+            return false;
+        }
+
+        match (self.stale_after, self.agreed) {
+            (Some(stale_after), Some((agreed_head, agreed_since)))
+                if agreed_since > node.since && node.head < agreed_head =>
+            {
+                let left_behind = *node.left_behind.get_or_insert(agreed_since);
+                now.duration_since(left_behind) > stale_after
+            }
+            _ => {
+                node.left_behind = None;
+                false
+            }
+        }
+    }
+}
+
+/// How often we probe nodes in the poverty list. Each failed probe doubles the wait,
+/// starting at the health check interval, up to `max`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ProbeBackoff {
+    pub base: Duration,
+    pub max: Duration,
+}
+
+impl Default for ProbeBackoff {
+    fn default() -> Self {
+        Self {
+            base: Duration::from_millis(400),
+            max: Duration::from_secs(60),
+        }
+    }
+}
+
+impl ProbeBackoff {
+    /// How long to wait before probing a node that failed `failed_probes` probes in a row.
+    fn delay(&self, failed_probes: u32) -> Duration {
+        let delay = self
+            .base
+            .saturating_mul(1 << failed_probes.min(31))
+            .min(self.max);
+
+        // Jitter so nodes that went down together don't get probed together
+        delay / 2 + delay.mul_f64(rand::random::<f64>() / 2.0)
+    }
+}
+
 #[derive(Debug, Default)]
 struct HeadResult {
     rpc_list_index: usize,
     is_syncing: bool,
     reported_head: u64,
+    /// The node reported a different chain ID than the one we're serving.
+    wrong_chain: bool,
 }
 
 #[derive(Debug)]
 struct InnerResult {
     is_syncing: bool,
     reported_head: u64,
+    wrong_chain: bool,
 }
 
 /// Call check and safe_block in a loop
+///
+/// `chain_id` is the chain we're serving, nodes that switch to another one are refused.
 pub async fn health_check(
     rpc_list: Arc<RwLock<Vec<Rpc>>>,
     poverty_list: Arc<RwLock<Vec<Rpc>>>,
@@ -66,20 +219,27 @@ pub async fn health_check(
     liveness_tx: LiveReadyUpdateSnd,
     named_numbers_rwlock: &Arc<RwLock<NamedBlocknumbers>>,
     config: &Arc<RwLock<Settings>>,
+    chain_id: Option<u64>,
 ) -> Result<(), HealthError> {
+    let mut head_tracker = {
+        let config = config.read().unwrap();
+        HeadTracker::new(config.head_tolerance, config.stale_head_after())
+    };
+
     loop {
         let health_check_ttl = config.read().unwrap().health_check_ttl;
-        let ttl = config.read().unwrap().ttl;
-        let supress_rpc_check = config.read().unwrap().supress_rpc_check;
+        head_tracker.tolerance = config.read().unwrap().head_tolerance;
+        head_tracker.stale_after = config.read().unwrap().stale_head_after();
 
         sleep(Duration::from_millis(health_check_ttl)).await;
 
         check(
             &rpc_list,
             &poverty_list,
-            &ttl,
+            config,
             &liveness_tx,
-            supress_rpc_check,
+            chain_id,
+            &mut head_tracker,
         )
         .await?;
 
@@ -97,29 +257,59 @@ pub async fn health_check(
 async fn check(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
-    ttl: &u128,
+    config: &Arc<RwLock<Settings>>,
     liveness_tx: &LiveReadyUpdateSnd,
-    supress_rpc_check: bool,
+    chain_id: Option<u64>,
+    head_tracker: &mut HeadTracker,
 ) -> Result<(), HealthError> {
+    let (ttl, supress_rpc_check, backoff) = {
+        let config = config.read().unwrap();
+        let backoff = ProbeBackoff {
+            base: Duration::from_millis(config.health_check_ttl),
+            max: Duration::from_millis(config.max_probe_interval),
+        };
+        (config.ttl, config.supress_rpc_check, backoff)
+    };
+
     if !supress_rpc_check {
         tracing::info!("Checking RPC health... ");
     }
     // Head blocks reported by each RPC, we also use it to mark delinquents
     //
     // If a head is marked at `0` that means that the rpc is delinquent
-    let heads = head_check(rpc_list, *ttl).await?;
+    let heads = head_check(rpc_list, ttl, chain_id).await?;
 
     // Remove RPCs that are falling behind
-    let agreed_head = make_poverty(rpc_list, poverty_list, heads)?;
+    let agreed_head = make_poverty(rpc_list, poverty_list, heads, head_tracker)?;
     metrics::gauge!("rpc_head_height").set(agreed_head as f64);
 
     // Check if any rpc nodes made it out
     // Its ok if we call them twice because some might have been accidentally put here
 
-    // Do a head check over the current poverty list to see if any nodes are back to normal
-    let poverty_heads = head_check(poverty_list, *ttl).await?;
-
-    let to_send = escape_poverty(rpc_list, poverty_list, poverty_heads, agreed_head)?;
+    // Do a head check over the current poverty list to see if any nodes are back to normal.
+    // Nodes that are backing off get skipped.
+    let poverty_heads = head_check(poverty_list, ttl, chain_id).await?;
+
+    let to_send = escape_poverty(
+        rpc_list,
+        poverty_list,
+        poverty_heads,
+        agreed_head,
+        head_tracker,
+        backoff,
+    )?;
+
+    // Every node stopping at once looks healthy from the outside, so we have to say so
+    let is_stale = head_tracker.is_stale(Instant::now());
+    metrics::gauge!("rpc_head_stale").set(if is_stale { 1.0 } else { 0.0 });
+    let to_send = if is_stale {
+        tracing::error!(
+            "The head is stuck at {agreed_head}! Every RPC stopped following the chain."
+        );
+        LiveReadyUpdate::Health(HealthState::Stale)
+    } else {
+        to_send
+    };
 
     // Send the current status of nodes to the liveness monitor
     let _ = liveness_tx.send(to_send).await;
@@ -131,10 +321,11 @@ async fn check(
     Ok(())
 }
 
-/// Check what heads are reported by each RPC
+/// Check what heads are reported by each RPC, skipping the ones backing off
 async fn head_check(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     ttl: u128,
+    chain_id: Option<u64>,
 ) -> Result<Vec<HeadResult>, HealthError> {
     let len;
     let rpc_list_clone;
@@ -144,8 +335,14 @@ async fn head_check(
             e.into_inner()
         });
 
-        len = rpc_list_guard.len();
-        rpc_list_clone = rpc_list_guard.clone();
+        let now = Instant::now();
+        rpc_list_clone = rpc_list_guard
+            .iter()
+            .cloned()
+            .enumerate()
+            .filter(|(_, rpc)| rpc.status.next_probe.map_or(true, |next| next <= now))
+            .collect::<Vec<_>>();
+        len = rpc_list_clone.len();
     }
     let mut heads = Vec::<HeadResult>::new();
 
@@ -161,7 +358,7 @@ async fn head_check(
     let (tx, mut rx) = mpsc::channel(len);
 
     // Iterate over all RPCs
-    for (rpc_list_index, rpc) in rpc_list_clone.into_iter().enumerate().take(len) {
+    for (rpc_list_index, rpc) in rpc_list_clone {
         let tx = tx.clone(); // Clone the sender for this RPC
 
         // Spawn a future for each RPC
@@ -172,10 +369,16 @@ async fn head_check(
             let a = async move {
                 let block_number = rpc.block_number().await.unwrap_or(0);
                 let syncing = rpc.syncing().await.unwrap_or(true);
+                // A node that fails to answer is already caught by the checks above
+                let wrong_chain = match chain_id {
+                    Some(chain_id) => rpc.chain_id().await.is_ok_and(|id| id != chain_id),
+                    None => false,
+                };
 
                 let rax = InnerResult {
                     is_syncing: syncing,
                     reported_head: block_number,
+                    wrong_chain,
                 };
 
                 let _ = send_tx.send(rax);
@@ -191,6 +394,7 @@ async fn head_check(
                     InnerResult {
                         is_syncing: true,
                         reported_head: 0,
+                        wrong_chain: false,
                     }
                 }
             };
@@ -199,6 +403,7 @@ async fn head_check(
                 rpc_list_index,
                 is_syncing: result.is_syncing,
                 reported_head: result.reported_head,
+                wrong_chain: result.wrong_chain,
             };
 
             // Send the result to the main thread through the channel
@@ -225,30 +430,91 @@ async fn head_check(
     Ok(heads)
 }
 
+/// Returns the head most nodes agree on, the median of the heads reported by nodes
+/// that are synced and on our chain. The higher one if there's an even amount of them.
+///
+/// Unlike the highest head, a single node reporting a bogus head can't move it. Once we
+/// agreed on a head, heads too far ahead of it don't count either, so a bogus head can't
+/// win against a single other node. Unless every head is, then the chain moved on while
+/// we weren't looking.
+fn agreed_head(heads: &[HeadResult], head_tracker: &HeadTracker) -> u64 {
+    let mut reported = heads
+        .iter()
+        .filter(|head| !head.is_syncing && !head.wrong_chain && head.reported_head != 0)
+        .map(|head| head.reported_head)
+        .collect::<Vec<_>>();
+    if let Some((agreed, _)) = head_tracker.agreed {
+        let plausible = reported
+            .iter()
+            .copied()
+            .filter(|&head| !head_tracker.tolerance.is_outlier(head, agreed))
+            .collect::<Vec<_>>();
+        if !plausible.is_empty() {
+            reported = plausible;
+        }
+    }
+    if reported.is_empty() {
+        return 0;
+    }
+
+    reported.sort_unstable();
+    reported[reported.len() / 2]
+}
+
 /// Add unresponsive/erroring RPCs to the poverty list
 fn make_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     heads: Vec<HeadResult>,
+    head_tracker: &mut HeadTracker,
 ) -> Result<u64, HealthError> {
-    // Get the highest head reported by the RPCs
-    let mut highest_head = 0;
-    for head in &heads {
-        if head.reported_head > highest_head {
-            highest_head = head.reported_head;
-        }
-    }
+    let agreed_head = agreed_head(&heads, head_tracker);
+    let now = Instant::now();
+    head_tracker.observe_agreed(agreed_head, now);
 
-    // Mark all RPCs that dont report the highest head as erroring
+    // Mark all RPCs that stray too far from the agreed head as erroring
     let mut rpc_list_guard = rpc_list.write().unwrap();
     let mut poverty_list_guard = poverty_list.write().unwrap();
 
     for head in heads {
-        if head.reported_head < highest_head || head.is_syncing {
+        // Lets us route requests for a block to nodes that have it
+        rpc_list_guard[head.rpc_list_index].status.head = head.reported_head;
+
+        let is_behind = head_tracker
+            .tolerance
+            .is_behind(head.reported_head, agreed_head);
+        let is_outlier = head_tracker
+            .tolerance
+            .is_outlier(head.reported_head, agreed_head);
+        let is_stuck = head_tracker.is_stuck(
+            &rpc_list_guard[head.rpc_list_index],
+            head.reported_head,
+            now,
+        );
+
+        if is_behind || is_outlier || is_stuck || head.is_syncing || head.wrong_chain {
             // Mark the RPC as erroring
             rpc_list_guard[head.rpc_list_index].status.is_erroring = true;
             let rpc_name = &rpc_list_guard[head.rpc_list_index].name;
-            tracing::warn!("{rpc_name} is falling behind! Removing from active RPC pool.");
+            if head.wrong_chain {
+                tracing::error!(
+                    "{rpc_name} switched to a different chain! Removing from active RPC pool."
+                );
+            } else if is_outlier {
+                tracing::warn!(
+                    reported_head = head.reported_head,
+                    agreed_head,
+                    "{rpc_name} reports a head far ahead of the other nodes! Removing from active RPC pool."
+                );
+                metrics::counter!("rpc_head_outliers", "rpc_name" => rpc_name.to_owned())
+                    .increment(1);
+            } else if is_stuck {
+                tracing::warn!(
+                    "{rpc_name} stopped following the head! Removing from active RPC pool."
+                );
+            } else {
+                tracing::warn!("{rpc_name} is falling behind! Removing from active RPC pool.");
+            }
             metrics::gauge!(
                 "rpc_health_by_name",
                 "rpc_name" => rpc_name.to_owned(),
@@ -265,17 +531,20 @@ fn make_poverty(
     // Go over rpc_list_guard and remove all erroring rpcs
     rpc_list_guard.retain(|rpc| !rpc.status.is_erroring);
 
-    Ok(highest_head)
+    Ok(agreed_head)
 }
 
 /// Go over the `poverty_list` to see if any nodes are back to normal.
 ///
-/// Update liveness statuses when done.
+/// Nodes escape once they follow the `agreed_head` within tolerance again, the same rules
+/// that put them there. Update liveness statuses when done.
 fn escape_poverty(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_list: &Arc<RwLock<Vec<Rpc>>>,
     poverty_heads: Vec<HeadResult>,
     agreed_head: u64,
+    head_tracker: &mut HeadTracker,
+    backoff: ProbeBackoff,
 ) -> Result<crate::LiveReadyUpdate, HealthError> {
     // Check if any nodes made it 🗣️🔥🔥🔥
     let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
@@ -287,10 +556,29 @@ fn escape_poverty(
         e.into_inner()
     });
 
+    let now = Instant::now();
     for head in poverty_heads {
-        if head.reported_head >= agreed_head && !head.is_syncing {
+        let is_stuck = head_tracker.is_stuck(
+            &poverty_list_guard[head.rpc_list_index],
+            head.reported_head,
+            now,
+        );
+
+        if !is_stuck
+            && !head_tracker
+                .tolerance
+                .is_behind(head.reported_head, agreed_head)
+            && !head_tracker
+                .tolerance
+                .is_outlier(head.reported_head, agreed_head)
+            && !head.is_syncing
+            && !head.wrong_chain
+        {
             let mut rpc = poverty_list_guard[head.rpc_list_index].clone();
             rpc.status.is_erroring = false;
+            rpc.status.failed_probes = 0;
+            rpc.status.next_probe = None;
+            rpc.status.head = head.reported_head;
             let rpc_name = &rpc.name;
             tracing::info!("{rpc_name} is following the head again! Added to active RPC pool.");
             metrics::gauge!(
@@ -306,6 +594,17 @@ fn escape_poverty(
 
             // Remove the RPC from the poverty list
             poverty_list_guard[head.rpc_list_index].status.is_erroring = false;
+        } else {
+            // Back off so we don't spam nodes that have been down for a while
+            let rpc = &mut poverty_list_guard[head.rpc_list_index];
+            let delay = backoff.delay(rpc.status.failed_probes);
+            rpc.status.failed_probes = rpc.status.failed_probes.saturating_add(1);
+            rpc.status.next_probe = Some(now + delay);
+            tracing::debug!(
+                failed_probes = rpc.status.failed_probes,
+                "{} is still unhealthy, probing again in {delay:?}",
+                rpc.name
+            );
         }
     }
 
@@ -412,16 +711,19 @@ mod tests {
                 rpc_list_index: 0,
                 is_syncing: false,
                 reported_head: 18177557,
+                ..Default::default()
             },
             HeadResult {
                 rpc_list_index: 1,
                 is_syncing: false,
                 reported_head: 18193012,
+                ..Default::default()
             },
             HeadResult {
                 rpc_list_index: 2,
                 is_syncing: false,
                 reported_head: 0,
+                ..Default::default()
             },
         ]
     }
@@ -440,15 +742,16 @@ mod tests {
         let heads = dummy_head_check();
 
         // Call the make_poverty function
-        let result = make_poverty(&rpc_list, &poverty_list, heads);
+        let result = make_poverty(&rpc_list, &poverty_list, heads, &mut HeadTracker::default());
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
         let rpc_list_guard = rpc_list.read().unwrap();
         let poverty_list_guard = poverty_list.read().unwrap();
 
-        // Only 1 RPC should be in the rpc list
+        // Only 1 RPC should be in the rpc list, and it knows its head
         assert_eq!(rpc_list_guard.len(), 1);
+        assert_eq!(rpc_list_guard[0].status.head, 18193012);
 
         // The poverty list should now contain 2 RPCs
         assert_eq!(poverty_list_guard.len(), 2);
@@ -473,16 +776,25 @@ mod tests {
                 rpc_list_index: 0,
                 is_syncing: false,
                 reported_head: 18177557,
+                ..Default::default()
             },
             HeadResult {
                 rpc_list_index: 1,
                 is_syncing: false,
                 reported_head: 18193012,
+                ..Default::default()
             },
         ];
 
         // Call the escape_poverty function
-        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012);
+        let result = escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads,
+            18193012,
+            &mut HeadTracker::default(),
+            ProbeBackoff::default(),
+        );
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -514,16 +826,25 @@ mod tests {
                 rpc_list_index: 0,
                 is_syncing: false,
                 reported_head: 18193012,
+                ..Default::default()
             },
             HeadResult {
                 rpc_list_index: 1,
                 is_syncing: true,
                 reported_head: 18193012,
+                ..Default::default()
             },
         ];
 
         // Call the escape_poverty function
-        let result = escape_poverty(&rpc_list, &poverty_list, heads, 18193012);
+        let result = escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads,
+            18193012,
+            &mut HeadTracker::default(),
+            ProbeBackoff::default(),
+        );
         assert!(result.is_ok());
 
         // Check the state of RPCs after the test
@@ -535,4 +856,218 @@ mod tests {
         // The poverty list should have 1 RPC
         assert_eq!(poverty_list_guard.len(), 1);
     }
+
+    #[test]
+    fn test_wrong_chain_is_poverty() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(), Rpc::default()]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+
+        // The node on another chain is ahead, but it can't set the head for us
+        let heads = vec![
+            HeadResult {
+                rpc_list_index: 0,
+                reported_head: 18193012,
+                ..Default::default()
+            },
+            HeadResult {
+                rpc_list_index: 1,
+                reported_head: 99999999,
+                wrong_chain: true,
+                ..Default::default()
+            },
+        ];
+
+        let agreed_head =
+            make_poverty(&rpc_list, &poverty_list, heads, &mut HeadTracker::default()).unwrap();
+        assert_eq!(agreed_head, 18193012);
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+
+        // It stays there even once it follows the head
+        let heads = vec![HeadResult {
+            rpc_list_index: 0,
+            reported_head: 18193012,
+            wrong_chain: true,
+            ..Default::default()
+        }];
+        escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads,
+            18193012,
+            &mut HeadTracker::default(),
+            ProbeBackoff::default(),
+        )
+        .unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 1);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    fn heads(reported: &[u64]) -> Vec<HeadResult> {
+        reported
+            .iter()
+            .enumerate()
+            .map(|(rpc_list_index, &reported_head)| {
+                HeadResult {
+                    rpc_list_index,
+                    reported_head,
+                    ..Default::default()
+                }
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_agreed_head() {
+        let tracker = HeadTracker::default();
+        assert_eq!(agreed_head(&heads(&[]), &tracker), 0);
+        assert_eq!(agreed_head(&heads(&[100]), &tracker), 100);
+        assert_eq!(agreed_head(&heads(&[100, 101]), &tracker), 101);
+        // One bogus head doesn't move it
+        assert_eq!(agreed_head(&heads(&[100, 101, 99999999]), &tracker), 101);
+        // Neither do dead nodes
+        assert_eq!(agreed_head(&heads(&[0, 0, 101]), &tracker), 101);
+
+        // Not even with only one other node, once we know where the head is
+        let mut tracker = HeadTracker::default();
+        tracker.observe_agreed(99, Instant::now());
+        assert_eq!(agreed_head(&heads(&[100, 99999999]), &tracker), 100);
+        // Unless that's where everyone went
+        assert_eq!(
+            agreed_head(&heads(&[99999998, 99999999]), &tracker),
+            99999999
+        );
+    }
+
+    #[test]
+    fn test_lag_tolerance_and_outliers() {
+        let rpc_list = Arc::new(RwLock::new(vec![Rpc::default(); 5]));
+        let poverty_list = Arc::new(RwLock::new(vec![]));
+        let tolerance = HeadTolerance {
+            max_lag: 2,
+            max_lead: 64,
+        };
+
+        // 1 block behind is fine, 3 isn't, and the bogus head doesn't demote anyone else
+        let agreed = make_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[100, 99, 100, 97, 99999999]),
+            &mut HeadTracker::new(tolerance, None),
+        )
+        .unwrap();
+        assert_eq!(agreed, 100);
+        assert_eq!(rpc_list.read().unwrap().len(), 3);
+        assert_eq!(poverty_list.read().unwrap().len(), 2);
+
+        // Same rules on the way out
+        let poverty_heads = heads(&[98, 99999999]);
+        escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            poverty_heads,
+            100,
+            &mut HeadTracker::new(tolerance, None),
+            ProbeBackoff::default(),
+        )
+        .unwrap();
+        assert_eq!(rpc_list.read().unwrap().len(), 4);
+        assert_eq!(poverty_list.read().unwrap().len(), 1);
+    }
+
+    #[test]
+    fn test_head_tracker() {
+        let mut tracker = HeadTracker::new(HeadTolerance::default(), Some(Duration::from_secs(10)));
+        let rpc = Rpc::default();
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+
+        tracker.observe_agreed(100, at(0));
+        assert!(!tracker.is_stuck(&rpc, 100, at(0)));
+
+        // Everyone stands still, that's the whole chain being stale and not the node
+        assert!(!tracker.is_stale(at(5)));
+        assert!(!tracker.is_stuck(&rpc, 100, at(11)));
+        assert!(tracker.is_stale(at(11)));
+
+        // The others move on without it
+        tracker.observe_agreed(101, at(12));
+        assert!(!tracker.is_stale(at(12)));
+        assert!(!tracker.is_stuck(&rpc, 100, at(13)));
+        tracker.observe_agreed(102, at(20));
+        assert!(!tracker.is_stuck(&rpc, 100, at(20)));
+        assert!(tracker.is_stuck(&rpc, 100, at(23)));
+
+        // Until it moves again
+        assert!(!tracker.is_stuck(&rpc, 101, at(24)));
+        assert!(!tracker.is_stuck(&rpc, 101, at(30)));
+
+        // A node ahead of the others isn't stuck while they catch up to it, or stall behind it
+        let leader = Rpc::new("http://leader".parse().unwrap(), None, 0, 0, 0.0);
+        assert!(!tracker.is_stuck(&leader, 105, at(30)));
+        tracker.observe_agreed(103, at(32));
+        tracker.observe_agreed(104, at(40));
+        assert!(!tracker.is_stuck(&leader, 105, at(55)));
+        // Until they pass it
+        tracker.observe_agreed(106, at(56));
+        assert!(!tracker.is_stuck(&leader, 105, at(60)));
+        assert!(tracker.is_stuck(&leader, 105, at(67)));
+
+        // And nothing is ever stuck with the checks off
+        let mut tracker = HeadTracker::new(HeadTolerance::default(), None);
+        tracker.observe_agreed(100, at(0));
+        tracker.is_stuck(&rpc, 100, at(0));
+        tracker.observe_agreed(101, at(100));
+        assert!(!tracker.is_stale(at(1000)));
+        assert!(!tracker.is_stuck(&rpc, 100, at(1000)));
+    }
+
+    #[test]
+    fn test_probe_backoff() {
+        let backoff = ProbeBackoff {
+            base: Duration::from_millis(400),
+            max: Duration::from_secs(60),
+        };
+
+        for _ in 0..100 {
+            let delay = backoff.delay(0);
+            assert!(delay >= Duration::from_millis(200) && delay <= Duration::from_millis(400));
+            let delay = backoff.delay(3);
+            assert!(delay >= Duration::from_millis(1600) && delay <= Duration::from_millis(3200));
+            let delay = backoff.delay(u32::MAX);
+            assert!(delay >= Duration::from_secs(30) && delay <= Duration::from_secs(60));
+        }
+    }
+
+    #[test]
+    fn test_escape_resets_backoff() {
+        let mut down = Rpc::default();
+        down.status.is_erroring = true;
+        down.status.failed_probes = 2;
+        let mut back = down.clone();
+        back.status.next_probe = Some(Instant::now());
+
+        let rpc_list = Arc::new(RwLock::new(vec![]));
+        let poverty_list = Arc::new(RwLock::new(vec![down, back]));
+
+        escape_poverty(
+            &rpc_list,
+            &poverty_list,
+            heads(&[0, 100]),
+            100,
+            &mut HeadTracker::default(),
+            ProbeBackoff::default(),
+        )
+        .unwrap();
+
+        // The node that's still down waits longer for its next probe
+        let poverty_list = poverty_list.read().unwrap();
+        assert_eq!(poverty_list[0].status.failed_probes, 3);
+        assert!(poverty_list[0].status.next_probe.unwrap() > Instant::now());
+
+        // The one that made it gets probed like any other
+        let rpc_list = rpc_list.read().unwrap();
+        assert_eq!(rpc_list[0].status.failed_probes, 0);
+        assert_eq!(rpc_list[0].status.next_probe, None);
+    }
 }
@@ -1,27 +1,190 @@
-use crate::database::{
-    accept::db_batch,
-    error::DbError,
-    types::{
-        Batch,
-        GenericBytes,
-        RequestBus,
+use crate::{
+    database::{
+        accept::db_batch,
+        error::DbError,
+        hot_cache::HotCache,
+        types::{
+            Batch,
+            GenericBytes,
+            GenericDatabase,
+            RequestBus,
+        },
     },
+    rpc::types::hex_to_decimal,
 };
 
 use std::{
-    collections::BTreeMap,
+    collections::{
+        BTreeMap,
+        VecDeque,
+    },
     sync::{
         Arc,
         RwLock,
     },
 };
 
+use serde_json::Value;
 use tokio_stream::{
     wrappers::WatchStream,
     StreamExt,
 };
 
-/// Check if we need to do a reorg or if a new block has finalized.
+/// Reserved key prefix for persisting head cache membership in the DB.
+///
+/// Every member gets its own 32 byte key, so adding one never rewrites the rest:
+/// the prefix, the big endian height, and 8 bytes of a hash of the member.
+/// The value is the member itself.
+pub const HEAD_CACHE_PREFIX: &[u8; 16] = b"blutgang_headc\0\0";
+
+/// Returns the DB key we persist `member` of the head cache at `height` under.
+pub fn head_cache_key(height: u64, member: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    key[..16].copy_from_slice(HEAD_CACHE_PREFIX);
+    key[16..24].copy_from_slice(&height.to_be_bytes());
+    key[24..].copy_from_slice(&blake3::hash(member).as_bytes()[..8]);
+    key
+}
+
+/// Reload the head cache we persisted in the DB.
+///
+/// Heights at or below `finalized` can't reorg anymore, so we drop their
+/// membership instead of loading it. If `finalized` is 0 we don't know
+/// where finality is, so we keep everything around.
+pub fn load_head_cache<K, DB>(cache: &DB, finalized: u64) -> BTreeMap<u64, Vec<K>>
+where
+    K: for<'a> TryFrom<&'a [u8]>,
+    DB: GenericDatabase,
+{
+    let mut head_cache = BTreeMap::<u64, Vec<K>>::new();
+    let mut stale = Batch::<Vec<u8>, Vec<u8>>::with_capacity(0);
+
+    for item in cache.iter_prefix(HEAD_CACHE_PREFIX) {
+        let (key, member) = match item {
+            Ok(item) => item,
+            Err(err) => {
+                tracing::error!(?err, "Failed to read persisted head cache");
+                break;
+            }
+        };
+
+        let height = match key.get(16..24).map(TryInto::try_into) {
+            Some(Ok(height)) => u64::from_be_bytes(height),
+            _ => continue,
+        };
+
+        if finalized != 0 && height <= finalized {
+            stale.delete(key);
+            continue;
+        }
+
+        match K::try_from(member.as_slice()).ok() {
+            Some(member) => head_cache.entry(height).or_default().push(member),
+            None => {
+                tracing::warn!(height, "Corrupt head cache entry, dropping it");
+                stale.delete(key);
+            }
+        }
+    }
+
+    if let Err(err) = cache.batch(stale) {
+        tracing::error!(?err, "Failed to remove finalized head cache entries");
+    }
+
+    tracing::info!(heights = head_cache.len(), finalized, "Reloaded head cache");
+
+    head_cache
+}
+
+/// How many heads we remember for reorg detection.
+pub const HEAD_RING_LEN: usize = 64;
+
+/// The parts of a block header we need to detect reorgs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockHead {
+    pub number: u64,
+    pub hash: String,
+    pub parent_hash: String,
+}
+
+impl BlockHead {
+    /// Parse a `newHeads` notification result or a block object.
+    pub fn from_header(header: &Value) -> Option<Self> {
+        Some(BlockHead {
+            number: hex_to_decimal(header["number"].as_str()?).ok()?,
+            hash: header["hash"].as_str()?.to_string(),
+            parent_hash: header["parentHash"].as_str()?.to_string(),
+        })
+    }
+}
+
+/// What a new head means for what we've seen so far.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeadUpdate {
+    /// We've already seen this exact head.
+    Duplicate,
+    /// The head builds on top of what we've seen.
+    Extends,
+    /// Every height starting from `from` was replaced.
+    Reorg { from: u64 },
+}
+
+/// Short history of (number, hash, parentHash) of the heads we received.
+#[derive(Debug, Default)]
+pub struct HeadRing {
+    heads: VecDeque<BlockHead>,
+}
+
+impl HeadRing {
+    fn get(&self, number: u64) -> Option<&BlockHead> {
+        self.heads.iter().find(|head| head.number == number)
+    }
+
+    /// Returns the parent hash of `head` if it doesn't match the block we have at its parent height.
+    ///
+    /// Fetching and pushing the parent before `head` lets us find exactly where the chains forked.
+    pub fn missing_parent(&self, head: &BlockHead) -> Option<String> {
+        let parent = self.get(head.number.checked_sub(1)?)?;
+        (parent.hash != head.parent_hash).then(|| head.parent_hash.clone())
+    }
+
+    /// Record a new head, returning whether it caused a reorg.
+    pub fn push(&mut self, head: BlockHead) -> HeadUpdate {
+        if self
+            .get(head.number)
+            .is_some_and(|seen| seen.hash == head.hash)
+        {
+            return HeadUpdate::Duplicate;
+        }
+
+        let update = if self.missing_parent(&head).is_some() {
+            // We couldn't find where the chains forked. All we know is that our block
+            // at the parent height isn't on the new chain, so that's where we start.
+            HeadUpdate::Reorg {
+                from: head.number - 1,
+            }
+        } else if self.heads.iter().any(|seen| seen.number >= head.number) {
+            HeadUpdate::Reorg { from: head.number }
+        } else {
+            HeadUpdate::Extends
+        };
+
+        if let HeadUpdate::Reorg { from } = update {
+            self.heads.retain(|seen| seen.number < from);
+        }
+
+        self.heads.push_back(head);
+        while self.heads.len() > HEAD_RING_LEN {
+            self.heads.pop_front();
+        }
+
+        update
+    }
+}
+
+/// Remove stale entries from the cache once a new block finalizes.
+///
+/// Reorgs are detected and handled by the newHeads subscription, see `HeadRing`.
 pub async fn manage_cache<K, V>(
     head_cache: &Arc<RwLock<BTreeMap<u64, Vec<K>>>>,
     blocknum_rx: tokio::sync::watch::Receiver<u64>,
@@ -29,35 +192,31 @@ pub async fn manage_cache<K, V>(
     cache: RequestBus<K, V>,
 ) -> Result<(), DbError>
 where
-    K: GenericBytes,
+    K: GenericBytes + From<[u8; 32]>,
     V: GenericBytes,
 {
-    let mut block_number = 0;
     let mut last_finalized = 0;
 
     let mut blocknum_stream = WatchStream::new(blocknum_rx.clone());
 
     // Loop for waiting on new values from the finalized_rx channel
     while blocknum_stream.next().await.is_some() {
-        let new_block = *blocknum_rx.borrow();
-
-        // If a new block is less or equal to the last block in our cache,
-        // that means that the chain has experienced a reorg and that we should
-        // remove everything from the last block to the `new_block`
-        if new_block <= block_number {
-            tracing::warn!("Reorg detected! Removing stale entries from the cache.");
-            handle_reorg(head_cache, block_number, new_block, cache.clone()).await?;
-        }
-
         // Check if finalized_stream has changed
         if last_finalized != *finalized_rx.borrow() {
             last_finalized = *finalized_rx.borrow();
             tracing::info!("New finalized block! Removing stale entries from the cache.");
-            // Remove stale entries from the head_cache
-            remove_stale(head_cache, last_finalized)?;
+            // Remove stale entries from the head_cache, and their persisted membership
+            let stale = remove_stale(head_cache, last_finalized)?;
+            if !stale.is_empty() {
+                let mut batch = Batch::with_capacity(0);
+                for (height, keys) in stale {
+                    for key in keys {
+                        batch.delete(head_cache_key(height, key.as_ref()).into());
+                    }
+                }
+                drop(db_batch(&cache, batch).await);
+            }
         }
-
-        block_number = new_block;
     }
     Ok(())
 }
@@ -65,61 +224,61 @@ where
 /// We use the head_cache to store keys of querries we made near the tip
 /// If a reorg happens, we need to remove all queries in the reorg range
 /// from the sled database.
-async fn handle_reorg<K, V>(
+pub async fn handle_reorg<K, V>(
     head_cache: &Arc<RwLock<BTreeMap<u64, Vec<K>>>>,
-    block_number: u64,
-    new_block: u64,
+    from: u64,
+    to: u64,
+    hot_cache: &HotCache,
     cache: RequestBus<K, V>,
 ) -> Result<(), DbError>
 where
-    K: GenericBytes,
+    K: GenericBytes + From<[u8; 32]>,
     V: GenericBytes,
 {
-    let range = block_number..=new_block;
-    let mut batch = Batch::with_capacity(range.clone().count());
-
-    // Go over the head cache and get all the keys from block_number to new_block
+    // Go over the head cache and get all the keys from `from` to `to`
+    let mut batch = Batch::with_capacity(0);
+    let mut removed = Vec::new();
     {
-        let mut head_cache_guard = head_cache.write().unwrap();
-        for i in range {
-            if let Some(keys) = head_cache_guard.get(&i).cloned() {
+        let mut head_cache_guard = head_cache.write().unwrap_or_else(|e| e.into_inner());
+        let heights: Vec<u64> = head_cache_guard
+            .range(from..=to)
+            .map(|(height, _)| *height)
+            .collect();
+
+        for height in heights {
+            if let Some(keys) = head_cache_guard.remove(&height) {
                 for key in keys {
-                    batch.delete(key);
+                    batch.delete(head_cache_key(height, key.as_ref()).into());
+                    batch.delete(key.clone());
+                    removed.push(key);
                 }
-                // Remove the entry from the head_cache
-                head_cache_guard.remove(&i);
             }
         }
     }
 
-    // Send the batch to the cache
-    drop(db_batch(&cache, batch).await);
+    // Only evict from memory once they're gone from the DB, or a miss could
+    // read the orphaned response and promote it right back
+    db_batch(&cache, batch).await.await?;
+    hot_cache.invalidate(removed.iter().map(|key| key.as_ref()));
 
     Ok(())
 }
 
-/// Removes stale entries from `head_cache`
+/// Removes stale entries from `head_cache`, returning the ones removed.
 ///
 /// Once a new block finalizes, we can be sure that certain TXs wont
 /// reorg, so theyre safe to be permanantly in the cache.
 fn remove_stale<K: GenericBytes>(
     head_cache: &Arc<RwLock<BTreeMap<u64, Vec<K>>>>,
     block_number: u64,
-) -> Result<(), DbError> {
-    // Get the lowest block_number from the BTreeMap
+) -> Result<BTreeMap<u64, Vec<K>>, DbError> {
     let mut head_cache_guard = head_cache.write().unwrap();
 
-    let oldest = match head_cache_guard.iter().next() {
-        Some((oldest, _)) => *oldest,
-        None => return Ok(()), // Return early if the map is empty
-    };
-
     // Remove all entries from the head_cache up to block_number
-    for i in oldest..=block_number + 1 {
-        head_cache_guard.remove(&i);
-    }
+    let fresh = head_cache_guard.split_off(&(block_number + 2));
+    let stale = std::mem::replace(&mut *head_cache_guard, fresh);
 
-    Ok(())
+    Ok(stale)
 }
 
 #[cfg(test)]
@@ -149,16 +308,16 @@ mod tests {
         // Add some data to the head_cache
         {
             let mut head_cache_guard = head_cache.write().unwrap();
-            head_cache_guard.insert(1, vec!["key1".as_bytes()]);
-            head_cache_guard.insert(2, vec!["key2".as_bytes()]);
-            head_cache_guard.insert(3, vec!["key3".as_bytes()]);
+            head_cache_guard.insert(1, vec!["key1".as_bytes().to_vec()]);
+            head_cache_guard.insert(2, vec!["key2".as_bytes().to_vec()]);
+            head_cache_guard.insert(3, vec!["key3".as_bytes().to_vec()]);
         }
 
-        let (db_tx, db_rx) = mpsc::unbounded_channel::<DbRequest<&[u8], &[u8]>>();
-        tokio::task::spawn(database_processing(db_rx, cache));
+        let (db_tx, db_rx) = mpsc::channel::<DbRequest<Vec<u8>, Vec<u8>>>(64);
+        tokio::task::spawn(database_processing(db_rx, cache, 4));
 
         // Call handle_reorg
-        let result = handle_reorg(&head_cache, 2, 3, db_tx.clone()).await;
+        let result = handle_reorg(&head_cache, 2, 3, &HotCache::new(0), db_tx.clone()).await;
 
         // Verify the result and check if the data is removed from the cache
         assert!(result.is_ok(), "handle_reorg failed");
@@ -177,20 +336,168 @@ mod tests {
         );
 
         // Check if the data is removed from the cache
-        let key1 = db_get!(db_tx.clone(), "key1".as_bytes()).unwrap();
+        let key1 = db_get!(db_tx.clone(), "key1".as_bytes().to_vec()).unwrap();
         assert!(key1.is_some(), "failed to get key1 from db");
-        let key2 = db_get!(db_tx.clone(), "key2".as_bytes()).unwrap();
+        let key2 = db_get!(db_tx.clone(), "key2".as_bytes().to_vec()).unwrap();
         assert!(
             key2.is_none(),
             "successfully got key2 from db which should have failed"
         );
-        let key3 = db_get!(db_tx.clone(), "key3".as_bytes()).unwrap();
+        let key3 = db_get!(db_tx.clone(), "key3".as_bytes().to_vec()).unwrap();
         assert!(
             key3.is_none(),
             "successfully got key3 from db which should have failed"
         );
     }
 
+    fn block_head(number: u64, hash: &str, parent_hash: &str) -> BlockHead {
+        BlockHead {
+            number,
+            hash: hash.to_string(),
+            parent_hash: parent_hash.to_string(),
+        }
+    }
+
+    #[test]
+    fn test_block_head_from_header() {
+        let header = serde_json::json!({
+            "number": "0x10",
+            "hash": "0xb",
+            "parentHash": "0xa",
+            "miner": "0x0",
+        });
+        assert_eq!(
+            BlockHead::from_header(&header),
+            Some(block_head(16, "0xb", "0xa"))
+        );
+        assert_eq!(
+            BlockHead::from_header(&serde_json::json!({"number": "0x10"})),
+            None
+        );
+    }
+
+    #[test]
+    fn test_head_ring_extends() {
+        let mut ring = HeadRing::default();
+        assert_eq!(ring.push(block_head(1, "a1", "a0")), HeadUpdate::Extends);
+        assert_eq!(ring.push(block_head(2, "a2", "a1")), HeadUpdate::Extends);
+        assert_eq!(ring.push(block_head(2, "a2", "a1")), HeadUpdate::Duplicate);
+        // Gaps are fine, we just don't know the parent
+        assert_eq!(ring.push(block_head(4, "a4", "a3")), HeadUpdate::Extends);
+    }
+
+    #[test]
+    fn test_head_ring_same_height_reorg() {
+        let mut ring = HeadRing::default();
+        ring.push(block_head(1, "a1", "a0"));
+        ring.push(block_head(2, "a2", "a1"));
+
+        assert_eq!(
+            ring.push(block_head(2, "b2", "a1")),
+            HeadUpdate::Reorg { from: 2 }
+        );
+        assert_eq!(ring.push(block_head(3, "b3", "b2")), HeadUpdate::Extends);
+    }
+
+    #[test]
+    fn test_head_ring_higher_reorg() {
+        let mut ring = HeadRing::default();
+        ring.push(block_head(1, "a1", "a0"));
+        ring.push(block_head(2, "a2", "a1"));
+        ring.push(block_head(3, "a3", "a2"));
+
+        // A longer fork replacing 3 shows up at 4
+        let head = block_head(4, "b4", "b3");
+        assert_eq!(ring.missing_parent(&head), Some("b3".to_string()));
+
+        // Pushing the fetched parent first tells us exactly where it forked
+        let parent = block_head(3, "b3", "a2");
+        assert_eq!(ring.missing_parent(&parent), None);
+        assert_eq!(ring.push(parent), HeadUpdate::Reorg { from: 3 });
+        assert_eq!(ring.push(head), HeadUpdate::Extends);
+    }
+
+    #[test]
+    fn test_head_ring_unresolved_parent() {
+        let mut ring = HeadRing::default();
+        ring.push(block_head(5, "a5", "a4"));
+        ring.push(block_head(6, "a6", "a5"));
+        ring.push(block_head(7, "a7", "a6"));
+
+        // Without the parent we only know our block at 7 was replaced
+        assert_eq!(
+            ring.push(block_head(8, "b8", "b7")),
+            HeadUpdate::Reorg { from: 7 }
+        );
+        assert_eq!(ring.push(block_head(9, "b9", "b8")), HeadUpdate::Extends);
+        assert!(ring.get(6).is_some());
+    }
+
+    #[test]
+    fn test_head_ring_is_bounded() {
+        let mut ring = HeadRing::default();
+        for number in 1..=(HEAD_RING_LEN as u64 * 2) {
+            ring.push(block_head(
+                number,
+                &format!("a{number}"),
+                &format!("a{}", number - 1),
+            ));
+        }
+        assert_eq!(ring.heads.len(), HEAD_RING_LEN);
+        assert_eq!(ring.heads.front().unwrap().number, HEAD_RING_LEN as u64 + 1);
+    }
+
+    #[tokio::test]
+    #[serial_test::serial]
+    async fn test_handle_reorg_open_range() {
+        let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+        let cache = Config::tmp().unwrap();
+        let cache = Db::open_with_config(&cache).unwrap();
+
+        let _ = cache.insert("key1", "value1");
+        let _ = cache.insert("key5", "value5");
+        let _ = cache.insert("key9", "value9");
+        let _ = cache.insert(head_cache_key(5, b"key5"), "key5");
+
+        {
+            let mut head_cache_guard = head_cache.write().unwrap();
+            head_cache_guard.insert(1, vec!["key1".as_bytes().to_vec()]);
+            head_cache_guard.insert(5, vec!["key5".as_bytes().to_vec()]);
+            head_cache_guard.insert(9, vec!["key9".as_bytes().to_vec()]);
+        }
+
+        let (db_tx, db_rx) = mpsc::channel::<DbRequest<Vec<u8>, Vec<u8>>>(64);
+        tokio::task::spawn(database_processing(db_rx, cache, 4));
+
+        let hot_cache = HotCache::new(1024 * 1024);
+        hot_cache.insert(b"key1", b"value1".to_vec());
+        hot_cache.insert(b"key9", b"value9".to_vec());
+
+        handle_reorg(&head_cache, 5, u64::MAX, &hot_cache, db_tx.clone())
+            .await
+            .unwrap();
+
+        assert_eq!(
+            head_cache.read().unwrap().keys().collect::<Vec<_>>(),
+            vec![&1]
+        );
+        assert!(db_get!(db_tx.clone(), "key1".as_bytes().to_vec())
+            .unwrap()
+            .is_some());
+        assert!(db_get!(db_tx.clone(), "key5".as_bytes().to_vec())
+            .unwrap()
+            .is_none());
+        assert!(db_get!(db_tx.clone(), "key9".as_bytes().to_vec())
+            .unwrap()
+            .is_none());
+        // Along with the membership we persisted
+        assert!(db_get!(db_tx.clone(), head_cache_key(5, b"key5").to_vec())
+            .unwrap()
+            .is_none());
+        assert!(hot_cache.get(b"key1").is_some());
+        assert!(hot_cache.get(b"key9").is_none());
+    }
+
     #[test]
     fn test_remove_stale() {
         // Create test data and resources
@@ -212,4 +519,57 @@ mod tests {
         assert!(!head_cache_guard.contains_key(&1));
         assert!(!head_cache_guard.contains_key(&2));
     }
+
+    #[test]
+    fn test_head_cache_key_sorts_by_height() {
+        assert!(head_cache_key(255, &[9; 32]) < head_cache_key(256, &[1; 32]));
+        assert!(head_cache_key(1, b"key").starts_with(HEAD_CACHE_PREFIX));
+        // Members of the same height get their own key, whatever their length
+        assert_ne!(head_cache_key(1, &[1; 32]), head_cache_key(1, &[2; 32]));
+        assert_ne!(head_cache_key(1, &[1; 300]), head_cache_key(1, &[1; 301]));
+    }
+
+    #[test]
+    fn test_load_head_cache() {
+        let cache = Config::tmp().unwrap();
+        let cache = Db::open_with_config(&cache).unwrap();
+
+        for height in 1..=4 {
+            let member = [height as u8; 32];
+            cache
+                .write(head_cache_key(height, &member).to_vec(), member.to_vec())
+                .unwrap();
+        }
+        let member = [3; 32];
+        let other = [7; 32];
+        cache
+            .write(head_cache_key(3, &other).to_vec(), other.to_vec())
+            .unwrap();
+        cache
+            .write(head_cache_key(5, &member).to_vec(), vec![1, 2])
+            .unwrap();
+        // Regular cache entries shouldn't be picked up
+        cache.write(vec![9u8; 32], b"{}".to_vec()).unwrap();
+
+        let head_cache = load_head_cache::<[u8; 32], _>(&cache, 2);
+        assert_eq!(head_cache.keys().copied().collect::<Vec<_>>(), vec![3, 4]);
+        let mut members = head_cache[&3].clone();
+        members.sort();
+        assert_eq!(members, vec![member, other]);
+
+        // Finalized and corrupt records get removed from the DB
+        assert!(cache
+            .read(head_cache_key(2, &[2; 32]).to_vec())
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .read(head_cache_key(5, &member).to_vec())
+            .unwrap()
+            .is_none());
+        assert!(cache
+            .read(head_cache_key(3, &member).to_vec())
+            .unwrap()
+            .is_some());
+        assert!(cache.read(vec![9u8; 32]).unwrap().is_some());
+    }
 }
@@ -1,7 +1,17 @@
 use crate::{
-    balancer::processing::CacheArgs,
+    balancer::{
+        format::NamedNumber,
+        processing::CacheArgs,
+    },
     config::system::WS_HEALTH_CHECK_USER_ID,
     database::types::GenericBytes,
+    health::head_cache::{
+        handle_reorg,
+        BlockHead,
+        HeadRing,
+        HeadUpdate,
+        HEAD_RING_LEN,
+    },
     rpc::{
         error::RpcError,
         method::EthRpcMethod,
@@ -27,7 +37,11 @@ use std::sync::{
     RwLock,
 };
 
-use serde_json::Value;
+use rust_tracing::deps::metrics;
+use serde_json::{
+    json,
+    Value,
+};
 
 use tokio::{
     sync::{
@@ -38,13 +52,15 @@ use tokio::{
     time::{
         timeout,
         Duration,
+        Instant,
     },
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
 pub struct NamedBlocknumbers {
     pub latest: u64,
-    pub earliest: u64,
+    /// `None` until we know, since the earliest block can very well be 0.
+    pub earliest: Option<u64>,
     pub safe: u64,
     pub finalized: u64,
     pub pending: u64,
@@ -55,7 +71,7 @@ impl NamedBlocknumbers {
     pub fn defualt() -> NamedBlocknumbers {
         NamedBlocknumbers {
             latest: 0,
-            earliest: 0,
+            earliest: None,
             safe: 0,
             finalized: 0,
             pending: 0,
@@ -63,30 +79,81 @@ impl NamedBlocknumbers {
     }
 }
 
-/// Get the latest finalized block
+/// Get the latest finalized and safe blocks, and the earliest one if we don't know it yet
 pub async fn get_safe_block(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     finalized_tx: &tokio::sync::watch::Sender<u64>,
     named_numbers_rwlock: &Arc<RwLock<NamedBlocknumbers>>,
     ttl: u64,
 ) -> Result<u64, RpcError> {
-    let len;
-    let rpc_list_clone;
-    {
-        let rpc_list_guard = rpc_list.read().unwrap_or_else(|e| {
+    let rpc_list_clone = rpc_list
+        .read()
+        .unwrap_or_else(|e| {
             // Handle the case where the RwLock is poisoned
             e.into_inner()
-        });
+        })
+        .clone();
+
+    // The earliest block doesn't move, so we only need it once
+    let known_earliest = named_numbers_rwlock.read().unwrap().earliest;
+    let (finalized, safe, earliest) = tokio::join!(
+        fetch_tagged(rpc_list_clone.clone(), NamedNumber::Finalized, ttl),
+        fetch_tagged(rpc_list_clone.clone(), NamedNumber::Safe, ttl),
+        async {
+            match known_earliest {
+                Some(earliest) => Some(earliest),
+                None => fetch_tagged(rpc_list_clone, NamedNumber::Earliest, ttl).await,
+            }
+        },
+    );
+    let finalized = finalized.unwrap_or(0);
+    let safe = safe.unwrap_or(0);
 
-        len = rpc_list_guard.len();
-        rpc_list_clone = rpc_list_guard.clone();
-    }
+    // Send new blocknumber if modified
+    let send_if_changed = |number: &mut u64| {
+        if number != &finalized {
+            *number = finalized;
+            return true;
+        }
+        false
+    };
+
+    finalized_tx.send_if_modified(send_if_changed);
+
+    tracing::debug!(safe, ?earliest, "Finalized block: {}", finalized);
+
+    // Return as NamedBlocknumbers
+    let mut nn_rwlock = named_numbers_rwlock.write().unwrap();
+    nn_rwlock.finalized = finalized;
+    nn_rwlock.safe = safe;
+    nn_rwlock.earliest = earliest;
 
-    let mut safe = 0;
+    Ok(finalized)
+}
+
+/// Ask every RPC for its finalized block and return the highest one.
+///
+/// Returns 0 if nobody answered.
+pub async fn fetch_finalized(rpc_list: Vec<Rpc>, ttl: u64) -> u64 {
+    fetch_tagged(rpc_list, NamedNumber::Finalized, ttl)
+        .await
+        .unwrap_or(0)
+}
 
-    // If len == 0 return 0
+/// Ask every RPC for the block `tag` points to.
+///
+/// For `safe` and `finalized` that's the highest one, since a node reporting it saw
+/// it finalize and nodes lagging behind will get there too. For `earliest` it's the
+/// lowest one, the furthest back any node that answered can serve.
+///
+/// Returns `None` if nobody answered.
+async fn fetch_tagged(rpc_list: Vec<Rpc>, tag: NamedNumber, ttl: u64) -> Option<u64> {
+    let len = rpc_list.len();
+    let mut picked = None;
+
+    // If len == 0 return None
     if len == 0 {
-        return Ok(safe);
+        return picked;
     }
 
     // Create a vector to store the futures of all RPC requests
@@ -96,23 +163,23 @@ pub async fn get_safe_block(
     let (tx, mut rx) = mpsc::channel(len);
 
     // Iterate over all RPCs
-    for rpc in rpc_list_clone.into_iter().take(len) {
+    for rpc in rpc_list.into_iter() {
         let tx = tx.clone(); // Clone the sender for this RPC
 
         // Spawn a future for each RPC
         let rpc_future = async move {
-            let a = rpc.get_finalized_block();
+            let a = rpc.get_tagged_block(tag.as_ref());
             let result = timeout(Duration::from_millis(ttl), a).await;
 
-            // Handle timeout as 0
-            let reported_finalized = match result {
-                Ok(Ok(response)) => response,
-                Err(_) => 0,
-                Ok(Err(_)) => 0,
+            // Handle timeout as no answer
+            let reported = match result {
+                Ok(Ok(response)) => Some(response),
+                Err(_) => None,
+                Ok(Err(_)) => None,
             };
 
             // Send the result to the main thread through the channel
-            tx.send(reported_finalized)
+            tx.send(reported)
                 .await
                 .expect("head check: Channel send error");
         };
@@ -127,31 +194,16 @@ pub async fn get_safe_block(
 
     // Collect the results in order from the channel
     for _ in 0..len {
-        if let Some(result) = rx.recv().await {
-            if result > safe {
-                safe = result;
-            }
+        if let Some(Some(result)) = rx.recv().await {
+            picked = Some(match (picked, tag) {
+                (Some(picked), NamedNumber::Earliest) => result.min(picked),
+                (Some(picked), _) => result.max(picked),
+                (None, _) => result,
+            });
         }
     }
 
-    // Send new blocknumber if modified
-    let send_if_changed = |number: &mut u64| {
-        if number != &safe {
-            *number = safe;
-            return true;
-        }
-        false
-    };
-
-    finalized_tx.send_if_modified(send_if_changed);
-
-    tracing::debug!("Safe block: {}", safe);
-
-    // Return as NamedBlocknumbers
-    let mut nn_rwlock = named_numbers_rwlock.write().unwrap();
-    nn_rwlock.finalized = safe;
-
-    Ok(safe)
+    picked
 }
 
 /// Send a message subscribing to newHeads
@@ -194,8 +246,114 @@ async fn send_newheads_sub_message<K, V>(
     };
 }
 
+/// Fetch the header of the block with `hash` through the WS pipeline.
+async fn get_block_head_by_hash<K, V>(
+    hash: &str,
+    user_id: u32,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    outgoing_rx: &broadcast::Receiver<IncomingResponse>,
+    sub_data: &Arc<SubscriptionData>,
+    cache_args: &CacheArgs<K, V>,
+    ttl: u64,
+) -> Option<BlockHead>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let call = json!({
+        "jsonrpc": "2.0",
+        "id": user_id,
+        "method": EthRpcMethod::GetBlockByHash,
+        "params": [hash, false],
+    });
+
+    let mut response = timeout(
+        Duration::from_millis(ttl),
+        execute_ws_call(
+            call,
+            user_id,
+            incoming_tx,
+            outgoing_rx.resubscribe(),
+            sub_data,
+            cache_args,
+        ),
+    )
+    .await
+    .ok()?
+    .ok()?;
+
+    let response: Value = unsafe { simd_json::from_str(&mut response) }.ok()?;
+    BlockHead::from_header(&response["result"])
+}
+
+/// Record a new head in `ring`, returning the first height replaced by a reorg if there was one.
+///
+/// If the parent of `head` doesn't match what we have, we walk back through the new
+/// chain until it connects to a block we've seen, so we know exactly where it forked.
+/// The whole walk gets `ttl`, so a deep reorg can't hold up the heads behind it.
+async fn track_head<K, V>(
+    ring: &mut HeadRing,
+    head: BlockHead,
+    incoming_tx: &mpsc::UnboundedSender<WsconnMessage>,
+    outgoing_rx: &broadcast::Receiver<IncomingResponse>,
+    sub_data: &Arc<SubscriptionData>,
+    cache_args: &CacheArgs<K, V>,
+    ttl: u64,
+) -> Option<u64>
+where
+    K: GenericBytes + From<[u8; 32]>,
+    V: GenericBytes + From<Vec<u8>>,
+{
+    let deadline = Instant::now() + Duration::from_millis(ttl);
+    let mut heads = vec![head];
+    while heads.len() <= HEAD_RING_LEN {
+        let Some(parent_hash) = ring.missing_parent(heads.last().unwrap()) else {
+            break;
+        };
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            tracing::warn!(
+                depth = heads.len(),
+                "Ran out of time looking for where the chain forked"
+            );
+            break;
+        }
+
+        match get_block_head_by_hash(
+            &parent_hash,
+            WS_HEALTH_CHECK_USER_ID,
+            incoming_tx,
+            outgoing_rx,
+            sub_data,
+            cache_args,
+            remaining.as_millis().try_into().unwrap_or(u64::MAX),
+        )
+        .await
+        {
+            Some(parent) => heads.push(parent),
+            None => break,
+        }
+    }
+
+    // Push the oldest first so every head gets checked against its parent
+    heads
+        .into_iter()
+        .rev()
+        .filter_map(|head| {
+            match ring.push(head) {
+                HeadUpdate::Reorg { from } => Some(from),
+                _ => None,
+            }
+        })
+        .min()
+}
+
 /// Subscribe to eth_subscribe("newHeads") and write to NamedBlocknumbers
+///
+/// The node we're subscribed through gets its head in `rpc_list` bumped as well.
 pub async fn subscribe_to_new_heads<K, V>(
+    rpc_list: Arc<RwLock<Vec<Rpc>>>,
     incoming_tx: mpsc::UnboundedSender<WsconnMessage>,
     outgoing_rx: broadcast::Receiver<IncomingResponse>,
     blocknum_tx: watch::Sender<u64>,
@@ -226,20 +384,78 @@ pub async fn subscribe_to_new_heads<K, V>(
     // New message == new head received. We can then update and process
     // everything associated with a new head block.
     let mut subscription_id: String = "".to_string();
+    let mut ring = HeadRing::default();
     loop {
         match timeout(Duration::from_millis(expected_block_time), rx.recv()).await {
             Ok(Some(msg)) => {
                 if let RequestResult::Subscription(sub) = msg {
-                    let mut nn_rwlock = cache_args.named_numbers.write().unwrap();
-                    let a = hex_to_decimal(sub["params"]["result"]["number"].as_str().unwrap())
-                        .unwrap();
+                    let header = &sub["params"]["result"];
+                    let a = hex_to_decimal(header["number"].as_str().unwrap()).unwrap();
                     sub["params"]["subscription"]
                         .as_str()
                         .unwrap()
                         .clone_into(&mut subscription_id);
+
+                    // We know at least this node has it, even between health checks
+                    if let Some(ws_url) = sub_data.get_node_url_from_id(&subscription_id) {
+                        let mut rpc_list = rpc_list.write().unwrap_or_else(|e| e.into_inner());
+                        if let Some(rpc) = rpc_list
+                            .iter_mut()
+                            .find(|rpc| rpc.ws_url.as_ref() == Some(&ws_url))
+                        {
+                            rpc.status.head = rpc.status.head.max(a);
+                        }
+                    }
+
+                    // Remove anything a reorg replaced before we start serving the new head
+                    let reorg = match BlockHead::from_header(header) {
+                        Some(head) => {
+                            track_head(
+                                &mut ring,
+                                head,
+                                &incoming_tx,
+                                &outgoing_rx,
+                                &sub_data,
+                                &cache_args,
+                                expected_block_time,
+                            )
+                            .await
+                        }
+                        None => None,
+                    };
+                    if let Some(from) = reorg {
+                        tracing::warn!(
+                            from,
+                            "Reorg detected! Removing stale entries from the cache."
+                        );
+                        if let Err(err) = handle_reorg(
+                            &cache_args.head_cache,
+                            from,
+                            u64::MAX,
+                            &cache_args.hot_cache,
+                            cache_args.cache.clone(),
+                        )
+                        .await
+                        {
+                            tracing::error!(?err, from, "Failed to remove reorged entries!");
+                            metrics::counter!("reorg_invalidation_errors").increment(1);
+                        }
+                        cache_args
+                            .micro_cache
+                            .write()
+                            .unwrap_or_else(|e| e.into_inner())
+                            .clear();
+                    }
+
+                    let mut nn_rwlock = cache_args.named_numbers.write().unwrap();
                     tracing::info!(a, "New chain head");
                     let _ = blocknum_tx.send(a);
                     nn_rwlock.latest = a;
+                    cache_args
+                        .micro_cache
+                        .write()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .set_head(a);
                 }
             }
             Ok(None) => {
@@ -256,6 +472,11 @@ pub async fn subscribe_to_new_heads<K, V>(
                         e.into_inner()
                     });
                     nn_rwlock.latest = 0;
+                    cache_args
+                        .micro_cache
+                        .write()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .set_head(0);
                     match incoming_tx.send(WsconnMessage::Reconnect()) {
                         Ok(_) => {}
                         Err(_) => {
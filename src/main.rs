@@ -23,27 +23,48 @@ use crate::{
             ConnectionParams,
             RequestChannels,
         },
+        micro_cache::HeadMicroCache,
         processing::CacheArgs,
     },
     config::{
-        cache_setup::setup_data,
+        cache_setup::{
+            setup_data,
+            stored_chain_id,
+        },
+        cli_args::{
+            CacheCommand,
+            Command,
+        },
+        setup::verify_chain_info,
         system::FANOUT,
         types::{
+            rocksdb_config::RocksDbThreadMode,
             CacheSettings,
             Settings,
         },
     },
     database::{
         accept::database_processing,
+        dedup::Dedup,
+        eviction::Evicting,
+        hot_cache::HotCache,
+        memory::MemoryDb,
+        migrate::migrate,
+        snapshot,
         types::GenericDatabase,
+        write_behind::WriteBehind,
     },
     health::{
         check::{
             dropped_listener,
             health_check,
         },
-        head_cache::manage_cache,
+        head_cache::{
+            load_head_cache,
+            manage_cache,
+        },
         safe_block::{
+            fetch_finalized,
             subscribe_to_new_heads,
             NamedBlocknumbers,
         },
@@ -61,12 +82,9 @@ use crate::{
     },
 };
 
-use std::{
-    collections::BTreeMap,
-    sync::{
-        Arc,
-        RwLock,
-    },
+use std::sync::{
+    Arc,
+    RwLock,
 };
 
 use tokio::{
@@ -106,29 +124,151 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     // Get all the cli args and set them
     let mut settings = Settings::new()?;
-    if settings.sort_on_startup {
+    if settings.sort_on_startup && settings.command.is_none() {
         settings = settings.sort_on_startup().await?;
     }
     let cache_settings = settings.cache.clone();
+    let migration = settings.migration.clone();
     let config = Arc::new(RwLock::new(settings));
 
+    if let Some((from, to)) = migration {
+        return migrate_cache(from, to);
+    }
+
     // Create/Open DB
     match cache_settings {
-        CacheSettings::Sled(sled) => {
-            let cache = <sled::Db<{ FANOUT }> as GenericDatabase>::open(&sled)
-                .expect("Can't open/create database!");
-            run(cache, config).await
+        CacheSettings::Sled(sled) => start(open_sled(&sled), config).await,
+        CacheSettings::RocksDB(rocks, RocksDbThreadMode::Single) => {
+            start(open_rocksdb::<rocksdb::SingleThreaded>(rocks), config).await
+        }
+        CacheSettings::RocksDB(rocks, RocksDbThreadMode::Multi) => {
+            start(open_rocksdb::<rocksdb::MultiThreaded>(rocks), config).await
+        }
+        CacheSettings::Memory(memory) => {
+            let cache = MemoryDb::new(&memory);
+            start(cache, config).await
+        }
+    }
+}
+
+fn open_sled(config: &sled::Config) -> sled::Db<{ FANOUT }> {
+    <sled::Db<{ FANOUT }> as GenericDatabase>::open(config).expect("Can't open/create database!")
+}
+
+fn open_rocksdb<T: rocksdb::ThreadMode + Send>(
+    opts: rocksdb::Options,
+) -> rocksdb::DBWithThreadMode<T> {
+    <rocksdb::DBWithThreadMode<T> as GenericDatabase>::open(&(
+        opts,
+        std::path::PathBuf::from("./blutgang-cache-rocksdb"),
+    ))
+    .expect("Can't open/create database!")
+}
+
+/// Copies the cache from one backend into the other for `cache migrate`.
+fn migrate_cache(from: CacheSettings, to: CacheSettings) -> Result<(), Box<dyn std::error::Error>> {
+    let summary = match (from, to) {
+        // Migrating is single threaded either way
+        (CacheSettings::Sled(sled), CacheSettings::RocksDB(rocks, _)) => {
+            migrate(
+                &open_sled(&sled),
+                &open_rocksdb::<rocksdb::SingleThreaded>(rocks),
+            )?
+        }
+        (CacheSettings::RocksDB(rocks, _), CacheSettings::Sled(sled)) => {
+            migrate(
+                &open_rocksdb::<rocksdb::SingleThreaded>(rocks),
+                &open_sled(&sled),
+            )?
+        }
+        // Rejected while parsing the config
+        _ => unreachable!("migrating into the same backend"),
+    };
+
+    tracing::info!(entries = summary.entries, "Migrated and verified cache");
+    Ok(())
+}
+
+/// Runs the subcommand we were given, or starts blutgang.
+///
+/// Wraps the cache so identical responses are stored once if deduplication is
+/// enabled, or if the cache already has deduplicated responses we need to read.
+async fn start<DB: GenericDatabase + 'static>(
+    cache: DB,
+    config: Arc<RwLock<Settings>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (command, dedup) = {
+        let config_guard = config.read().unwrap();
+        (config_guard.command.clone(), config_guard.dedup.clone())
+    };
+
+    if let Some(Command::Cache { action }) = command {
+        return run_cache_command(&cache, action);
+    }
+
+    if dedup.enable || Dedup::in_use(&cache) {
+        // Enabling both is refused while parsing the config, but old bodies are still around
+        let byte_budget = config
+            .read()
+            .unwrap()
+            .eviction
+            .as_ref()
+            .is_some_and(|eviction| eviction.max_bytes.is_some());
+        if byte_budget {
+            tracing::warn!(
+                "The cache has deduplicated responses, `eviction.max_bytes` won't count their bodies."
+            );
+        }
+        start_evicting(Dedup::new(cache, dedup), config).await
+    } else {
+        start_evicting(cache, config).await
+    }
+}
+
+/// Wraps the cache so it stays within budget if eviction is configured.
+async fn start_evicting<DB: GenericDatabase + 'static>(
+    cache: DB,
+    config: Arc<RwLock<Settings>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let eviction = config.read().unwrap().eviction.clone();
+    match eviction {
+        Some(eviction) => start_batching(Evicting::new(cache, eviction), config).await,
+        None => start_batching(cache, config).await,
+    }
+}
+
+/// Batches cache inserts in front of the DB if write-behind is enabled.
+async fn start_batching<DB: GenericDatabase + 'static>(
+    cache: DB,
+    config: Arc<RwLock<Settings>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let write_behind = config.read().unwrap().write_behind.clone();
+    match write_behind {
+        Some(write_behind) => run(WriteBehind::new(cache, write_behind), config).await,
+        None => run(cache, config).await,
+    }
+}
+
+fn run_cache_command<DB: GenericDatabase>(
+    cache: &DB,
+    action: CacheCommand,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match action {
+        CacheCommand::Export { out } => {
+            let entries = snapshot::export(cache, &out)?;
+            tracing::info!(entries, ?out, "Exported cache snapshot");
         }
-        CacheSettings::RocksDB(rocks) => {
-            let cache =
-                <rocksdb::DBWithThreadMode<rocksdb::SingleThreaded> as GenericDatabase>::open(&(
-                    rocks,
-                    std::path::PathBuf::from("./blutgang-cache-rocksdb"),
-                ))
-                .expect("Can't open/create database!");
-            run(cache, config).await
+        CacheCommand::Import {
+            input,
+            allow_other_hash,
+        } => {
+            let entries = snapshot::import(cache, &input, allow_other_hash)?;
+            tracing::info!(entries, ?input, "Imported cache snapshot");
         }
+        // Needs both backends, so it's handled before we get here
+        CacheCommand::Migrate { .. } => unreachable!("migrate is handled by `migrate_cache`"),
     }
+    Ok(())
 }
 
 async fn run<DB: GenericDatabase + 'static>(
@@ -136,7 +276,19 @@ async fn run<DB: GenericDatabase + 'static>(
     config: Arc<RwLock<Settings>>,
 ) -> Result<(), Box<dyn std::error::Error>> {
     // Copy the configuration values we need
-    let (addr, do_clear, do_health_check, admin_enabled, is_ws, expected_block_time) = {
+    let (
+        addr,
+        do_clear,
+        do_health_check,
+        admin_enabled,
+        is_ws,
+        expected_block_time,
+        health_check_ttl,
+        hot_cache_bytes,
+        verify_cache_hits,
+        db_queue_capacity,
+        db_read_concurrency,
+    ) = {
         let config_guard = config.read().unwrap();
         (
             config_guard.address,
@@ -145,23 +297,67 @@ async fn run<DB: GenericDatabase + 'static>(
             config_guard.admin.enabled,
             config_guard.is_ws,
             config_guard.expected_block_time,
+            config_guard.health_check_ttl,
+            config_guard.hot_cache_bytes,
+            config_guard.verify_cache_hits,
+            config_guard.db_queue_capacity,
+            config_guard.db_read_concurrency,
         )
     };
 
-    // Make the list a rwlock
-    let rpc_list_rwlock = Arc::new(RwLock::new(config.read().unwrap().rpc_list.clone()));
+    let (mut rpc_list, mut poverty_list) = {
+        let config_guard = config.read().unwrap();
+        (
+            config_guard.rpc_list.clone(),
+            config_guard.poverty_list.clone(),
+        )
+    };
 
-    // Cache for storing querries near the tip
-    let head_cache = Arc::new(RwLock::new(BTreeMap::new()));
+    // Values like the chain ID never change, so we verify them once and answer them ourselves.
+    //
+    // Nodes on another chain are refused, and so is a cache written for another chain.
+    // Clearing the cache means it's fair game for any chain.
+    let cached_chain_id = (!do_clear).then(|| stored_chain_id(&cache)).flatten();
+    let (chain_id, chain_info) = verify_chain_info(
+        &mut rpc_list,
+        &mut poverty_list,
+        cached_chain_id,
+        health_check_ttl,
+    )
+    .await?;
+    let chain_info = Arc::new(chain_info);
+
+    // Make the lists a rwlock
+    let rpc_list_rwlock = Arc::new(RwLock::new(rpc_list.clone()));
+    let rpc_poverty_list = Arc::new(RwLock::new(poverty_list));
+
+    // Memory-only cache for querries that are only valid until the next head
+    let micro_cache = Arc::new(RwLock::new(HeadMicroCache::default()));
+
+    // Hottest responses, so repeat requests don't have to round trip through the DB task
+    let hot_cache = Arc::new(HotCache::new(hot_cache_bytes));
 
     // Insert data about blutgang and our settings into the DB. Clears if specified.
     //
     // Print any relevant warnings about a misconfigured DB. Check docs for more.
-    setup_data(&cache, do_clear);
+    setup_data(&cache, do_clear, Some(chain_id));
+
+    // Cache for storing querries near the tip.
+    //
+    // Reloaded from the DB and reconciled against the current finalized block,
+    // so entries we inserted before a restart can still be reorged out.
+    let finalized = fetch_finalized(rpc_list.clone(), health_check_ttl).await;
+    let head_cache = Arc::new(RwLock::new(load_head_cache::<[u8; 32], DB>(
+        &cache, finalized,
+    )));
 
     // Starts the database task.
-    let (db_tx, db_rx) = mpsc::unbounded_channel();
-    tokio::task::spawn(database_processing::<[u8; 32], Vec<u8>, DB>(db_rx, cache));
+    let (db_tx, db_rx) = mpsc::channel(db_queue_capacity);
+    tokio::task::spawn(database_processing::<[u8; 32], Vec<u8>, DB>(
+        db_rx,
+        cache,
+        db_read_concurrency,
+    ));
 
     // We create a TcpListener and bind it to 127.0.0.1:3000
     let listener = TcpListener::bind(addr).await?;
@@ -171,7 +367,6 @@ async fn run<DB: GenericDatabase + 'static>(
     let (finalized_tx, finalized_rx) = watch::channel(0);
 
     let finalized_rx_arc = Arc::new(finalized_rx.clone());
-    let rpc_poverty_list = Arc::new(RwLock::new(config.read().unwrap().poverty_list.clone()));
 
     // We need liveness status channels even if admin is unused
     let (liveness_tx, liveness_rx) = mpsc::channel(16);
@@ -234,6 +429,7 @@ async fn run<DB: GenericDatabase + 'static>(
                 liveness_tx_health,
                 &named_blocknumbers_health,
                 &config_health,
+                Some(chain_id),
             )
             .await;
         });
@@ -254,6 +450,7 @@ async fn run<DB: GenericDatabase + 'static>(
         let outgoing_rx_ws = outgoing_rx.resubscribe();
         let incoming_tx_ws = incoming_tx.clone();
         let ws_error_tx_ws = ws_error_tx.clone();
+        let named_numbers_ws = Arc::clone(&named_blocknumbers);
 
         let sub_dispatcher = Arc::clone(&sub_data);
 
@@ -269,6 +466,7 @@ async fn run<DB: GenericDatabase + 'static>(
                 incoming_rx,
                 outgoing_tx,
                 ws_error_tx_ws,
+                named_numbers_ws,
             )
             .await;
         });
@@ -292,6 +490,7 @@ async fn run<DB: GenericDatabase + 'static>(
                 .await
             });
 
+            let heads_rpc = Arc::clone(&rpc_list_rwlock);
             let heads_inc = incoming_tx.clone();
             let heads_rx = outgoing_rx.resubscribe();
             let heads_sub_data = sub_data.clone();
@@ -301,10 +500,15 @@ async fn run<DB: GenericDatabase + 'static>(
                 finalized_rx: finalized_rx.clone(),
                 named_numbers: named_blocknumbers.clone(),
                 head_cache: head_cache.clone(),
+                micro_cache: micro_cache.clone(),
+                hot_cache: hot_cache.clone(),
+                chain_info: chain_info.clone(),
+                verify_hits: verify_cache_hits,
             };
 
             tokio::task::spawn(async move {
                 subscribe_to_new_heads(
+                    heads_rpc,
                     heads_inc,
                     heads_rx,
                     blocknum_tx,
@@ -342,6 +546,10 @@ async fn run<DB: GenericDatabase + 'static>(
             named_numbers: named_blocknumbers.clone(),
             cache: db_tx.clone(),
             head_cache: head_cache.clone(),
+            micro_cache: micro_cache.clone(),
+            hot_cache: hot_cache.clone(),
+            chain_info: chain_info.clone(),
+            verify_hits: verify_cache_hits,
         };
 
         let connection_params =
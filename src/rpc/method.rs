@@ -1,6 +1,15 @@
 //! Available RPC methods and impls.
+//!
+//! [`EthRpcMethod`] covers the methods we send ourselves. Everything we know about the
+//! methods clients can send, like where their block parameter is or if we can cache them,
+//! lives in the [`MethodInfo`] table.
 
-use std::fmt;
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::OnceLock,
+    time::Duration,
+};
 
 #[derive(Debug, thiserror::Error)]
 #[error("failed to convert method to `EthRpcMethod`:\n\ngot: {0:?}\nexpected:\n{1:#?}")]
@@ -58,6 +67,13 @@ pub enum EthRpcMethod {
     Subscribe,
     Unsubscribe,
     Subscription,
+    GasPrice,
+    MaxPriorityFeePerGas,
+    FeeHistory,
+    ChainId,
+    NetVersion,
+    GetBlockByHash,
+    GetLogs,
 }
 impl EthRpcMethod {
     const ETH_BLOCK_NUMBER: &str = "eth_blockNumber";
@@ -76,8 +92,15 @@ impl EthRpcMethod {
     const ETH_SUBSCRIBE: &str = "eth_subscribe";
     const ETH_UNSUBSCRIBE: &str = "eth_unsubscribe";
     const ETH_SUBSCRIPTION: &str = "eth_subscription";
+    const ETH_GAS_PRICE: &str = "eth_gasPrice";
+    const ETH_MAX_PRIORITY_FEE_PER_GAS: &str = "eth_maxPriorityFeePerGas";
+    const ETH_FEE_HISTORY: &str = "eth_feeHistory";
+    const ETH_CHAIN_ID: &str = "eth_chainId";
+    const NET_VERSION: &str = "net_version";
+    const ETH_GET_BLOCK_BY_HASH: &str = "eth_getBlockByHash";
+    const ETH_GET_LOGS: &str = "eth_getLogs";
 
-    const ETH_ALL: &[&str; 15] = &[
+    const ETH_ALL: &[&str; 22] = &[
         Self::ETH_BLOCK_NUMBER,
         Self::ETH_GET_BLOCK_BY_NUMBER,
         Self::ETH_SYNCING,
@@ -93,6 +116,13 @@ impl EthRpcMethod {
         Self::ETH_SUBSCRIBE,
         Self::ETH_UNSUBSCRIBE,
         Self::ETH_SUBSCRIPTION,
+        Self::ETH_GAS_PRICE,
+        Self::ETH_MAX_PRIORITY_FEE_PER_GAS,
+        Self::ETH_FEE_HISTORY,
+        Self::ETH_CHAIN_ID,
+        Self::NET_VERSION,
+        Self::ETH_GET_BLOCK_BY_HASH,
+        Self::ETH_GET_LOGS,
     ];
 
     /// Useful for circumventing lifetimes associated with `let` bindings.
@@ -117,27 +147,260 @@ impl EthRpcMethod {
             Self::Subscribe => Self::ETH_SUBSCRIBE,
             Self::Unsubscribe => Self::ETH_UNSUBSCRIBE,
             Self::Subscription => Self::ETH_SUBSCRIPTION,
+            Self::GasPrice => Self::ETH_GAS_PRICE,
+            Self::MaxPriorityFeePerGas => Self::ETH_MAX_PRIORITY_FEE_PER_GAS,
+            Self::FeeHistory => Self::ETH_FEE_HISTORY,
+            Self::ChainId => Self::ETH_CHAIN_ID,
+            Self::NetVersion => Self::NET_VERSION,
+            Self::GetBlockByHash => Self::ETH_GET_BLOCK_BY_HASH,
+            Self::GetLogs => Self::ETH_GET_LOGS,
+        }
+    }
+}
+
+/// Whether a method only reads, or changes state on the chain or the node answering it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MethodKind {
+    Read,
+    Write,
+}
+
+/// Where a method takes its block parameter.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockParam {
+    None,
+    /// A block number, tag or [EIP-1898](https://eips.ethereum.org/EIPS/eip-1898) object at this index.
+    At(usize),
+    /// Same as `At`, but it can be left out, which means `latest`.
+    Optional(usize),
+    /// A filter object at this index, with a `fromBlock`/`toBlock` range or a `blockHash`.
+    /// Missing ends of the range mean `latest`.
+    Filter(usize),
+}
+
+impl BlockParam {
+    /// Index of the param holding the block, if there is one.
+    pub const fn position(&self) -> Option<usize> {
+        match *self {
+            Self::None => None,
+            Self::At(position) | Self::Optional(position) | Self::Filter(position) => {
+                Some(position)
+            }
+        }
+    }
+
+    /// Leaving the block out means `latest`.
+    pub const fn defaults_to_latest(&self) -> bool {
+        matches!(self, Self::Optional(_) | Self::Filter(_))
+    }
+}
+
+/// How long we wait for methods that are known to be slow, unless `ttl` is longer.
+const SLOW_METHOD_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Everything we know about a method, see [`MethodInfo::of`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MethodInfo {
+    pub name: &'static str,
+    pub kind: MethodKind,
+    pub block_param: BlockParam,
+    /// Addressed by a block or transaction hash instead of a number.
+    pub hash_addressed: bool,
+    /// The response never changes once block tags are replaced with numbers.
+    pub cacheable: bool,
+    /// How long we wait for an answer, if it's expected to take longer than `ttl`.
+    pub timeout: Option<Duration>,
+}
+
+impl MethodInfo {
+    const fn read(name: &'static str) -> Self {
+        Self {
+            name,
+            kind: MethodKind::Read,
+            block_param: BlockParam::None,
+            hash_addressed: false,
+            cacheable: true,
+            timeout: None,
+        }
+    }
+
+    const fn write(name: &'static str) -> Self {
+        Self {
+            kind: MethodKind::Write,
+            cacheable: false,
+            ..Self::read(name)
+        }
+    }
+
+    const fn block(self, block_param: BlockParam) -> Self {
+        Self {
+            block_param,
+            ..self
+        }
+    }
+
+    const fn by_hash(self) -> Self {
+        Self {
+            hash_addressed: true,
+            ..self
         }
     }
 
-    /// Determine the correct parameter index based on the method
-    pub fn get_position<M: TryInto<Self>>(method: M) -> Option<usize> {
-        match method.try_into() {
-            Ok(Self::GetBalance)
-            | Ok(Self::GetTransactionCount)
-            | Ok(Self::GetCode)
-            | Ok(Self::Call) => Some(1),
-            Ok(Self::GetStorageAt) => Some(2),
-            Ok(Self::GetBlockTransactionCountByNumber)
-            | Ok(Self::GetUncleCountByBlockNumber)
-            | Ok(Self::GetBlockByNumber)
-            | Ok(Self::GetTransactionByBlockNumberAndIndex)
-            | Ok(Self::GetUncleByBlockNumberAndIndex) => Some(0),
-            _ => None,
+    const fn uncacheable(self) -> Self {
+        Self {
+            cacheable: false,
+            ..self
         }
     }
+
+    const fn slow(self) -> Self {
+        Self {
+            timeout: Some(SLOW_METHOD_TIMEOUT),
+            ..self
+        }
+    }
+
+    /// Look up `method`. `None` if it's not in the table, which we treat as uncacheable.
+    pub fn of(method: Option<&str>) -> Option<&'static Self> {
+        static BY_NAME: OnceLock<HashMap<&'static str, &'static MethodInfo>> = OnceLock::new();
+        let by_name =
+            BY_NAME.get_or_init(|| METHODS.iter().map(|method| (method.name, method)).collect());
+
+        by_name.get(method?).copied()
+    }
+
+    /// Returns true if responses to this method can be cached.
+    ///
+    /// Never for writes, whatever the table says. We can't tell who they changed state on.
+    pub fn caches(&self) -> bool {
+        self.kind == MethodKind::Read && self.cacheable
+    }
+
+    /// Returns true if responses to `method` can be cached.
+    pub fn is_cacheable(method: Option<&str>) -> bool {
+        Self::of(method).is_some_and(Self::caches)
+    }
+
+    /// Returns true if `method` changes state on the chain or the node answering it,
+    /// so it always has to reach a node.
+    pub fn is_write(method: Option<&str>) -> bool {
+        Self::of(method).is_some_and(|info| info.kind == MethodKind::Write)
+    }
+
+    /// Returns how long to wait for `method` in ms, given the configured `ttl`.
+    pub fn ttl_for(method: Option<&str>, ttl: u128) -> u128 {
+        Self::of(method)
+            .and_then(|info| info.timeout)
+            .map_or(ttl, |timeout| timeout.as_millis().max(ttl))
+    }
 }
 
+/// Methods from the execution API spec, plus the `net`, `web3`, `debug` and `trace`
+/// namespaces as implemented by geth, reth and erigon.
+static METHODS: &[MethodInfo] = &[
+    // eth
+    MethodInfo::read("eth_accounts").uncacheable(),
+    MethodInfo::read("eth_blobBaseFee").uncacheable(),
+    MethodInfo::read("eth_blockNumber").uncacheable(),
+    MethodInfo::read("eth_call").block(BlockParam::Optional(1)),
+    MethodInfo::read("eth_chainId"),
+    MethodInfo::read("eth_coinbase").uncacheable(),
+    MethodInfo::read("eth_createAccessList").block(BlockParam::Optional(1)),
+    MethodInfo::read("eth_estimateGas").block(BlockParam::Optional(1)),
+    MethodInfo::read("eth_feeHistory").block(BlockParam::At(1)),
+    MethodInfo::read("eth_gasPrice").uncacheable(),
+    MethodInfo::read("eth_getBalance").block(BlockParam::At(1)),
+    MethodInfo::read("eth_getBlockByHash").by_hash(),
+    MethodInfo::read("eth_getBlockByNumber").block(BlockParam::At(0)),
+    MethodInfo::read("eth_getBlockReceipts").block(BlockParam::At(0)),
+    MethodInfo::read("eth_getBlockTransactionCountByHash").by_hash(),
+    MethodInfo::read("eth_getBlockTransactionCountByNumber").block(BlockParam::At(0)),
+    MethodInfo::read("eth_getCode").block(BlockParam::At(1)),
+    MethodInfo::read("eth_getFilterChanges").uncacheable(),
+    MethodInfo::read("eth_getFilterLogs").uncacheable(),
+    MethodInfo::read("eth_getHeaderByHash").by_hash(),
+    MethodInfo::read("eth_getHeaderByNumber").block(BlockParam::At(0)),
+    MethodInfo::read("eth_getLogs")
+        .block(BlockParam::Filter(0))
+        .slow(),
+    MethodInfo::read("eth_getProof").block(BlockParam::At(2)),
+    MethodInfo::read("eth_getStorageAt").block(BlockParam::At(2)),
+    MethodInfo::read("eth_getTransactionByBlockHashAndIndex").by_hash(),
+    MethodInfo::read("eth_getTransactionByBlockNumberAndIndex").block(BlockParam::At(0)),
+    MethodInfo::read("eth_getTransactionByHash").by_hash(),
+    // Mostly asked for `pending`, which moves with every transaction sent
+    MethodInfo::read("eth_getTransactionCount")
+        .block(BlockParam::At(1))
+        .uncacheable(),
+    MethodInfo::read("eth_getTransactionReceipt").by_hash(),
+    MethodInfo::read("eth_getUncleByBlockHashAndIndex").by_hash(),
+    MethodInfo::read("eth_getUncleByBlockNumberAndIndex").block(BlockParam::At(0)),
+    MethodInfo::read("eth_getUncleCountByBlockHash").by_hash(),
+    MethodInfo::read("eth_getUncleCountByBlockNumber").block(BlockParam::At(0)),
+    MethodInfo::read("eth_hashrate").uncacheable(),
+    MethodInfo::read("eth_maxPriorityFeePerGas").uncacheable(),
+    MethodInfo::read("eth_mining").uncacheable(),
+    MethodInfo::read("eth_protocolVersion").uncacheable(),
+    MethodInfo::read("eth_simulateV1").block(BlockParam::Optional(1)),
+    MethodInfo::read("eth_syncing").uncacheable(),
+    // Filters and subscriptions live on the node that made them
+    MethodInfo::write("eth_newBlockFilter"),
+    MethodInfo::write("eth_newFilter"),
+    MethodInfo::write("eth_newPendingTransactionFilter"),
+    MethodInfo::write("eth_uninstallFilter"),
+    MethodInfo::write("eth_subscribe"),
+    MethodInfo::write("eth_unsubscribe"),
+    MethodInfo::write("eth_sendRawTransaction"),
+    MethodInfo::write("eth_sendTransaction"),
+    MethodInfo::write("eth_sign"),
+    MethodInfo::write("eth_signTransaction"),
+    // net
+    MethodInfo::read("net_listening").uncacheable(),
+    MethodInfo::read("net_peerCount").uncacheable(),
+    MethodInfo::read("net_version"),
+    // web3
+    MethodInfo::read("web3_clientVersion").uncacheable(),
+    MethodInfo::read("web3_sha3"),
+    // debug
+    MethodInfo::read("debug_getBadBlocks").uncacheable(),
+    MethodInfo::read("debug_getRawBlock").block(BlockParam::At(0)),
+    MethodInfo::read("debug_getRawHeader").block(BlockParam::At(0)),
+    MethodInfo::read("debug_getRawReceipts").block(BlockParam::At(0)),
+    MethodInfo::read("debug_getRawTransaction").by_hash(),
+    MethodInfo::read("debug_storageRangeAt").by_hash(),
+    MethodInfo::read("debug_traceBlock").slow(),
+    MethodInfo::read("debug_traceBlockByHash").by_hash().slow(),
+    MethodInfo::read("debug_traceBlockByNumber")
+        .block(BlockParam::At(0))
+        .slow(),
+    MethodInfo::read("debug_traceCall")
+        .block(BlockParam::At(1))
+        .slow(),
+    MethodInfo::read("debug_traceTransaction").by_hash().slow(),
+    // trace
+    MethodInfo::read("trace_block")
+        .block(BlockParam::At(0))
+        .slow(),
+    MethodInfo::read("trace_call")
+        .block(BlockParam::Optional(2))
+        .slow(),
+    MethodInfo::read("trace_callMany")
+        .block(BlockParam::Optional(1))
+        .slow(),
+    MethodInfo::read("trace_filter")
+        .block(BlockParam::Filter(0))
+        .slow(),
+    MethodInfo::read("trace_get").by_hash(),
+    MethodInfo::read("trace_rawTransaction")
+        .uncacheable()
+        .slow(),
+    MethodInfo::read("trace_replayBlockTransactions")
+        .block(BlockParam::At(0))
+        .slow(),
+    MethodInfo::read("trace_replayTransaction").by_hash().slow(),
+    MethodInfo::read("trace_transaction").by_hash().slow(),
+];
+
 impl<T> PartialEq<T> for EthRpcMethod
 where
     T: PartialEq<str>,
@@ -177,6 +440,13 @@ impl TryFrom<Option<&str>> for EthRpcMethod {
             Some(Self::ETH_SUBSCRIBE) => Ok(Self::Subscribe),
             Some(Self::ETH_UNSUBSCRIBE) => Ok(Self::Unsubscribe),
             Some(Self::ETH_SUBSCRIPTION) => Ok(Self::Subscription),
+            Some(Self::ETH_GAS_PRICE) => Ok(Self::GasPrice),
+            Some(Self::ETH_MAX_PRIORITY_FEE_PER_GAS) => Ok(Self::MaxPriorityFeePerGas),
+            Some(Self::ETH_FEE_HISTORY) => Ok(Self::FeeHistory),
+            Some(Self::ETH_CHAIN_ID) => Ok(Self::ChainId),
+            Some(Self::NET_VERSION) => Ok(Self::NetVersion),
+            Some(Self::ETH_GET_BLOCK_BY_HASH) => Ok(Self::GetBlockByHash),
+            Some(Self::ETH_GET_LOGS) => Ok(Self::GetLogs),
             _ => Err(Error::new(value.map(ToString::to_string))),
         }
     }
@@ -230,7 +500,58 @@ impl<'de> serde::Deserialize<'de> for EthRpcMethod {
             Self::ETH_SUBSCRIBE => Ok(Self::Subscribe),
             Self::ETH_UNSUBSCRIBE => Ok(Self::Unsubscribe),
             Self::ETH_SUBSCRIPTION => Ok(Self::Subscription),
+            Self::ETH_GAS_PRICE => Ok(Self::GasPrice),
+            Self::ETH_MAX_PRIORITY_FEE_PER_GAS => Ok(Self::MaxPriorityFeePerGas),
+            Self::ETH_FEE_HISTORY => Ok(Self::FeeHistory),
+            Self::ETH_CHAIN_ID => Ok(Self::ChainId),
+            Self::NET_VERSION => Ok(Self::NetVersion),
+            Self::ETH_GET_BLOCK_BY_HASH => Ok(Self::GetBlockByHash),
+            Self::ETH_GET_LOGS => Ok(Self::GetLogs),
             _ => Err(serde::de::Error::unknown_variant(s, Self::ETH_ALL)),
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_method_table() {
+        // Everything we send ourselves is in there, once. Except for subscription
+        // notifications, nodes send those to us
+        assert!(MethodInfo::of(Some("eth_subscription")).is_none());
+        for method in EthRpcMethod::ETH_ALL
+            .iter()
+            .filter(|method| **method != EthRpcMethod::ETH_SUBSCRIPTION)
+        {
+            assert!(
+                MethodInfo::of(Some(method)).is_some(),
+                "{method} is missing"
+            );
+        }
+        for (i, method) in METHODS.iter().enumerate() {
+            assert!(
+                !METHODS[i + 1..]
+                    .iter()
+                    .any(|other| other.name == method.name),
+                "{} is in there twice",
+                method.name
+            );
+            // Can't tell who it changed state on
+            assert!(method.kind == MethodKind::Read || !method.cacheable);
+        }
+
+        assert!(MethodInfo::is_cacheable(Some("eth_getProof")));
+        assert!(!MethodInfo::is_cacheable(Some("eth_sendRawTransaction")));
+        assert!(MethodInfo::is_write(Some("eth_sendRawTransaction")));
+        assert!(!MethodInfo::is_write(Some("eth_call")));
+        assert!(!MethodInfo::is_write(Some("eth_somethingNew")));
+        assert!(!MethodInfo::is_cacheable(Some("eth_somethingNew")));
+        assert!(!MethodInfo::is_cacheable(None));
+
+        assert_eq!(MethodInfo::ttl_for(Some("eth_getBalance"), 1000), 1000);
+        assert_eq!(MethodInfo::ttl_for(Some("debug_traceCall"), 1000), 30_000);
+        assert_eq!(MethodInfo::ttl_for(Some("debug_traceCall"), 60_000), 60_000);
+    }
+}
@@ -18,6 +18,11 @@ pub struct Status {
     // Also set the last time it was called, so we can check again later
     pub is_erroring: bool,
     pub last_error: u64,
+    // Probes failed in a row while in the poverty list, and when we can probe it again
+    pub failed_probes: u32,
+    pub next_probe: Option<std::time::Instant>,
+    // Last head the node told us about, 0 if it hasn't yet
+    pub head: u64,
 
     // The latency is a moving average of the last n calls
     pub latency: f64,
@@ -27,6 +32,15 @@ pub struct Status {
     // pub throughput: f64,
 }
 
+/// Chain constant values every node agreed on at startup.
+///
+/// `None` if we couldn't get a unanimous answer.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ChainInfo {
+    pub chain_id: Option<u64>,
+    pub net_version: Option<String>,
+}
+
 #[derive(Debug, Clone)]
 pub struct Rpc {
     pub name: String,             // sanitized name for appearing in logs
@@ -102,8 +116,14 @@ impl Rpc {
         }
     }
 
+    /// Whether the node should have `block` by now, as far as we know.
+    ///
+    /// Nodes we haven't heard a head from get the benefit of the doubt.
+    pub fn has_block(&self, block: u64) -> bool {
+        self.status.head == 0 || self.status.head >= block
+    }
+
     /// Explicitly get the url of the Rpc, potentially dangerous as it can expose basic auth
-    #[cfg(test)]
     pub fn get_url(&self) -> Url {
         self.url.clone()
     }
@@ -173,12 +193,66 @@ impl Rpc {
         Ok(status)
     }
 
-    /// Get the latest finalized block
-    pub async fn get_finalized_block(&self) -> Result<u64, crate::rpc::types::RpcError> {
+    /// Returns the chain ID the node is on.
+    pub async fn chain_id(&self) -> Result<u64, crate::rpc::types::RpcError> {
+        let method = EthRpcMethod::ChainId;
+        let request = json!({
+            "method": method,
+            "params": [],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        metrics::gauge!("rpc_requests_active", "method" => method.as_str()).increment(1);
+        metrics::counter!("rpc_requests_total", "method" => method.as_str()).increment(1);
+
+        let req_start = std::time::Instant::now();
+        let chain_id = self.send_request(request).await?;
+
+        metrics::histogram!("rpc_response_time_secs", "method" => method.as_str())
+            .record(req_start.elapsed().as_secs_f64());
+        metrics::gauge!("rpc_requests_active", "method" => method.as_str()).decrement(1);
+
+        extract_number(&chain_id)
+    }
+
+    /// Returns the network ID the node is on.
+    pub async fn net_version(&self) -> Result<String, crate::rpc::types::RpcError> {
+        let method = EthRpcMethod::NetVersion;
+        let request = json!({
+            "method": method,
+            "params": [],
+            "id": 1,
+            "jsonrpc": "2.0".to_string(),
+        });
+
+        metrics::gauge!("rpc_requests_active", "method" => method.as_str()).increment(1);
+        metrics::counter!("rpc_requests_total", "method" => method.as_str()).increment(1);
+
+        let req_start = std::time::Instant::now();
+        let mut version = self.send_request(request).await?;
+
+        metrics::histogram!("rpc_response_time_secs", "method" => method.as_str())
+            .record(req_start.elapsed().as_secs_f64());
+        metrics::gauge!("rpc_requests_active", "method" => method.as_str()).decrement(1);
+
+        let version: Value = unsafe { simd_json::serde::from_str(&mut version)? };
+        match version["result"].as_str() {
+            Some(version) => Ok(version.to_string()),
+            None => {
+                Err(RpcError::InvalidResponse(
+                    "error: Can't get net_version!".to_string(),
+                ))
+            }
+        }
+    }
+
+    /// Get the number of the block a tag like `finalized` or `safe` points to
+    pub async fn get_tagged_block(&self, tag: &str) -> Result<u64, crate::rpc::types::RpcError> {
         let method = EthRpcMethod::GetBlockByNumber;
         let request = json!({
             "method": method,
-            "params": ["finalized", false],
+            "params": [tag, false],
             "id": 1,
             "jsonrpc": "2.0".to_string(),
         });
@@ -199,9 +273,9 @@ impl Rpc {
         let number = match number.as_str() {
             Some(number) => number,
             None => {
-                return Err(RpcError::InvalidResponse(
-                    "error: Can't get finalized block!".to_string(),
-                ))
+                return Err(RpcError::InvalidResponse(format!(
+                    "error: Can't get {tag} block!"
+                )))
             }
         };
 
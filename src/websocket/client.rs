@@ -1,17 +1,30 @@
 use crate::{
     balancer::{
-        format::replace_block_tags,
+        format::{
+            get_block_number_from_request,
+            replace_block_tags,
+        },
+        local_responder::local_response,
+        micro_cache::micro_cache_head,
         processing::{
             cache_query,
+            get_cached,
             update_rpc_latency,
             CacheArgs,
         },
-        selection::select::pick,
+        selection::select::pick_at,
+        splice::splice_id,
+    },
+    database::{
+        key::CacheKey,
+        types::GenericBytes,
     },
-    database::types::GenericBytes,
-    db_get,
+    health::safe_block::NamedBlocknumbers,
     rpc::{
-        method::EthRpcMethod,
+        method::{
+            EthRpcMethod,
+            MethodInfo,
+        },
         types::Rpc,
     },
     websocket::{
@@ -38,10 +51,7 @@ use futures_util::{
     StreamExt,
 };
 use serde_json::Value;
-use simd_json::{
-    from_slice,
-    from_str,
-};
+use simd_json::from_str;
 
 use tokio::sync::{
     broadcast,
@@ -52,12 +62,6 @@ use tokio_tungstenite::{
     tungstenite::protocol::Message,
 };
 
-#[cfg(not(feature = "xxhash"))]
-use blake3::hash;
-
-#[cfg(feature = "xxhash")]
-use xxhash_rust::xxh3::xxh3_64;
-
 /// Accepts incoming internal WS messages.
 ///
 /// Upon receiving a `WsconnMessage::Reconnect()` it will drop all current WS
@@ -68,6 +72,7 @@ pub async fn ws_conn_manager(
     mut incoming_rx: mpsc::UnboundedReceiver<WsconnMessage>,
     broadcast_tx: broadcast::Sender<IncomingResponse>,
     ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
+    named_numbers: Arc<RwLock<NamedBlocknumbers>>,
 ) {
     // Initialize WebSocket connections
     update_ws_connections(&rpc_list, &ws_handles, &broadcast_tx, &ws_error_tx).await;
@@ -83,13 +88,14 @@ pub async fn ws_conn_manager(
                     &rpc_list,
                     incoming,
                     specified_index,
+                    &named_numbers,
                     &mut ws_buffer,
                 )
                 .await;
             }
             WsconnMessage::Reconnect() => {
                 update_ws_connections(&rpc_list, &ws_handles, &broadcast_tx, &ws_error_tx).await;
-                unload_buffer(&rpc_list, &ws_handles, &mut ws_buffer).await;
+                unload_buffer(&rpc_list, &ws_handles, &named_numbers, &mut ws_buffer).await;
             }
         }
     }
@@ -115,35 +121,47 @@ async fn update_ws_connections(
 async fn unload_buffer(
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
     ws_buffer: &mut Vec<Value>,
 ) {
     for i in 0..ws_buffer.len() {
         let incoming = ws_buffer[i].clone();
-        handle_incoming_message(ws_handles, rpc_list, incoming, None, ws_buffer).await;
+        handle_incoming_message(
+            ws_handles,
+            rpc_list,
+            incoming,
+            None,
+            named_numbers,
+            ws_buffer,
+        )
+        .await;
     }
     ws_buffer.clear();
 }
 
 /// Sends an incoming request to a WS connection.
 ///
-/// Indexes can be specified via the `specified_index` param.
+/// Indexes can be specified via the `specified_index` param, otherwise
+/// only nodes that have the requested block are picked.
 async fn handle_incoming_message(
     ws_handles: &Arc<RwLock<Vec<Option<mpsc::UnboundedSender<Value>>>>>,
     rpc_list: &Arc<RwLock<Vec<Rpc>>>,
     incoming: Value,
     specified_index: Option<usize>,
+    named_numbers: &Arc<RwLock<NamedBlocknumbers>>,
     ws_buffer: &mut Vec<Value>,
 ) {
     let rpc_position = if let Some(index) = specified_index {
         index
     } else {
+        let block = get_block_number_from_request(incoming.clone(), named_numbers);
         let mut rpc_list_guard = rpc_list.write().unwrap_or_else(|e| {
             // Handle the case where the rpc_list RwLock is poisoned
             tracing::error!(?e);
             e.into_inner()
         });
 
-        match pick(&mut rpc_list_guard).1 {
+        match pick_at(&mut rpc_list_guard, block).1 {
             Some(position) => position,
             None => {
                 // Check if the incoming content is a subscription.
@@ -227,7 +245,8 @@ pub async fn ws_conn(
     ws_error_tx: mpsc::UnboundedSender<WsChannelErr>,
     index: usize,
 ) {
-    let ws_stream = match connect_async(&rpc.ws_url.unwrap()).await {
+    let ws_url = rpc.ws_url.unwrap();
+    let ws_stream = match connect_async(&ws_url).await {
         Ok((ws_stream, _)) => ws_stream,
         Err(_) => {
             tracing::error!(
@@ -285,9 +304,19 @@ pub async fn ws_conn(
                         }
                     };
 
+                    let node_head = rpc_list
+                        .read()
+                        .unwrap_or_else(|e| e.into_inner())
+                        .iter()
+                        .find(|rpc| rpc.ws_url.as_ref() == Some(&ws_url))
+                        .map_or(0, |rpc| rpc.status.head);
+
                     let incoming = IncomingResponse {
                         node_id: index,
                         content: rax,
+                        ws_url: ws_url.clone(),
+                        node_name: rpc.name.clone(),
+                        node_head,
                     };
 
                     let _ = broadcast_tx.send(incoming);
@@ -327,21 +356,45 @@ where
     );
 
     let id = call["id"].take();
-    let tx_hash = {
-        #[cfg(not(feature = "xxhash"))]
-        {
-            hash(call.to_string().as_bytes())
+
+    // Answer what we can without the DB or an upstream
+    if let Some(local) = local_response(
+        &call,
+        id.clone(),
+        &cache_args.chain_info,
+        &cache_args.named_numbers,
+    ) {
+        return Ok(local);
+    }
+
+    // Head scoped requests only live in the micro cache
+    let micro_head = micro_cache_head(&call, &cache_args.named_numbers);
+
+    // Replace block tags if applicable. Done before hashing so `latest`
+    // shares its key with the block number it points to.
+    if micro_head.is_none() {
+        call = replace_block_tags(&mut call, &cache_args.named_numbers);
+    }
+
+    let tx_hash = CacheKey::new(call.to_string());
+
+    if let Some(head) = micro_head {
+        let cached = cache_args
+            .micro_cache
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(head, tx_hash.as_bytes(), id.clone());
+
+        if let Some(cached) = cached {
+            return Ok(cached);
         }
-        #[cfg(feature = "xxhash")]
-        {
-            xxh3_64(call.to_string().as_bytes())
+    } else if MethodInfo::is_cacheable(call["method"].as_str()) {
+        if let Ok(Some(rax)) = get_cached(cache_args, &tx_hash).await {
+            // Frames are text, so it still has to be valid UTF-8, but we don't parse it
+            if let Ok(cached) = String::from_utf8(splice_id(&rax, &id)) {
+                return Ok(cached);
+            }
         }
-    };
-
-    if let Ok(Some(mut rax)) = db_get!(cache_args.cache, tx_hash.as_bytes().to_owned().into()) {
-        let mut cached: Value = from_slice(rax.as_mut()).unwrap();
-        cached["id"] = id;
-        return Ok(cached.to_string());
     }
 
     // Remove and unsubscribe user is "eth_unsubscribe"
@@ -388,9 +441,6 @@ where
                 id, rax
             ));
         }
-    } else {
-        // Replace block tags if applicable
-        call = replace_block_tags(&mut call, &cache_args.named_numbers);
     }
 
     call["id"] = user_id.into();
@@ -413,9 +463,24 @@ where
 
         tracing::info!(sub_id, "sub_id");
         sub_data.register_subscription(call.clone(), sub_id.clone(), response.node_id);
+        sub_data.set_node_url(sub_id.clone(), response.ws_url);
         sub_data.subscribe_user(user_id, call)?;
+    } else if let Some(head) = micro_head {
+        cache_args
+            .micro_cache
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(head, tx_hash.as_bytes(), response.content.clone());
     } else {
-        cache_query(&mut response.content.to_string(), call, tx_hash, cache_args).await;
+        cache_query(
+            &mut response.content.to_string(),
+            call,
+            &tx_hash,
+            &response.node_name,
+            Some(response.node_head),
+            cache_args,
+        )
+        .await;
     }
 
     response.content["id"] = id;
@@ -512,6 +577,7 @@ mod tests {
             &rpc_list,
             incoming.clone(),
             Some(0),
+            &Arc::new(RwLock::new(NamedBlocknumbers::default())),
             &mut ws_buffer,
         )
         .await;
@@ -521,6 +587,40 @@ mod tests {
         assert_eq!(received, Some(incoming));
     }
 
+    #[tokio::test]
+    async fn test_handle_incoming_message_at_block() {
+        let rpc_list = Arc::new(RwLock::new(vec![
+            mock_rpc("node1.example.com"),
+            mock_rpc("node2.example.com"),
+        ]));
+        rpc_list.write().unwrap()[0].status.head = 10;
+        rpc_list.write().unwrap()[1].status.head = 100;
+        let (tx0, mut rx0) = mpsc::unbounded_channel();
+        let (tx1, mut rx1) = mpsc::unbounded_channel();
+        let ws_handles = Arc::new(RwLock::new(vec![Some(tx0), Some(tx1)]));
+        let incoming = json!({
+            "id": 1,
+            "jsonrpc": "2.0",
+            "method": EthRpcMethod::GetBalance,
+            "params": ["0x407d73d8a49eeb85d32cf465507dd71d507100c1", "0x50"]
+        });
+        let mut ws_buffer: Vec<Value> = Vec::new();
+
+        handle_incoming_message(
+            &ws_handles,
+            &rpc_list,
+            incoming.clone(),
+            None,
+            &Arc::new(RwLock::new(NamedBlocknumbers::default())),
+            &mut ws_buffer,
+        )
+        .await;
+
+        // Only the second node has block 0x50
+        assert_eq!(rx1.try_recv().ok(), Some(incoming));
+        assert!(rx0.try_recv().is_err());
+    }
+
     #[tokio::test]
     async fn test_ws_conn_handling_error() {
         let (_rpc_list, incoming_tx, mut incoming_rx, _broadcast_tx, _ws_error_tx) =
@@ -567,6 +667,9 @@ mod tests {
                     "result": "0x1a2b3c"
                 }),
                 node_id: 0,
+                ws_url: "ws://test".parse().unwrap(),
+                node_name: "test".to_string(),
+                node_head: 0,
             };
             b_clone.send(response).unwrap();
         });
@@ -607,6 +710,9 @@ mod tests {
                     "result": "0x1a2b3c"
                 }),
                 node_id: 0,
+                ws_url: "ws://test".parse().unwrap(),
+                node_name: "test".to_string(),
+                node_head: 0,
             };
             broadcast_tx.send(response).unwrap();
         });
@@ -635,6 +741,9 @@ mod tests {
                     "result": "0x1a2b3c"
                 }),
                 node_id: 0,
+                ws_url: "ws://test".parse().unwrap(),
+                node_name: "test".to_string(),
+                node_head: 0,
             };
             broadcast_tx.send(response).unwrap();
         });
@@ -140,8 +140,8 @@ pub async fn move_subscriptions(
             Some(rax) => rax,
             None => return Err(WsError::MissingSubscription()),
         };
-        match sub_data.move_subscriptions(response.node_id, params, sub_id) {
-            Ok(_) => {}
+        match sub_data.move_subscriptions(response.node_id, params, sub_id.clone()) {
+            Ok(_) => sub_data.set_node_url(sub_id, response.ws_url),
             Err(err) => return Err(err),
         };
 
@@ -190,6 +190,9 @@ mod tests {
         let incoming_response = IncomingResponse {
             content: subscription_content,
             node_id: 0,
+            ws_url: "ws://test".parse().unwrap(),
+            node_name: "test".to_string(),
+            node_head: 0,
         };
         tx.send(incoming_response).unwrap();
 
@@ -229,6 +232,9 @@ mod tests {
                     let mock_response = IncomingResponse {
                         content: json!({"jsonrpc": "2.0", "id": id, "result": random_result}),
                         node_id: 2, // new node ID
+                        ws_url: "ws://new_node".parse().unwrap(),
+                        node_name: "new_node".to_string(),
+                        node_head: 0,
                     };
                     tokio::time::sleep(Duration::from_millis(50)).await; // Simulate network delay
                     tx_clone.send(mock_response).unwrap();
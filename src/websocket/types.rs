@@ -65,6 +65,13 @@ pub struct NodeSubInfo {
 pub struct IncomingResponse {
     pub content: Value,
     pub node_id: usize,
+    /// WS URL of the node that sent it. `node_id` is only the connection's index,
+    /// which stops lining up with the `rpc_list` once nodes get dropped.
+    pub ws_url: url::Url,
+    /// Name of the node that sent it.
+    pub node_name: String,
+    /// Head of the node when it sent it, 0 if unknown.
+    pub node_head: u64,
 }
 
 /// Main struct for storing data related to subscriptions and the associated users
@@ -74,6 +81,8 @@ pub struct SubscriptionData {
     users: Arc<RwLock<HashMap<u32, UserData>>>,
     subscriptions: Arc<RwLock<HashMap<NodeSubInfo, HashSet<u32>>>>,
     incoming_subscriptions: Arc<RwLock<HashMap<String, NodeSubInfo>>>,
+    // subscription id -> WS URL of the node it's on
+    node_urls: Arc<RwLock<HashMap<String, url::Url>>>,
 }
 
 impl SubscriptionData {
@@ -82,6 +91,7 @@ impl SubscriptionData {
             users: Arc::new(RwLock::new(HashMap::new())),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
             incoming_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            node_urls: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
@@ -160,14 +170,23 @@ impl SubscriptionData {
             .write()
             .unwrap_or_else(|e| e.into_inner());
 
-        if incoming_subscriptions
-            .remove(&subscription_request)
-            .is_some()
-        {
+        if let Some(node_sub_info) = incoming_subscriptions.remove(&subscription_request) {
             metrics::gauge!("ws_node_subs_total").decrement(1);
+            self.node_urls
+                .write()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&node_sub_info.subscription_id);
         }
     }
 
+    // Record which node a subscription is on, so we can find it in the `rpc_list`
+    pub fn set_node_url(&self, subscription_id: String, ws_url: url::Url) {
+        self.node_urls
+            .write()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(subscription_id, ws_url);
+    }
+
     // Subscribe user to existing subscription and return the subscription id
     //
     // If the subscription does not exist, return error
@@ -258,6 +277,15 @@ impl SubscriptionData {
             })
     }
 
+    // Return the WS url of the node serving a subscription
+    pub fn get_node_url_from_id(&self, subscription_id: &str) -> Option<url::Url> {
+        self.node_urls
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .get(subscription_id)
+            .cloned()
+    }
+
     // Return all sub ids for a given node_id
     pub fn get_sub_id_by_node(&self, node_id: usize) -> Vec<String> {
         let incoming_subscriptions = self
@@ -682,6 +710,23 @@ mod tests {
         assert!(subscriptions_for_nonexistent_node.is_empty());
     }
 
+    #[test]
+    fn test_node_url() {
+        let sub_data = SubscriptionData::new();
+        let subscription = json!({"jsonrpc": "2.0", "id": 1, "method": EthRpcMethod::Subscribe, "params": ["newHeads"]});
+        let ws_url: url::Url = "ws://node".parse().unwrap();
+
+        sub_data.register_subscription(subscription.clone(), "sub123".to_string(), 1);
+        assert_eq!(sub_data.get_node_url_from_id("sub123"), None);
+
+        sub_data.set_node_url("sub123".to_string(), ws_url.clone());
+        assert_eq!(sub_data.get_node_url_from_id("sub123"), Some(ws_url));
+
+        // Forgotten along with the subscription
+        sub_data.unregister_subscription(subscription["params"].to_string());
+        assert_eq!(sub_data.get_node_url_from_id("sub123"), None);
+    }
+
     #[tokio::test]
     async fn test_get_sub_id_by_params() {
         // Create a mock SubscriptionData
@@ -689,6 +734,7 @@ mod tests {
             users: Arc::new(RwLock::new(HashMap::new())),
             subscriptions: Arc::new(RwLock::new(HashMap::new())),
             incoming_subscriptions: Arc::new(RwLock::new(HashMap::new())),
+            node_urls: Arc::new(RwLock::new(HashMap::new())),
         };
 
         // Mock subscription data